@@ -0,0 +1,86 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// 就绪检查的配置，由使用 API 服务的一方在启动时设置
+#[derive(Debug, Clone, Default)]
+pub struct Readiness {
+    /// 需要先绑定监听端口（见 [`set_listening`]）才算就绪
+    pub listener: bool,
+    /// 需要可写的保存目录
+    pub output_dir: Option<PathBuf>,
+    /// 保存目录所在磁盘至少要有的剩余空间（字节）
+    pub min_free_space: u64,
+}
+
+static CONFIG: Mutex<Option<Readiness>> = Mutex::new(None);
+static LISTENING: AtomicBool = AtomicBool::new(false);
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// 设置就绪检查的内容
+pub fn configure(readiness: Readiness) {
+    *CONFIG.lock().unwrap() = Some(readiness);
+}
+
+/// 监听端口已绑定或已关闭
+pub fn set_listening(listening: bool) {
+    LISTENING.store(listening, Ordering::Relaxed);
+}
+
+/// 进入停止流程：不再接受新连接，只等待进行中的传输完成，此后不再就绪
+pub fn set_draining(draining: bool) {
+    DRAINING.store(draining, Ordering::Relaxed);
+}
+
+/// 是否已进入停止流程，调用方据此不再开始新的传输
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::Relaxed)
+}
+
+/// 一项就绪检查的结果
+#[derive(Debug, serde::Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, result: Result<(), String>) -> Check {
+        Check { name, ok: result.is_ok(), detail: result.err() }
+    }
+}
+
+/// 执行全部就绪检查，所有检查通过时为就绪
+pub fn check() -> Vec<Check> {
+    let config = CONFIG.lock().unwrap().clone().unwrap_or_default();
+    let mut checks = Vec::new();
+    if config.listener {
+        let listening = LISTENING.load(Ordering::Relaxed);
+        checks.push(Check::new("listener", if listening { Ok(()) } else { Err("监听端口未绑定".into()) }));
+    }
+    if let Some(dir) = &config.output_dir {
+        checks.push(Check::new("output_dir", check_writable(dir)));
+        checks.push(Check::new("free_space", check_free_space(dir, config.min_free_space)));
+    }
+    checks.push(Check::new("draining", if is_draining() { Err("正在停止，不再接受新连接".into()) } else { Ok(()) }));
+    checks
+}
+
+/// 在目录中创建并删除一个探测文件
+fn check_writable(dir: &Path) -> Result<(), String> {
+    let probe = dir.join(format!(".uft-ready-{}", std::process::id()));
+    std::fs::write(&probe, b"")
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|e| format!("{} 不可写: {}", dir.display(), e))
+}
+
+fn check_free_space(dir: &Path, min: u64) -> Result<(), String> {
+    let available = fs2::available_space(dir)
+        .map_err(|e| format!("无法获取 {} 的剩余空间: {}", dir.display(), e))?;
+    if available < min {
+        return Err(format!("剩余空间 {} 字节，低于要求的 {} 字节", available, min));
+    }
+    Ok(())
+}
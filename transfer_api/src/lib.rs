@@ -4,7 +4,9 @@ use tokio::sync::broadcast;
 use warp::Filter;
 use warp::ws::{Message, WebSocket};
 use futures_util::{StreamExt, SinkExt};
-use chrono::Utc;
+
+pub mod health;
+pub mod metrics;
 
 /// 定义各种事件
 #[derive(Debug, serde::Serialize, Clone)]
@@ -23,8 +25,9 @@ lazy_static::lazy_static! {
     static ref GLOBAL_SENDER: Arc<Mutex<Option<EventSender>>> = Arc::new(Mutex::new(None));
 }
 
-/// 初始化 WebSocket API 服务
-pub fn init_ws_server(port: u16) {
+/// 初始化 API 服务：`/ws` 推送传输事件，`/metrics` 以 Prometheus 文本格式输出运行指标，
+/// `/healthz` 和 `/readyz` 供存活和就绪探测。端口绑定失败时返回错误
+pub fn init_ws_server(port: u16) -> Result<(), warp::Error> {
     // 创建广播通道，缓冲 16 条消息
     let (tx, _rx) = broadcast::channel::<TransferEvent>(16);
     *GLOBAL_SENDER.lock().unwrap() = Some(tx.clone());
@@ -37,18 +40,45 @@ pub fn init_ws_server(port: u16) {
             ws.on_upgrade(move |socket| client_connection(socket, tx))
         });
 
-    // 启动 warp 服务
+    // 指标路由：/metrics
+    let metrics_route = warp::path("metrics")
+        .and(warp::get())
+        .map(|| warp::reply::with_header(metrics::render(), "content-type", "text/plain; version=0.0.4"));
+
+    // 探测路由：/healthz 只要服务在运行即返回 200；/readyz 在全部就绪检查通过时返回 200，否则返回 503
+    let healthz_route = warp::path("healthz")
+        .and(warp::get())
+        .map(|| "ok");
+    let readyz_route = warp::path("readyz")
+        .and(warp::get())
+        .map(|| {
+            let checks = health::check();
+            let ready = checks.iter().all(|c| c.ok);
+            let status = if ready { warp::http::StatusCode::OK } else { warp::http::StatusCode::SERVICE_UNAVAILABLE };
+            warp::reply::with_status(warp::reply::json(&serde_json::json!({ "ready": ready, "checks": checks })), status)
+        });
+
+    // 启动 warp 服务，绑定端口后再返回
+    let routes = ws_route.or(metrics_route).or(healthz_route).or(readyz_route);
+    let (bound_tx, bound_rx) = std::sync::mpsc::channel();
     thread::spawn(move || {
         let rt = tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()
             .unwrap();
         rt.block_on(async move {
-            warp::serve(ws_route)
-                .run(([0, 0, 0, 0], port))
-                .await;
+            match warp::serve(routes).try_bind_ephemeral(([0, 0, 0, 0], port)) {
+                Ok((_, server)) => {
+                    let _ = bound_tx.send(Ok(()));
+                    server.await;
+                }
+                Err(e) => {
+                    let _ = bound_tx.send(Err(e));
+                }
+            }
         });
     });
+    bound_rx.recv().expect("API 服务线程意外退出")
 }
 
 /// 处理客户端连接
@@ -114,43 +144,3 @@ pub fn receive_file(output_dir: &str) {
         println!("Received and saved file: {}", file_path);
     }
 }
-
-// transfer-cli/src/main.rs
-use clap::Parser;
-use serde::Serialize;
-use lazy_static::lazy_static;
-
-#[derive(Parser)]
-struct Args {
-    #[clap(subcommand)]
-    cmd: Command,
-    /// 启用 WebSocket API 服务
-    #[clap(long)]
-    ws: bool,
-    /// 服务监听端口
-    #[clap(long, default_value = "3030")]
-    port: u16,
-}
-
-#[derive(clap::Subcommand)]
-enum Command {
-    Send { file: String },
-    Recv {
-        #[clap(long, default_value = ".")]
-        output_dir: String,
-    },
-}
-
-fn main() {
-    let args = Args::parse();
-
-    if args.ws {
-        init_ws_server(args.port);
-        println!("WebSocket API 服务已启动，地址 ws://127.0.0.1:{}/ws", args.port);
-    }
-
-    match args.cmd {
-        Command::Send { file } => send_file(&file),
-        Command::Recv { output_dir } => receive_file(&output_dir),
-    }
-}
@@ -0,0 +1,122 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+/// 握手耗时直方图的桶上限（秒）
+const HANDSHAKE_BUCKETS: [f64; 9] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0];
+
+static BYTES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+static ACTIVE_TRANSFERS: AtomicI64 = AtomicI64::new(0);
+static COMPLETED_TRANSFERS: AtomicU64 = AtomicU64::new(0);
+/// 按错误类型统计的失败次数
+static FAILED_TRANSFERS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+static INTEGRITY_FAILURES: AtomicU64 = AtomicU64::new(0);
+/// 各桶的计数（不累计），最后一项为超过最大上限的部分
+static HANDSHAKE_COUNTS: [AtomicU64; HANDSHAKE_BUCKETS.len() + 1] = [const { AtomicU64::new(0) }; HANDSHAKE_BUCKETS.len() + 1];
+static HANDSHAKE_SUM_MICROS: AtomicU64 = AtomicU64::new(0);
+static QUEUE_DEPTH: AtomicU64 = AtomicU64::new(0);
+
+/// 记录从网络收到的字节数
+pub fn add_bytes_received(n: u64) {
+    BYTES_RECEIVED.fetch_add(n, Ordering::Relaxed);
+}
+
+/// 记录向网络发出的字节数
+pub fn add_bytes_sent(n: u64) {
+    BYTES_SENT.fetch_add(n, Ordering::Relaxed);
+}
+
+/// 进行中的传输，丢弃时从活动传输数中减去
+pub struct ActiveTransfer(());
+
+impl Drop for ActiveTransfer {
+    fn drop(&mut self) {
+        ACTIVE_TRANSFERS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 开始一次传输，返回值须保留到传输结束
+pub fn transfer_started() -> ActiveTransfer {
+    ACTIVE_TRANSFERS.fetch_add(1, Ordering::Relaxed);
+    ActiveTransfer(())
+}
+
+/// 当前进行中的传输数
+pub fn active_transfers() -> i64 {
+    ACTIVE_TRANSFERS.load(Ordering::Relaxed)
+}
+
+pub fn transfer_completed() {
+    COMPLETED_TRANSFERS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// 记录一次失败的传输，`kind` 为错误类型，如 `network`、`integrity`
+pub fn transfer_failed(kind: &str) {
+    *FAILED_TRANSFERS.lock().unwrap().entry(kind.to_string()).or_default() += 1;
+}
+
+/// 记录一次完整性校验失败（解密失败、摘要或分块校验不符）
+pub fn integrity_failure() {
+    INTEGRITY_FAILURES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// 记录一次握手的耗时
+pub fn observe_handshake(elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64();
+    let bucket = HANDSHAKE_BUCKETS.iter().position(|&le| seconds <= le).unwrap_or(HANDSHAKE_BUCKETS.len());
+    HANDSHAKE_COUNTS[bucket].fetch_add(1, Ordering::Relaxed);
+    HANDSHAKE_SUM_MICROS.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+}
+
+/// 设置发送队列中等待发送的任务数
+pub fn set_queue_depth(depth: u64) {
+    QUEUE_DEPTH.store(depth, Ordering::Relaxed);
+}
+
+/// 以 Prometheus 文本格式输出全部指标
+pub fn render() -> String {
+    let mut out = String::new();
+    metric(&mut out, "uft_received_bytes_total", "counter", "从网络收到的字节数（含协议开销）");
+    writeln!(out, "uft_received_bytes_total {}", BYTES_RECEIVED.load(Ordering::Relaxed)).ok();
+    metric(&mut out, "uft_sent_bytes_total", "counter", "向网络发出的字节数（含协议开销）");
+    writeln!(out, "uft_sent_bytes_total {}", BYTES_SENT.load(Ordering::Relaxed)).ok();
+    metric(&mut out, "uft_transfers_active", "gauge", "进行中的传输数");
+    writeln!(out, "uft_transfers_active {}", ACTIVE_TRANSFERS.load(Ordering::Relaxed)).ok();
+    metric(&mut out, "uft_transfers_completed_total", "counter", "成功完成的传输数");
+    writeln!(out, "uft_transfers_completed_total {}", COMPLETED_TRANSFERS.load(Ordering::Relaxed)).ok();
+    metric(&mut out, "uft_transfers_failed_total", "counter", "失败的传输数，按错误类型区分");
+    for (kind, count) in FAILED_TRANSFERS.lock().unwrap().iter() {
+        writeln!(out, "uft_transfers_failed_total{{kind=\"{}\"}} {}", escape_label(kind), count).ok();
+    }
+    metric(&mut out, "uft_integrity_failures_total", "counter", "完整性校验失败次数（解密失败、摘要或分块校验不符）");
+    writeln!(out, "uft_integrity_failures_total {}", INTEGRITY_FAILURES.load(Ordering::Relaxed)).ok();
+
+    metric(&mut out, "uft_handshake_duration_seconds", "histogram", "接收端握手耗时");
+    let mut cumulative = 0;
+    for (le, count) in HANDSHAKE_BUCKETS.iter().zip(&HANDSHAKE_COUNTS) {
+        cumulative += count.load(Ordering::Relaxed);
+        writeln!(out, "uft_handshake_duration_seconds_bucket{{le=\"{}\"}} {}", le, cumulative).ok();
+    }
+    cumulative += HANDSHAKE_COUNTS[HANDSHAKE_BUCKETS.len()].load(Ordering::Relaxed);
+    writeln!(out, "uft_handshake_duration_seconds_bucket{{le=\"+Inf\"}} {}", cumulative).ok();
+    let sum = HANDSHAKE_SUM_MICROS.load(Ordering::Relaxed) as f64 / 1e6;
+    writeln!(out, "uft_handshake_duration_seconds_sum {}", sum).ok();
+    writeln!(out, "uft_handshake_duration_seconds_count {}", cumulative).ok();
+
+    metric(&mut out, "uft_queue_depth", "gauge", "发送队列中等待发送的任务数");
+    writeln!(out, "uft_queue_depth {}", QUEUE_DEPTH.load(Ordering::Relaxed)).ok();
+    out
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).ok();
+    writeln!(out, "# TYPE {} {}", name, kind).ok();
+}
+
+/// 转义标签值中的反斜杠、引号和换行
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
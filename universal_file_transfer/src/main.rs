@@ -1,8 +1,19 @@
 mod service;
 
-use clap::{Parser, Subcommand};
+use clap::{ArgAction, Args, Parser, Subcommand};
+use service::{RecvOptions, SendOptions};
+use service::sync::SyncOptions;
+use service::watch::WatchOptions;
+use service::compression::Compression;
+use service::cryptography::{CipherSuite, Kdf};
+use service::hashing::HashAlgorithm;
+use service::logging::{LogFormat, LogOptions, LogRotation};
+use service::output::OutputFormat;
+use transfer_api::health::Readiness;
 use std::io::{self, ErrorKind};
 use std::net::{IpAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use url::Url;
 
 fn resolve_url_to_ips(url_str: &str) -> io::Result<Vec<IpAddr>> {
@@ -19,78 +30,579 @@ fn resolve_url_to_ips(url_str: &str) -> io::Result<Vec<IpAddr>> {
     Ok(ips)
 }
 
+/// 解析 `主机[:端口]` 形式的地址，未写端口时使用 `default_port`
+fn parse_host_port(s: &str, default_port: u16) -> io::Result<(String, u16)> {
+    match s.rsplit_once(':') {
+        Some((host, port)) => {
+            let port = port.parse::<u16>()
+                .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+            Ok((host.to_string(), port))
+        }
+        None => Ok((s.to_string(), default_port)),
+    }
+}
+
 #[derive(Parser)]
 #[command(author, version, about)]
 struct Cli {
     #[command(subcommand)]
     cmd: Commands,
+    /// 不显示传输进度，日志只输出错误
+    #[arg(short = 'q', long, global = true)]
+    quiet: bool,
+    /// 输出格式：`json` 时标准输出每行一个 JSON 事件，最后一行为命令结果
+    #[arg(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+// 日志参数，对所有子命令有效
+#[derive(Args)]
+struct LogArgs {
+    /// 输出更详细的日志：`-v` 为 info，`-vv` 为 debug，`-vvv` 为 trace；设置了 `RUST_LOG` 时以其为准
+    #[arg(short = 'v', long = "verbose", action = ArgAction::Count, global = true)]
+    verbose: u8,
+    /// 日志格式
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// 日志写入该文件而非标准错误，按 `--log-rotation` 切分，默认记录 info 级别
+    #[arg(long, global = true, value_name = "日志文件")]
+    log_file: Option<PathBuf>,
+    /// 日志文件的切分周期
+    #[arg(long, global = true, value_enum, default_value_t = LogRotation::Daily, requires = "log_file")]
+    log_rotation: LogRotation,
+}
+
+/// 解析 `--password`：只写 `--password` 时从终端读取口令
+fn read_password(arg: Option<Option<String>>) -> anyhow::Result<Option<String>> {
+    let password = match arg {
+        None => return Ok(None),
+        Some(Some(password)) => password,
+        Some(None) => rpassword::prompt_password("请输入口令: ")?,
+    };
+    if password.is_empty() {
+        anyhow::bail!("口令不能为空");
+    }
+    Ok(Some(password))
+}
+
+fn recv_options(
+    allow_sync: bool,
+    allow_delete: bool,
+    allow_delta: bool,
+    insecure: bool,
+    password: Option<Option<String>>,
+    store_encrypted: Option<String>,
+    quiet: bool,
+) -> anyhow::Result<RecvOptions> {
+    Ok(RecvOptions {
+        allow_sync,
+        allow_delete,
+        allow_delta,
+        allow_insecure: insecure,
+        password: read_password(password)?,
+        store_encrypted: store_encrypted.as_deref().map(service::identity::decode_recipient).transpose()?,
+        quiet,
+    })
+}
+
+// 发送类子命令共用的传输参数
+#[derive(Args)]
+struct TransferArgs {
+    /// 加密前使用的压缩算法，已是压缩格式的文件会自动跳过
+    #[arg(long, value_enum, default_value_t = Compression::Zstd)]
+    compress: Compression,
+    /// 压缩级别（zstd: 1-22，gzip: 0-9），默认使用算法推荐值
+    #[arg(long)]
+    level: Option<i32>,
+    /// 增量传输：接收端已有同名文件时只发送变化的块
+    #[arg(long)]
+    delta: bool,
+    /// 加密给指定接收者的公钥（对方 `keys show` 中的接收公钥），中间节点只能原样保存密文
+    #[arg(long, value_name = "接收公钥")]
+    to: Option<String>,
+    /// 文件数据的加密算法，默认按本机是否有 AES 指令自动选择并与接收端协商
+    #[arg(long, value_enum)]
+    cipher: Option<CipherSuite>,
+    /// 允许 `--cipher none` 不加密传输，仅限可信的实验网络
+    #[arg(long)]
+    insecure: bool,
+    /// 完整性校验的摘要算法，默认优先 BLAKE3 并与接收端协商
+    #[arg(long, value_enum)]
+    hash: Option<HashAlgorithm>,
+    /// 用本机身份密钥对文件摘要签名，接收端校验后保存为 `.sig` 签名文件
+    #[arg(long)]
+    sign: bool,
+    /// 与接收端事先约定的口令，口令本身不经网络传输；写 `--password` 不带值时从终端读取
+    #[arg(long, value_name = "口令", num_args = 0..=1, require_equals = true)]
+    password: Option<Option<String>>,
+    /// Argon2id 口令派生使用的内存（KiB）
+    #[arg(long, default_value_t = service::cryptography::ARGON2_MEMORY_KIB)]
+    kdf_memory: u32,
+    /// Argon2id 口令派生的迭代轮数
+    #[arg(long, default_value_t = service::cryptography::ARGON2_ITERATIONS)]
+    kdf_iterations: u32,
+    /// Argon2id 口令派生的并行度
+    #[arg(long, default_value_t = service::cryptography::ARGON2_PARALLELISM)]
+    kdf_parallelism: u32,
+}
+
+impl TransferArgs {
+    fn send_options(&self, quiet: bool) -> anyhow::Result<SendOptions> {
+        let opts = SendOptions {
+            compression: self.compress,
+            level: self.level,
+            delta: self.delta,
+            recipient: self.to.clone(),
+            cipher: self.cipher,
+            insecure: self.insecure,
+            hash: self.hash,
+            sign: self.sign,
+            quiet,
+            password: read_password(self.password.clone())?,
+            kdf: Kdf::Argon2id {
+                memory_kib: self.kdf_memory,
+                iterations: self.kdf_iterations,
+                parallelism: self.kdf_parallelism,
+            },
+            ..Default::default()
+        };
+        // 连接对端前先检查加密参数
+        opts.ciphers()?;
+        opts.kdf.validate()?;
+        Ok(opts)
+    }
 }
 
 #[derive(Subcommand)]
 enum Commands {
     Send {
+        /// 服务器地址；只给出一个参数时视为文件路径，并改用一次性传输码
         server: String,
-        port: u16,
-        file: String,
+        port: Option<u16>,
+        /// 要发送的文件，`-` 表示从标准输入读取
+        file: Option<String>,
+        /// 经 WebSocket 连接接收端，server 为 `ws://` 地址；握手和加密与 TCP 模式相同
         #[arg(long)]
         ws: bool,
+        /// 通过中继发送：此时 server/port 为中继地址，双方使用相同的传输码配对；
+        /// 传输码模式下用于指定自定义传输码
+        #[arg(long, conflicts_with = "ws")]
+        code: Option<String>,
+        /// 传输码模式下使用的中继（主机[:端口]），不指定则在局域网内发现接收端
+        #[arg(long, conflicts_with = "ws")]
+        relay: Option<String>,
+        /// 先写入本地发送队列再发送，失败时按指数退避自动重试，进程重启后可用 `queue run` 继续
+        /// 口令不会写入队列，因此不能与 `--password` 同时使用
+        #[arg(long, conflicts_with_all = ["ws", "code", "password"])]
+        queue: bool,
+        /// 队列任务的最大尝试次数
+        #[arg(long, requires = "queue", default_value_t = service::queue::DEFAULT_MAX_ATTEMPTS)]
+        max_attempts: u32,
+        #[command(flatten)]
+        transfer: TransferArgs,
     },
     Recv {
+        /// 保存目录；只给出一个参数时视为传输码，文件保存到当前目录（`--stdout` 时视为端口）
         output_dir: String,
-        port: u16,
+        port: Option<u16>,
+        /// 以 WebSocket 方式监听，协议与 TCP 模式相同
         #[arg(long)]
         ws: bool,
+        /// 不监听端口，而是连接该中继（主机[:端口]）接收文件
+        #[arg(long, conflicts_with = "ws")]
+        relay: Option<String>,
+        /// 与发送端约定的传输码
+        #[arg(long, requires = "relay")]
+        code: Option<String>,
+        /// 接受目录同步和监听模式的发送端：返回保存目录的文件清单，并按相对路径覆盖已有文件
+        #[arg(long)]
+        allow_sync: bool,
+        /// 允许目录同步的发送端删除保存目录中的多余文件
+        #[arg(long, requires = "allow_sync")]
+        allow_delete: bool,
+        /// 接受增量传输：向发送端返回保存目录中同名文件的块签名，重建后覆盖该文件（`--allow-sync` 时已接受）
+        #[arg(long, conflicts_with = "store_encrypted")]
+        allow_delta: bool,
+        /// 只接收一个连接，把收到的数据写到标准输出
+        #[arg(long, conflicts_with_all = ["ws", "relay", "allow_sync", "allow_delta"])]
+        stdout: bool,
+        /// 接受发送端以 `--cipher none` 发起的不加密传输，仅限可信的实验网络
+        #[arg(long)]
+        insecure: bool,
+        /// 与发送端事先约定的口令；写 `--password` 不带值时从终端读取
+        #[arg(long, value_name = "口令", num_args = 0..=1, require_equals = true)]
+        password: Option<Option<String>>,
+        /// 加密存储：收到的文件校验后重新加密给该接收公钥（`keys show` 中的接收公钥）保存为 .uft 文件，
+        /// 磁盘上不留明文，由持有私钥的一方使用 decrypt 命令解密
+        #[arg(long, value_name = "接收公钥", conflicts_with_all = ["stdout", "allow_delete"])]
+        store_encrypted: Option<String>,
+        /// 在该端口提供 HTTP 接口：`/metrics` 输出 Prometheus 格式的运行指标，`/healthz`、`/readyz` 供存活和就绪探测
+        #[arg(long, value_name = "端口", conflicts_with = "stdout")]
+        api_port: Option<u16>,
+        /// 保存目录所在磁盘的剩余空间（MiB）低于该值时 `/readyz` 报告未就绪
+        #[arg(long, value_name = "MiB", default_value_t = 512, requires = "api_port")]
+        min_free_space: u64,
+    },
+    /// 把本地目录同步到接收端的保存目录，只传输新增或变化的文件
+    Sync {
+        local_dir: String,
+        server: String,
+        port: u16,
+        /// 删除接收端多出的文件（接收端需启用 --allow-delete）
+        #[arg(long)]
+        delete: bool,
+        /// 只打印同步计划，不做任何修改
+        #[arg(long)]
+        dry_run: bool,
+        #[command(flatten)]
+        transfer: TransferArgs,
+    },
+    /// 监听目录，新增或修改的文件写入稳定后自动发送
+    Watch {
+        dir: String,
+        server: String,
+        port: u16,
+        /// 文件在这么多秒内没有变化才视为写入完成
+        #[arg(long, default_value_t = 2)]
+        settle: u64,
+        /// 确认送达后把文件移动到该目录
+        #[arg(long)]
+        move_to: Option<String>,
+        /// 记录已发送文件的状态文件，默认位于监听目录下
+        #[arg(long)]
+        state: Option<String>,
+        #[command(flatten)]
+        transfer: TransferArgs,
+    },
+    /// 查看和管理本地发送队列
+    Queue {
+        #[command(subcommand)]
+        cmd: QueueCommands,
+    },
+    /// 离线加密文件为 .uft 文件（加密给 `--to` 指定的接收者或使用 `--password` 口令），格式与网络传输相同
+    Encrypt {
+        file: String,
+        /// 输出路径，默认为同目录下的 `<文件名>.uft`
+        #[arg(short = 'o', long = "out", value_name = "输出路径")]
+        out: Option<String>,
+        #[command(flatten)]
+        transfer: TransferArgs,
+    },
+    /// 解密 .uft 文件：加密给本机的文件使用本机接收私钥，口令保护的文件使用 `--password`
+    Decrypt {
+        file: String,
+        /// 输出路径，默认保存到加密文件所在目录并使用原始文件名
+        #[arg(short = 'o', long = "out", value_name = "输出路径")]
+        out: Option<String>,
+        /// 加密时使用的口令；写 `--password` 不带值时从终端读取
+        #[arg(long, value_name = "口令", num_args = 0..=1, require_equals = true)]
+        password: Option<Option<String>>,
+    },
+    /// 计算文件的摘要（`sha256sum` 格式），或按校验文件逐个校验
+    Hash {
+        #[arg(required_unless_present = "check")]
+        files: Vec<String>,
+        /// 按该校验文件中的摘要校验文件
+        #[arg(short, long, value_name = "校验文件", conflicts_with = "files")]
+        check: Option<String>,
+        /// 摘要算法
+        #[arg(short, long, value_enum, default_value_t = HashAlgorithm::Sha256)]
+        algorithm: HashAlgorithm,
+    },
+    /// 用 `.sig` 签名文件校验文件：摘要须一致，签名者须为本机或已信任的对端
+    Verify {
+        file: String,
+        /// 签名文件，默认为 `<文件>.sig`
+        #[arg(long, value_name = "签名文件")]
+        sig: Option<String>,
+    },
+    /// 查看本机身份并管理已信任的对端公钥
+    Keys {
+        #[command(subcommand)]
+        cmd: KeysCommands,
+    },
+    /// 运行中继服务，为双方都处于 NAT 后的收发端转发加密数据
+    Relay {
+        #[arg(default_value_t = service::relay::DEFAULT_PORT)]
+        port: u16,
     },
 }
 
+#[derive(Subcommand)]
+enum QueueCommands {
+    /// 列出队列中的任务
+    List,
+    /// 立即重试任务；不指定任务时重试全部失败任务
+    Retry {
+        id: Option<String>,
+    },
+    /// 从队列中移除任务
+    Cancel {
+        id: String,
+    },
+    /// 发送队列中到期的任务，直到没有待发送任务为止
+    Run {
+        /// 持续运行，处理之后新加入的任务
+        #[arg(long)]
+        follow: bool,
+        /// 在该端口提供 HTTP 接口：`/metrics` 输出 Prometheus 格式的运行指标，`/healthz`、`/readyz` 供存活和就绪探测
+        #[arg(long, value_name = "端口")]
+        api_port: Option<u16>,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeysCommands {
+    /// 显示本机公钥、指纹和已信任的对端
+    Show,
+    /// 信任对端（主机:端口）的公钥，替换已有记录
+    Trust {
+        peer: String,
+        public_key: String,
+    },
+    /// 删除对端记录，下次连接时重新按首次信任处理
+    Revoke {
+        peer: String,
+    },
+}
+
+impl Commands {
+    /// 子命令名称，用于 JSON 输出的命令结果
+    fn name(&self) -> &'static str {
+        match self {
+            Commands::Send { .. } => "send",
+            Commands::Recv { .. } => "recv",
+            Commands::Sync { .. } => "sync",
+            Commands::Watch { .. } => "watch",
+            Commands::Queue { .. } => "queue",
+            Commands::Encrypt { .. } => "encrypt",
+            Commands::Decrypt { .. } => "decrypt",
+            Commands::Hash { .. } => "hash",
+            Commands::Verify { .. } => "verify",
+            Commands::Keys { .. } => "keys",
+            Commands::Relay { .. } => "relay",
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    service::output::init(cli.output);
+    let _log_guard = service::logging::init(&LogOptions {
+        verbose: cli.log.verbose,
+        quiet: cli.quiet,
+        format: cli.log.log_format,
+        file: cli.log.log_file.clone(),
+        rotation: cli.log.log_rotation,
+    })?;
+    let command = cli.cmd.name();
+    let started = Instant::now();
+    let result = run(cli).await;
+    service::output::finish(command, started, &result);
+    result
+}
 
-    // 如果是 Send 且启用 ws，则解析 URL 第一个 IP
+async fn run(cli: Cli) -> anyhow::Result<()> {
+    // 如果是 Send 且启用 ws，并以 ws:// 地址给出服务器，则解析 URL 第一个 IP
     let server_addr = match &cli.cmd {
-        Commands::Send { server, ws: true, .. } => {
+        Commands::Send { server, ws: true, .. } if server.contains("://") => {
             resolve_url_to_ips(server)?.into_iter().next()
         }
         _ => None,
     };
 
+    let quiet = cli.quiet;
     match cli.cmd {
-        Commands::Recv { output_dir, port, ws } => {
-            if ws {
-                ws_recv(&output_dir, port).await?;
+        Commands::Recv { output_dir: port, port: None, stdout: true, insecure, password, .. } => {
+            let port = port.parse::<u16>()
+                .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+            tcp_recv_stdout(port, &recv_options(false, false, false, insecure, password, None, quiet)?).await?;
+        }
+        Commands::Recv { port: Some(port), stdout: true, insecure, password, .. } => {
+            tcp_recv_stdout(port, &recv_options(false, false, false, insecure, password, None, quiet)?).await?;
+        }
+        Commands::Recv { output_dir: code, port: None, relay, allow_delta, insecure, password, store_encrypted, api_port, min_free_space, .. } => {
+            let relay = relay.map(|r| parse_host_port(&r, service::relay::DEFAULT_PORT)).transpose()?;
+            let opts = recv_options(false, false, allow_delta, insecure, password, store_encrypted, quiet)?;
+            if let Some(api_port) = api_port {
+                serve_recv_api(api_port, ".", false, min_free_space)?;
+            }
+            code_recv(".", &code, relay.as_ref().map(|(h, p)| (h.as_str(), *p)), &opts).await?;
+        }
+        Commands::Recv { output_dir, port: Some(port), ws, relay, code, allow_sync, allow_delete, allow_delta, insecure, password, store_encrypted, api_port, min_free_space, .. } => {
+            let opts = recv_options(allow_sync, allow_delete, allow_delta, insecure, password, store_encrypted, quiet)?;
+            let relayed = relay.is_some() && code.is_some();
+            if let Some(api_port) = api_port {
+                serve_recv_api(api_port, &output_dir, !relayed, min_free_space)?;
+            }
+            if let (Some(relay), Some(code)) = (relay, code) {
+                let (host, port) = parse_host_port(&relay, port)?;
+                relay_recv(&output_dir, &host, port, &code, &opts).await?;
             } else {
-                tcp_recv(&output_dir, port).await?;
+                if ws {
+                    ws_recv(&output_dir, port, &opts).await?;
+                } else {
+                    tcp_recv(&output_dir, port, &opts).await?;
+                }
             }
         }
-        Commands::Send { server, port, file, ws } => {
+        Commands::Send { server: file, port: None, file: None, code, relay, transfer, .. } => {
+            let relay = relay.map(|r| parse_host_port(&r, service::relay::DEFAULT_PORT)).transpose()?;
+            let opts = transfer.send_options(quiet)?;
+            code_send(&file, code.as_deref(), relay.as_ref().map(|(h, p)| (h.as_str(), *p)), &opts).await?;
+        }
+        Commands::Send { server, port: Some(port), file: Some(file), ws, code, queue, max_attempts, transfer, .. } => {
             let target = server_addr.map_or(server.clone(), |ip| ip.to_string());
-            if ws {
-                ws_send(&target, port, &file).await?;
+            let opts = transfer.send_options(quiet)?;
+            if queue {
+                queue_send(&target, port, &file, &opts, max_attempts).await?;
+            } else if let Some(code) = code {
+                relay_send(&target, port, &file, &code, &opts).await?;
+            } else if ws {
+                ws_send(&target, port, &file, &opts).await?;
             } else {
-                tcp_send(&target, port, &file).await?;
+                tcp_send(&target, port, &file, &opts).await?;
+            }
+        }
+        Commands::Send { .. } => {
+            anyhow::bail!("用法：send <服务器地址> <端口> <文件路径>，或 send <文件路径> 使用传输码");
+        }
+        Commands::Sync { local_dir, server, port, delete, dry_run, transfer } => {
+            let send = transfer.send_options(quiet)?;
+            sync(&local_dir, &server, port, &SyncOptions { delete, dry_run, send }).await?;
+        }
+        Commands::Watch { dir, server, port, settle, move_to, state, transfer } => {
+            let opts = WatchOptions {
+                settle: Duration::from_secs(settle),
+                move_to: move_to.map(PathBuf::from),
+                state_file: state.map(PathBuf::from),
+                send: transfer.send_options(quiet)?,
+            };
+            watch(&dir, &server, port, &opts).await?;
+        }
+        Commands::Queue { cmd } => match cmd {
+            QueueCommands::List => service::queue::print_list()?,
+            QueueCommands::Retry { id } => service::queue::retry(id.as_deref())?,
+            QueueCommands::Cancel { id } => service::queue::cancel(&id)?,
+            QueueCommands::Run { follow, api_port } => {
+                if let Some(api_port) = api_port {
+                    service::metrics::serve(api_port, Readiness {
+                        listener: false,
+                        output_dir: Some(service::queue::spool_dir()),
+                        min_free_space: 0,
+                    })?;
+                }
+                service::queue::run(None, follow).await?;
             }
+        },
+        Commands::Encrypt { file, out, transfer } => {
+            service::sealed::encrypt(&file, out.as_deref(), &transfer.send_options(quiet)?)?;
+        }
+        Commands::Decrypt { file, out, password } => {
+            let password = read_password(password)?;
+            service::sealed::open(&file, out.as_deref(), password.as_deref())?;
+        }
+        Commands::Hash { files, check, algorithm } => match check {
+            Some(sum_file) => service::checksum::check(&sum_file, algorithm)?,
+            None => service::checksum::print(&files, algorithm)?,
+        },
+        Commands::Verify { file, sig } => service::signing::verify_file(&file, sig.as_deref())?,
+        Commands::Keys { cmd } => match cmd {
+            KeysCommands::Show => service::identity::show()?,
+            KeysCommands::Trust { peer, public_key } => service::identity::trust(&peer, &public_key)?,
+            KeysCommands::Revoke { peer } => service::identity::revoke(&peer)?,
+        },
+        Commands::Relay { port } => {
+            relay(port).await?;
         }
     }
     Ok(())
 }
 
+/// 接收端：启动 HTTP 接口，就绪检查保存目录（及监听端口，`listener` 为真时）
+fn serve_recv_api(api_port: u16, output_dir: &str, listener: bool, min_free_space: u64) -> anyhow::Result<()> {
+    service::metrics::serve(api_port, Readiness {
+        listener,
+        output_dir: Some(PathBuf::from(output_dir)),
+        min_free_space: min_free_space.saturating_mul(1024 * 1024),
+    })
+}
+
 /// 接收端：监听 TCP，保存到文件
-async fn tcp_recv(output_dir: &str, port: u16) -> anyhow::Result<()> {
-    service::tcp_recv(output_dir, port).await
+async fn tcp_recv(output_dir: &str, port: u16, opts: &RecvOptions) -> anyhow::Result<()> {
+    service::tcp_recv(output_dir, port, opts).await
+}
+
+/// 接收端：监听 TCP，把收到的数据写到标准输出
+async fn tcp_recv_stdout(port: u16, opts: &RecvOptions) -> anyhow::Result<()> {
+    service::tcp_recv_stdout(port, opts).await
 }
 
 /// 发送端：连接 TCP，读取文件并发送
-async fn tcp_send(server: &str, port: u16, file_path: &str) -> anyhow::Result<()> {
-    service::tcp_send(server, port, file_path).await
+async fn tcp_send(server: &str, port: u16, file_path: &str, opts: &SendOptions) -> anyhow::Result<()> {
+    service::tcp_send(server, port, file_path, opts).await
+}
+
+/// 发送端：加入发送队列并发送，失败时自动重试
+async fn queue_send(server: &str, port: u16, file_path: &str, opts: &SendOptions, max_attempts: u32) -> anyhow::Result<()> {
+    let job = service::queue::enqueue(file_path, server, port, opts, max_attempts)?;
+    service::queue::run(Some(&job.id), false).await
 }
 
 /// 接收端：监听 WebSocket，保存到文件
-async fn ws_recv(output_dir: &str, port: u16) -> anyhow::Result<()> {
-    service::ws_recv(output_dir, port).await
+async fn ws_recv(output_dir: &str, port: u16, opts: &RecvOptions) -> anyhow::Result<()> {
+    service::ws_recv(output_dir, port, opts).await
 }
 
 /// 发送端：通过 WebSocket 发送文件
-async fn ws_send(server: &str, port: u16, file_path: &str) -> anyhow::Result<()> {
-    service::ws_send(server, port, file_path).await
+async fn ws_send(server: &str, port: u16, file_path: &str, opts: &SendOptions) -> anyhow::Result<()> {
+    service::ws_send(server, port, file_path, opts).await
+}
+
+/// 接收端：经中继接收文件
+async fn relay_recv(output_dir: &str, server: &str, port: u16, code: &str, opts: &RecvOptions) -> anyhow::Result<()> {
+    service::relay_recv(output_dir, server, port, code, opts).await
+}
+
+/// 发送端：经中继发送文件
+async fn relay_send(server: &str, port: u16, file_path: &str, code: &str, opts: &SendOptions) -> anyhow::Result<()> {
+    service::relay_send(server, port, file_path, code, opts).await
+}
+
+/// 中继：配对收发双方并转发字节
+async fn relay(port: u16) -> anyhow::Result<()> {
+    service::relay::run(port).await
+}
+
+/// 接收端：凭传输码接收文件
+async fn code_recv(
+    output_dir: &str,
+    code: &str,
+    relay: Option<(&str, u16)>,
+    opts: &RecvOptions,
+) -> anyhow::Result<()> {
+    service::code_recv(output_dir, code, relay, opts).await
+}
+
+/// 发送端：生成传输码并发送文件
+async fn code_send(
+    file_path: &str,
+    code: Option<&str>,
+    relay: Option<(&str, u16)>,
+    opts: &SendOptions,
+) -> anyhow::Result<()> {
+    service::code_send(file_path, code, relay, opts).await
+}
+
+/// 发送端：把本地目录同步到接收端
+async fn sync(local_dir: &str, server: &str, port: u16, opts: &SyncOptions) -> anyhow::Result<()> {
+    service::sync::run(local_dir, server, port, opts).await
+}
+
+/// 发送端：监听目录并自动发送
+async fn watch(dir: &str, server: &str, port: u16, opts: &WatchOptions) -> anyhow::Result<()> {
+    service::watch::run(dir, server, port, opts).await
 }
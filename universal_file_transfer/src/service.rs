@@ -1,345 +1,1376 @@
-use futures_util::{SinkExt, StreamExt};
 use serde_json::json;
 use tokio::fs;
 use tokio::fs::File;
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio_tungstenite::tungstenite::Message;
-use tokio_tungstenite::{accept_async, connect_async};
+use tracing::{Instrument, debug, error, info, warn};
 
-mod cryptography;
+pub(crate) mod checksum;
+mod chunked;
+mod code;
+pub(crate) mod compression;
+pub(crate) mod cryptography;
+mod delta;
+mod discovery;
+pub(crate) mod hashing;
+pub(crate) mod identity;
+pub(crate) mod logging;
+pub(crate) mod metrics;
+pub(crate) mod output;
+mod pake;
+pub(crate) mod progress;
+mod protocol;
+pub(crate) mod queue;
+pub(crate) mod relay;
+pub(crate) mod sealed;
+pub(crate) mod signing;
+pub(crate) mod sync;
+pub(crate) mod watch;
+mod websocket;
 
-/// 异步：TCP 模式下接收文件并保存
-pub(crate) async fn tcp_recv(output_dir: &str, port: u16) -> anyhow::Result<()> {
-    use tokio::net::TcpListener;
-    use tokio::io::AsyncReadExt;
-    use std::path::Path;
-    use std::fs::File;
-    use std::io::Write;
-    use cryptography;
-    use anyhow::Context;
+use compression::Compression;
+use cryptography::{CipherSuite, Kdf};
+use hashing::HashAlgorithm;
+use output::{FileRecord, say};
+
+/// 以此作为文件路径时从标准输入读取数据
+const STDIN_PATH: &str = "-";
+/// 标准输入数据流在接收端保存时使用的默认文件名
+const STDIN_NAME: &str = "stdin.bin";
+
+/// 传输码模式下等待局域网发现的最长时间
+const DISCOVERY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+
+/// 本地数据目录（发送队列等），优先使用环境变量 `UFT_HOME`，否则为用户主目录下的 `.uft`
+pub(crate) fn data_dir() -> std::path::PathBuf {
+    if let Some(dir) = std::env::var_os("UFT_HOME") {
+        return dir.into();
+    }
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(|| ".".into())
+        .join(".uft")
+}
+
+/// 发送端可调选项
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub(crate) struct SendOptions {
+    /// 首选压缩算法，最终以握手协商结果为准
+    pub(crate) compression: Compression,
+    /// 压缩级别，未指定时使用算法默认值
+    pub(crate) level: Option<i32>,
+    /// 增量传输：接收端已有同名文件时只发送变化的块
+    pub(crate) delta: bool,
+    /// 目录同步时文件在接收端的相对路径；设置后接收端按该路径覆盖保存
+    pub(crate) sync_path: Option<String>,
+    /// 接收者公钥（十六进制）：会话密钥改用该公钥加密，只有持有对应私钥的一方能解密
+    pub(crate) recipient: Option<String>,
+    /// 指定文件数据的加密算法，未指定时按本机偏好与接收端协商
+    pub(crate) cipher: Option<CipherSuite>,
+    /// 允许 `CipherSuite::None` 不加密传输
+    pub(crate) insecure: bool,
+    /// 与接收端共享的口令，用于保护会话密钥；不写入发送队列
+    #[serde(skip)]
+    pub(crate) password: Option<String>,
+    /// 由口令派生密钥的算法和参数
+    pub(crate) kdf: Kdf,
+    /// 指定完整性摘要算法，未指定时按本机偏好与接收端协商
+    pub(crate) hash: Option<HashAlgorithm>,
+    /// 用本机身份密钥对文件摘要签名，接收端校验后保存为 `.sig` 签名文件
+    pub(crate) sign: bool,
+    /// 不显示传输进度
+    pub(crate) quiet: bool,
+    /// 所属批量传输（如目录同步）的总体进度
+    #[serde(skip)]
+    pub(crate) batch: Option<std::sync::Arc<progress::Batch>>,
+}
+
+impl SendOptions {
+    /// 握手时提出的加密算法偏好
+    pub(crate) fn ciphers(&self) -> anyhow::Result<Vec<CipherSuite>> {
+        match self.cipher {
+            Some(CipherSuite::None) if !self.insecure => {
+                anyhow::bail!("--cipher none 会以明文传输文件，需同时指定 --insecure")
+            }
+            Some(cipher) => Ok(vec![cipher]),
+            None => Ok(CipherSuite::preferred()),
+        }
+    }
+
+    /// 握手时提出的摘要算法偏好
+    pub(crate) fn hashes(&self) -> Vec<HashAlgorithm> {
+        match self.hash {
+            Some(hash) => vec![hash],
+            None => HashAlgorithm::PREFERRED.to_vec(),
+        }
+    }
+
+    /// 握手时声明的口令派生参数，未指定口令时为 `None`；超出接收端接受的范围时提前报错
+    pub(crate) fn peer_kdf(&self) -> anyhow::Result<Option<Kdf>> {
+        if self.password.is_none() {
+            return Ok(None);
+        }
+        self.kdf.validate_peer()?;
+        Ok(Some(self.kdf))
+    }
+}
+
+/// 接收端可调选项
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RecvOptions {
+    /// 接受目录同步（交换清单）和按相对路径覆盖文件的同步传输
+    pub(crate) allow_sync: bool,
+    /// 允许目录同步的发送端删除保存目录中的多余文件
+    pub(crate) allow_delete: bool,
+    /// 接受增量传输：向发送端返回同名文件的块签名，重建后覆盖该文件
+    pub(crate) allow_delta: bool,
+    /// 接受发送端以 `--cipher none --insecure` 发起的不加密传输
+    pub(crate) allow_insecure: bool,
+    /// 与发送端共享的口令
+    pub(crate) password: Option<String>,
+    /// 加密存储：收到的文件校验后重新加密给该接收公钥再保存，磁盘上不留明文
+    pub(crate) store_encrypted: Option<x25519_dalek::PublicKey>,
+    /// 不显示传输进度
+    pub(crate) quiet: bool,
+}
+
+impl RecvOptions {
+    /// 是否接受增量传输：增量传输会泄露同名文件的块签名并覆盖该文件，须由接收端以 `--allow-delta` 启用，
+    /// `--allow-sync` 已允许发送端按路径覆盖文件，同样接受；加密存储模式下本地不保留明文，无法提供块签名
+    fn accepts_delta(&self) -> bool {
+        self.store_encrypted.is_none() && (self.allow_delta || self.allow_sync)
+    }
+
+    /// 是否接受目录同步和监听模式：须以 `--allow-sync` 启动，加密存储模式不支持
+    fn accepts_sync(&self) -> bool {
+        self.store_encrypted.is_none() && self.allow_sync
+    }
+}
 
+/// 异步：TCP 模式下接收文件并保存
+pub(crate) async fn tcp_recv(output_dir: &str, port: u16, opts: &RecvOptions) -> anyhow::Result<()> {
     // 绑定监听 TCP 端口
     let listener = TcpListener::bind(("0.0.0.0", port)).await?;
-    println!("TCP 模式：监听端口 {}...", port);
+    say!("TCP 模式：监听端口 {}...", port);
+    output::event("listening", json!({ "mode": "tcp", "port": port }));
+    transfer_api::health::set_listening(true);
 
     loop {
-        // 接受新的连接
-        let (mut socket, addr) = listener.accept().await?;
-        println!("已与 {} 建立 TCP 连接", addr);
+        // 接受新的连接；按 Ctrl+C 时停止接受，等进行中的传输完成后退出
+        let (socket, addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        say!("已与 {} 建立 TCP 连接", addr);
+        output::event("connected", json!({ "peer": addr.to_string() }));
 
         let output_dir = output_dir.to_string();
-        // 为每个连接创建独立任务
+        let opts = opts.clone();
+        // 为每个连接创建独立任务，该连接上的日志都带有对端地址
+        let span = tracing::info_span!("connection", peer = %addr);
         tokio::spawn(async move {
-            let client_addr = addr;
-            let result: anyhow::Result<()> = async {
-                // 1. 接收文件名长度及文件名
-                let mut len_buf = [0u8; 4];
-                socket.read_exact(&mut len_buf).await?;
-                let filename_len = u32::from_be_bytes(len_buf) as usize;
-                let mut filename_buf = vec![0u8; filename_len];
-                socket.read_exact(&mut filename_buf).await?;
-                let filename = String::from_utf8(filename_buf)
-                    .unwrap_or_else(|_| "received.bin".to_string());
-
-                // 2. 接收 SHA256 校验值（64 字节十六进制）
-                let mut sha_buf = vec![0u8; 64];
-                socket.read_exact(&mut sha_buf).await?;
-                let sha_hex = String::from_utf8(sha_buf).unwrap_or_default();
-
-                // 3. 接收加密参数：salt、nonce、ciphertext（均为十六进制），以及 16 字节密码文本
-                let mut salt_hex_buf = [0u8; 32];
-                socket.read_exact(&mut salt_hex_buf).await?;
-                let salt_hex = String::from_utf8(salt_hex_buf.to_vec()).unwrap_or_default();
-
-                let mut nonce_hex_buf = [0u8; 24];
-                socket.read_exact(&mut nonce_hex_buf).await?;
-                let nonce_hex = String::from_utf8(nonce_hex_buf.to_vec()).unwrap_or_default();
-
-                let mut ct_hex_buf = [0u8; 64];
-                socket.read_exact(&mut ct_hex_buf).await?;
-                let ct_hex = String::from_utf8(ct_hex_buf.to_vec()).unwrap_or_default();
-
-                let mut password_b_buf = [0u8; 16];
-                socket.read_exact(&mut password_b_buf).await?;
-                let password_b = String::from_utf8(password_b_buf.to_vec()).unwrap_or_default();
-
-                // 4. 解密得到实际文件加密密码
-                let pt = cryptography::decrypt_data(&password_b, &salt_hex, &nonce_hex, &ct_hex).unwrap();
-                let password = String::from_utf8(pt).unwrap_or_default();
-
-                // 5. 持续读取剩余的文件数据（加密后的内容）
-                let mut data_buffer: Vec<u8> = Vec::new();
-                let mut buf = [0u8; 4096];
-                loop {
-                    let n = socket.read(&mut buf).await?;
-                    if n == 0 {
-                        break;
+            info!("已建立 TCP 连接");
+            let _active = transfer_api::metrics::transfer_started();
+            let result = recv_stream(metrics::Counted::new(socket), &output_dir, &addr, None, &opts).await;
+            metrics::record_result(&result);
+            if let Err(e) = result {
+                error!("处理客户端时出错: {:#}", e);
+            }
+        }.instrument(span));
+    }
+
+    drop(listener);
+    drain().await
+}
+
+/// 停止接收：不再就绪，等待进行中的传输完成；再次按 Ctrl+C 时立即退出
+async fn drain() -> anyhow::Result<()> {
+    use transfer_api::metrics::active_transfers;
+
+    transfer_api::health::set_draining(true);
+    transfer_api::health::set_listening(false);
+    if active_transfers() > 0 {
+        say!("正在停止：等待 {} 个进行中的传输完成，再次按 Ctrl+C 立即退出", active_transfers());
+        let finished = async {
+            while active_transfers() > 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            }
+        };
+        tokio::select! {
+            _ = finished => {}
+            _ = tokio::signal::ctrl_c() => anyhow::bail!("已中断 {} 个进行中的传输", active_transfers()),
+        }
+    }
+    say!("接收端已停止");
+    Ok(())
+}
+
+/// 执行一项进行中的工作：期间按 Ctrl+C 时进入停止流程（不再就绪，调用方不再开始新的工作），
+/// 仍等该工作完成；再次按 Ctrl+C 时立即中止
+pub(crate) async fn finish_or_interrupt<T>(work: impl std::future::Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+    tokio::pin!(work);
+    tokio::select! {
+        result = &mut work => return result,
+        _ = tokio::signal::ctrl_c() => {}
+    }
+    transfer_api::health::set_draining(true);
+    say!("正在停止：等待进行中的传输完成，再次按 Ctrl+C 立即退出");
+    tokio::select! {
+        result = &mut work => result,
+        _ = tokio::signal::ctrl_c() => anyhow::bail!("已中断进行中的传输"),
+    }
+}
+
+/// 接收一个已配对的连接（中继和传输码模式），计入传输指标；按 Ctrl+C 时等传输完成后退出。
+/// 只接收这一个传输，结束后即不再就绪
+async fn recv_one<S>(
+    stream: S,
+    output_dir: &str,
+    addr: &std::net::SocketAddr,
+    code_key: &cryptography::SessionKey,
+    opts: &RecvOptions,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let _active = transfer_api::metrics::transfer_started();
+    let result = finish_or_interrupt(recv_stream(stream, output_dir, addr, Some(code_key), opts)).await;
+    metrics::record_result(&result);
+    transfer_api::health::set_draining(true);
+    result
+}
+
+/// 异步：通过中继接收文件，传输码用于配对，并经 PAKE 协商出保护会话密钥的共享密钥
+pub(crate) async fn relay_recv(
+    output_dir: &str,
+    server: &str,
+    port: u16,
+    code: &str,
+    opts: &RecvOptions,
+) -> anyhow::Result<()> {
+    let mut stream = relay::connect(server, port, code, relay::Role::Receiver).await?;
+    let addr = stream.peer_addr()?;
+    let code_key = pake::exchange(&mut stream, code, &code::channel_id(code), pake::Role::Receiver).await?;
+    recv_one(stream, output_dir, &addr, &code_key, opts).await
+}
+
+/// 异步：传输码模式下接收文件，经中继或局域网发现找到发送端
+pub(crate) async fn code_recv(
+    output_dir: &str,
+    code: &str,
+    relay: Option<(&str, u16)>,
+    opts: &RecvOptions,
+) -> anyhow::Result<()> {
+    if !code::is_valid(code) {
+        anyhow::bail!("无效的传输码: {}", code);
+    }
+    if let Some((server, port)) = relay {
+        return relay_recv(output_dir, server, port, code, opts).await;
+    }
+
+    let channel = code::channel_id(code);
+    let addr = discovery::find(&channel, DISCOVERY_TIMEOUT).await?;
+    let mut stream = TcpStream::connect(addr).await?;
+    say!("已连接发送端 {}", addr);
+    let code_key = pake::exchange(&mut stream, code, &channel, pake::Role::Receiver).await?;
+    recv_one(stream, output_dir, &addr, &code_key, opts).await
+}
+
+/// 处理一个已建立的连接：完成握手后按用途分发，文件传输结束时向发送端回执处理结果。
+/// `code_key` 为传输码配对得到的共享密钥，会话密钥的保护方式见 [`KeyProtection`]
+#[tracing::instrument(name = "transfer", skip_all, fields(id = tracing::field::Empty, file = tracing::field::Empty))]
+async fn recv_stream<S>(
+    mut socket: S,
+    output_dir: &str,
+    client_addr: &std::net::SocketAddr,
+    code_key: Option<&cryptography::SessionKey>,
+    opts: &RecvOptions,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // 握手：从发送端的偏好中选定压缩和加密算法，并交换身份签名
+    let Accepted { hello, agreed, shared } =
+        server_handshake(&mut socket, opts.accepts_delta(), opts.accepts_sync(), opts.allow_insecure).await?;
+    let protection = KeyProtection::new(opts.password.as_deref(), code_key, &shared);
+    if hello.mode == protocol::Mode::Manifest {
+        if opts.store_encrypted.is_some() {
+            anyhow::bail!("加密存储模式不支持目录同步");
+        }
+        if !opts.allow_sync {
+            anyhow::bail!("接收端未启用 --allow-sync，拒绝目录同步");
+        }
+        return sync::serve_manifest(&mut socket, output_dir, &shared, opts).await;
+    }
+
+    let started = std::time::Instant::now();
+    let result = recv_file(&mut socket, &hello, &agreed, output_dir, client_addr, protection, opts).await;
+    // 发送端可能已断开，回执写入失败时忽略
+    protocol::write_frame(&mut socket, &protocol::Receipt::from_result(&result)).await.ok();
+    if result.is_ok() {
+        info!(size = hello.size, elapsed_ms = started.elapsed().as_millis() as u64, "文件接收完成");
+    }
+    result
+}
+
+/// 读取并保存一个文件，`hello`/`agreed` 为已完成的握手结果。
+/// `opts.store_encrypted` 不为 `None` 时文件校验后重新加密给该公钥保存为 `.uft` 文件
+async fn recv_file<S>(
+    socket: &mut S,
+    hello: &protocol::ClientHello,
+    agreed: &protocol::ServerHello,
+    output_dir: &str,
+    client_addr: &std::net::SocketAddr,
+    protection: KeyProtection<'_>,
+    opts: &RecvOptions,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    use std::path::{Path, PathBuf};
+    use std::fs::File;
+    use anyhow::Context;
+
+    let sync_file = hello.mode == protocol::Mode::SyncFile;
+    let store_to = opts.store_encrypted.as_ref();
+    // 同步传输会按对端给出的相对路径覆盖已有文件，须由接收端显式启用
+    if sync_file && !opts.allow_sync {
+        anyhow::bail!("接收端未启用 --allow-sync，拒绝按相对路径覆盖文件");
+    }
+
+    // 1-4. 接收文件头并解密得到会话密钥
+    let Header { name: raw_name, digest, root, key, raw } =
+        read_header(socket, protection, hello, agreed).await?;
+    // 文件头和传输参数作为附加认证数据，被篡改时解密失败
+    let aad = protocol::header_aad(&raw, hello, agreed);
+    let signature = read_signature(socket, hello, agreed, &digest).await?
+        .map(|(signature, signer)| {
+            say!("文件签名有效，签名者 {}", signer);
+            signature
+        });
+    // 目录同步时保留相对路径，否则只取文件名部分，防止路径穿越
+    let filename = if sync_file {
+        sync::sanitize_relative(&raw_name)
+            .ok_or_else(|| anyhow::anyhow!("非法的同步路径: {}", raw_name))?
+    } else {
+        Path::new(&raw_name).file_name()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("received.bin"))
+    };
+    tracing::Span::current().record("file", tracing::field::display(filename.display()));
+
+    // 加密给其他接收者的文件：本机只做中转存储，原样保存密文
+    let key = match key {
+        FileKey::Session(key) => key,
+        FileKey::Foreign(key) => {
+            if hello.mode != protocol::Mode::File {
+                anyhow::bail!("目录同步和数据流模式不支持中转存储加密给其他接收者的文件");
+            }
+            let mut progress = progress::Progress::new(&filename.to_string_lossy(), None, opts.quiet);
+            let sealed_name = format!("{}.{}", filename.to_string_lossy(), sealed::EXTENSION);
+            let target = Path::new(output_dir).join(generate_unique_filename(output_dir, &sealed_name, client_addr));
+            let recipient = key.recipient.clone();
+            let header = sealed::SealedHeader {
+                name: filename.to_string_lossy().to_string(),
+                digest,
+                hash: agreed.hash,
+                compression: agreed.compression,
+                cipher: agreed.cipher,
+                key: Some(key),
+                passphrase: None,
+                header: hex::encode(&raw),
+                context: protocol::HeaderContext::new(hello, agreed),
+            };
+            // 分块的数据边收边写，不经内存整体缓存
+            let result = async {
+                let mut out = sealed::create(&target, &header)?;
+                chunked::copy_chunks(socket, &mut out, &mut progress).await
+            }.await;
+            result.inspect_err(|_| { std::fs::remove_file(&target).ok(); })
+                .with_context(|| format!("无法写入文件 {}", target.display()))?;
+            say!("文件加密给了接收者 {}，已原样保存为 {}（由接收者使用 decrypt 命令解密）", recipient, target.display());
+            output::file("stored", FileRecord::new(target.display().to_string()).size(hello.size).digest(agreed.hash, &header.digest));
+            if signature.is_some() {
+                say!("文件以密文保存，未写入签名文件");
+            }
+            return Ok(());
+        }
+    };
+
+    // 分块校验：发送端在数据之前给出每块的摘要
+    let chunk_tree = read_chunk_hashes(socket, &key, agreed, root.as_deref()).await?;
+
+    // 加密存储模式：逐块解密校验，再逐块加密给配置的接收公钥，磁盘上只保存密文
+    if let Some(owner) = store_to {
+        if sync_file {
+            anyhow::bail!("加密存储模式不支持目录同步");
+        }
+        let name = filename.to_string_lossy();
+        let sealed_name = format!("{}.{}", name, sealed::EXTENSION);
+        let target = Path::new(output_dir).join(generate_unique_filename(output_dir, &sealed_name, client_addr));
+        let stream = hello.mode == protocol::Mode::Stream;
+        let mut writer = sealed::Writer::create(&target, sealed::SealOptions {
+            name: &name,
+            digest: (!stream).then_some(digest.as_str()),
+            size: hello.size,
+            protection: sealed::Protection::Recipient(owner),
+            compression: agreed.compression,
+            level: agreed.level,
+            cipher: CipherSuite::preferred()[0],
+            hash: agreed.hash,
+        }).with_context(|| format!("无法写入文件 {}", target.display()))?;
+        let mut progress = progress::Progress::new(&name, hello.size, opts.quiet);
+        if stream {
+            let (total, _) = chunked::recv_chunks(socket, &key, &aad, agreed.cipher, agreed.compression, agreed.hash, &mut writer, &mut progress).await?;
+            check_size(hello.size, total)?;
+            writer.finish(None)?;
+        } else {
+            let size = declared_size(hello)?;
+            let verified = chunked::recv_verified(
+                socket, &key, &aad, agreed.cipher, agreed.compression, agreed.hash, size, chunk_tree.as_ref(), &mut writer, &mut progress,
+            ).await?;
+            check_claimed_digest(agreed.hash, &verified.digest, &digest)?;
+            if let Some(tree) = chunk_tree.as_ref().filter(|_| !verified.damaged.is_empty()) {
+                let repaired = fetch_repairs(socket, &key, agreed.hash, tree, size, &verified.damaged).await?;
+                writer = writer.replace_chunks(&repaired)?;
+            }
+            writer.finish(Some(&digest))?;
+        }
+        say!("文件已校验并加密保存为 {}（使用 decrypt 命令解密）", target.display());
+        output::file("stored", FileRecord::new(target.display().to_string()).size(hello.size).digest(agreed.hash, &digest));
+        if signature.is_some() {
+            say!("文件以密文保存，未写入签名文件");
+        }
+        return Ok(());
+    }
+
+    // 数据流模式：边接收边写入临时文件，尾块校验通过后改名
+    if hello.mode == protocol::Mode::Stream {
+        let unique_name = generate_unique_filename(output_dir, &filename.to_string_lossy(), client_addr);
+        let target = Path::new(output_dir).join(&unique_name);
+        let part = part_path(&target);
+        let mut out = std::io::BufWriter::new(File::create(&part)
+            .with_context(|| format!("无法创建文件 {}", part.display()))?);
+        let mut progress = progress::Progress::new(&unique_name, hello.size, opts.quiet);
+        let result = chunked::recv_chunks(socket, &key, &aad, agreed.cipher, agreed.compression, agreed.hash, &mut out, &mut progress).await;
+        drop(out);
+        let (total, stream_digest) = result
+            .and_then(|(total, digest)| check_size(hello.size, total).map(|_| (total, digest)))
+            .inspect_err(|_| { std::fs::remove_file(&part).ok(); })?;
+        std::fs::rename(&part, &target)
+            .with_context(|| format!("无法写入文件 {}", target.display()))?;
+        say!("数据流已接收（{} 字节）并保存为 {}", total, target.display());
+        output::file("received", FileRecord::new(target.display().to_string()).size(total).digest(agreed.hash, &stream_digest));
+        return Ok(());
+    }
+
+    // 增量模式：把现有同名文件的块签名加密后发给发送端
+    let base_path = Path::new(output_dir).join(&filename);
+    if let Some(parent) = base_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let base = if agreed.delta {
+        let base = std::fs::read(&base_path).unwrap_or_default();
+        let sigs = delta::signatures(&base);
+        protocol::write_sealed_frame(socket, &key, &sigs).await?;
+        Some((base, sigs.block_size))
+    } else {
+        None
+    };
+
+    // 生成唯一文件名以防覆盖；增量或目录同步模式校验通过后覆盖原文件
+    let replace = agreed.delta || sync_file;
+    let target = if replace {
+        base_path
+    } else {
+        Path::new(output_dir).join(generate_unique_filename(output_dir, &filename.to_string_lossy(), client_addr))
+    };
+    // 数据先写入临时文件，校验通过后再改名
+    let part = part_path(&target);
+    let mut progress = progress::Progress::new(&filename.to_string_lossy(), hello.size, opts.quiet);
+    let result = async {
+        let size = if base.is_none() {
+            // 逐块解密，每块到达时对照分块摘要校验，损坏的块先占位，之后只请求重传这些块
+            let size = declared_size(hello)?;
+            let mut out = std::io::BufWriter::new(File::create(&part)
+                .with_context(|| format!("无法创建文件 {}", part.display()))?);
+            let verified = chunked::recv_verified(
+                socket, &key, &aad, agreed.cipher, agreed.compression, agreed.hash, size, chunk_tree.as_ref(), &mut out, &mut progress,
+            ).await?;
+            drop(out);
+            check_claimed_digest(agreed.hash, &verified.digest, &digest)?;
+            if let Some(tree) = chunk_tree.as_ref().filter(|_| !verified.damaged.is_empty()) {
+                let repaired = fetch_repairs(socket, &key, agreed.hash, tree, size, &verified.damaged).await?;
+                write_repaired(&part, &repaired)?;
+                let calculated = hashing::hash_file(agreed.hash, &part)?;
+                if calculated != digest.to_lowercase() {
+                    return Err(metrics::integrity_failure(format!("{} 校验失败: {} != {}", agreed.hash, digest, calculated)));
+                }
+            }
+            size
+        } else {
+            // 增量数据要与现有文件合并，在内存中处理
+            let mut body = Vec::new();
+            chunked::recv_chunks(socket, &key, &aad, agreed.cipher, agreed.compression, agreed.hash, &mut body, &mut progress).await?;
+            let mut plaintext = match &base {
+                Some((base, block_size)) => delta::apply(base, *block_size, &body)
+                    .with_context(|| "增量数据应用失败")?,
+                None => body,
+            };
+            // 合并后逐块对照分块摘要，向发送端请求重传损坏的块
+            if let Some(tree) = &chunk_tree {
+                let size = declared_size(hello)?;
+                // 长度不符时按声明的大小补齐或截断，多出或缺少的部分随损坏的块一起重传
+                plaintext.resize(size.min(tree.leaf_count() * chunked::CHUNK_SIZE as u64) as usize, 0);
+                let damaged = tree.corrupt_chunks(&plaintext);
+                if !damaged.is_empty() {
+                    transfer_api::metrics::integrity_failure();
+                    for (index, chunk) in fetch_repairs(socket, &key, agreed.hash, tree, size, &damaged).await? {
+                        let start = index as usize * chunked::CHUNK_SIZE;
+                        plaintext[start..start + chunk.len()].copy_from_slice(&chunk);
                     }
-                    data_buffer.extend_from_slice(&buf[..n]);
                 }
+            }
+            check_size(hello.size, plaintext.len() as u64)?;
+            verify_digest(agreed.hash, &plaintext, &digest)?;
+            std::fs::write(&part, &plaintext)
+                .with_context(|| format!("无法写入文件 {}", part.display()))?;
+            plaintext.len() as u64
+        };
+        std::fs::rename(&part, &target)
+            .with_context(|| format!("无法写入文件 {}", target.display()))?;
+        Ok(size)
+    }.await;
+    let size = result.inspect_err(|_| { std::fs::remove_file(&part).ok(); })?;
+    if replace {
+        say!("文件已更新为 {}", target.display());
+    } else {
+        say!("文件已接收并保存为 {}", target.display());
+    }
+    // 文件已通过摘要校验，签名随之生效，写入签名文件
+    if let Some(signature) = &signature {
+        let sig_path = signing::write_sidecar(&target, signature)
+            .with_context(|| "无法写入签名文件")?;
+        say!("签名已保存为 {}", sig_path.display());
+    }
+    output::file("received", FileRecord::new(target.display().to_string()).size(size).digest(agreed.hash, &digest));
 
-                // 6. 生成唯一文件名以防覆盖
-                let unique_name = generate_unique_filename(&output_dir, &filename, &client_addr);
-
-                // 7. 将接收到的加密文件数据写入临时文件
-                let enc_path = Path::new(&output_dir).join(format!("{}.enc", unique_name));
-                let mut enc_file = File::create(&enc_path)
-                    .with_context(|| format!("无法创建文件 {}", enc_path.display()))?;
-                enc_file.write_all(&data_buffer)
-                    .with_context(|| "写入加密数据失败")?;
-
-                // 8. 解密文件内容到目标文件
-                let decrypted_path = Path::new(&output_dir).join(&unique_name);
-                cryptography::decrypt_file(
-                    enc_path.to_str().unwrap(),
-                    &password,
-                    decrypted_path.to_str().unwrap(),
-                ).with_context(|| "文件解密失败")?;
-
-                // 9. 计算并校验 SHA256
-                let sha_calculated = cryptography::calculate_sha256(decrypted_path.to_str().unwrap())
-                    .with_context(|| "计算 SHA256 失败")?;
-                if sha_calculated == sha_hex.to_lowercase() {
-                    println!("文件已接收并保存为 {}", decrypted_path.display());
-                } else {
-                    println!("SHA256 校验失败: {} != {}", sha_hex, sha_calculated);
-                }
+    Ok(())
+}
 
-                // 10. 清理临时加密文件
-                std::fs::remove_file(enc_path).ok();
+/// 分块发送的文件须事先声明大小，用于确定块数和每块的长度
+fn declared_size(hello: &protocol::ClientHello) -> anyhow::Result<u64> {
+    hello.size.ok_or_else(|| anyhow::anyhow!("发送端未声明文件大小"))
+}
 
-                Ok(())
-            }.await;
+/// 尾块中的摘要须与文件头中的摘要一致
+fn check_claimed_digest(hash: HashAlgorithm, claimed: &str, expected: &str) -> anyhow::Result<()> {
+    if claimed != expected.to_lowercase() {
+        return Err(metrics::integrity_failure(format!("{} 校验失败: {} != {}", hash, expected, claimed)));
+    }
+    Ok(())
+}
 
-            if let Err(e) = result {
-                eprintln!("处理客户端 {} 时出错: {:?}", client_addr, e);
+/// 接收中的临时文件：`<目标路径>.part`
+fn part_path(target: &std::path::Path) -> std::path::PathBuf {
+    let mut part = target.as_os_str().to_owned();
+    part.push(".part");
+    std::path::PathBuf::from(part)
+}
+
+/// 读取并校验发送端附带的文件签名：签名的摘要须与文件头一致，签名者须为已信任的对端。
+/// 返回签名及签名者的描述，未签名时为 `None`
+async fn read_signature<S>(
+    socket: &mut S,
+    hello: &protocol::ClientHello,
+    agreed: &protocol::ServerHello,
+    digest: &str,
+) -> anyhow::Result<Option<(signing::FileSignature, String)>>
+where
+    S: AsyncRead + Unpin,
+{
+    if !hello.signed {
+        return Ok(None);
+    }
+    if hello.mode == protocol::Mode::Stream {
+        anyhow::bail!("数据流模式不支持文件签名");
+    }
+    let signature: signing::FileSignature = protocol::read_frame(socket).await?;
+    if signature.hash != agreed.hash || signature.digest != digest.to_lowercase() {
+        anyhow::bail!("文件签名与文件摘要不符");
+    }
+    let signer = signature.verify()?;
+    Ok(Some((signature, signer)))
+}
+
+/// 发送端握手：发送传输偏好，校验接收端对握手记录的身份签名并回送本端签名，
+/// 返回协商结果和握手密钥交换得到的共享密钥。
+/// `peer` 为接收端地址时按首次信任规则比对 known_peers，为 `None` 时只显示指纹
+async fn client_handshake<S>(
+    stream: &mut S,
+    hello: &mut protocol::ClientHello,
+    peer: Option<&str>,
+) -> anyhow::Result<(protocol::ServerHello, cryptography::SessionKey)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let key = identity::load_or_create()?;
+    let (secret, ephemeral) = identity::handshake_secret();
+    hello.nonce = identity::new_nonce();
+    hello.ephemeral = ephemeral;
+    tracing::Span::current().record("id", transfer_id(&hello.nonce));
+    let started = std::time::Instant::now();
+    let client_raw = protocol::write_frame_raw(stream, &*hello).await?;
+    let (agreed, server_raw): (protocol::ServerHello, _) = protocol::read_frame_raw(stream).await?;
+    if !hello.ciphers.contains(&agreed.cipher) {
+        let offered: Vec<String> = hello.ciphers.iter().map(ToString::to_string).collect();
+        anyhow::bail!("接收端不接受本端提出的加密算法（{}）；不加密传输需要接收端也以 --insecure 启动", offered.join(", "));
+    }
+    if agreed.cipher == CipherSuite::None {
+        warn!("本次传输未加密，文件内容将以明文经过网络");
+    }
+
+    let transcript = identity::transcript_hash(&client_raw, &server_raw);
+    let proof: protocol::IdentityProof = protocol::read_frame(stream).await?;
+    let receiver = identity::verify_handshake(&proof.public_key, &proof.signature, identity::Role::Receiver, &transcript)?;
+    match peer {
+        Some(peer) => identity::check_peer(peer, &receiver)?,
+        None => say!("接收端身份指纹 {}", identity::fingerprint(&receiver)),
+    }
+
+    let proof = protocol::IdentityProof {
+        public_key: identity::encode_key(&key.verifying_key()),
+        signature: identity::sign_handshake(&key, identity::Role::Sender, &transcript),
+    };
+    protocol::write_frame(stream, &proof).await?;
+    // 握手照常完成，接收端据此记录拒绝的原因
+    if matches!(hello.mode, protocol::Mode::Manifest | protocol::Mode::SyncFile) && !agreed.sync {
+        return Err(sync::SyncRefused.into());
+    }
+    let shared = identity::handshake_key(secret, &agreed.ephemeral, &transcript)?;
+    debug!(
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        cipher = %agreed.cipher, compression = ?agreed.compression, hash = %agreed.hash, delta = agreed.delta,
+        "握手完成",
+    );
+    Ok((agreed, shared))
+}
+
+/// 接收端握手的结果
+struct Accepted {
+    hello: protocol::ClientHello,
+    agreed: protocol::ServerHello,
+    /// 握手密钥交换得到的共享密钥
+    shared: cryptography::SessionKey,
+}
+
+/// 接收端握手：协商传输参数，先发送本端对握手记录的身份签名，再校验发送端的签名。
+/// 发送端的地址可能随网络变化，也可能被多台主机共用，不据此固定身份：只按公钥在 known_peers 中查找并显示其指纹
+async fn server_handshake<S>(
+    socket: &mut S,
+    allow_delta: bool,
+    allow_sync: bool,
+    allow_insecure: bool,
+) -> anyhow::Result<Accepted>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let key = identity::load_or_create()?;
+    let (hello, client_raw): (protocol::ClientHello, _) = protocol::read_frame_raw(socket).await?;
+    let started = std::time::Instant::now();
+    let mut agreed = protocol::ServerHello::negotiate(&hello, allow_insecure);
+    agreed.delta &= allow_delta;
+    agreed.sync = allow_sync;
+    if agreed.cipher == CipherSuite::None {
+        warn!("发送端选择了不加密传输，文件内容以明文经过网络");
+    }
+    agreed.nonce = identity::new_nonce();
+    tracing::Span::current().record("id", transfer_id(&hello.nonce));
+    let (secret, ephemeral) = identity::handshake_secret();
+    agreed.ephemeral = ephemeral;
+    let server_raw = protocol::write_frame_raw(socket, &agreed).await?;
+
+    let transcript = identity::transcript_hash(&client_raw, &server_raw);
+    let proof = protocol::IdentityProof {
+        public_key: identity::encode_key(&key.verifying_key()),
+        signature: identity::sign_handshake(&key, identity::Role::Receiver, &transcript),
+    };
+    protocol::write_frame(socket, &proof).await?;
+    let proof: protocol::IdentityProof = protocol::read_frame(socket).await?;
+    let sender = identity::verify_handshake(&proof.public_key, &proof.signature, identity::Role::Sender, &transcript)?;
+    say!("{}", describe_sender(&sender)?);
+    let shared = identity::handshake_key(secret, &hello.ephemeral, &transcript)?;
+    debug!(
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        cipher = %agreed.cipher, compression = ?agreed.compression, hash = %agreed.hash, delta = agreed.delta,
+        sender = %identity::fingerprint(&sender),
+        "握手完成",
+    );
+    transfer_api::metrics::observe_handshake(started.elapsed());
+    Ok(Accepted { hello, agreed, shared })
+}
+
+/// 日志中标识一次传输的 ID：取发送端握手随机数的前 16 个十六进制字符，收发两端一致
+fn transfer_id(nonce: &str) -> &str {
+    nonce.get(..16).unwrap_or(nonce)
+}
+
+/// 描述发送端身份，并注明它是否在 known_peers 中
+fn describe_sender(key: &ed25519_dalek::VerifyingKey) -> anyhow::Result<String> {
+    let known = identity::KnownPeers::load()?;
+    let fp = identity::fingerprint(key);
+    Ok(match known.find_by_key(&identity::encode_key(key)) {
+        Some(peer) => format!("发送端身份指纹 {}（已信任：{}）", fp, peer),
+        None => format!("发送端身份指纹 {}（未在 known_peers 中）", fp),
+    })
+}
+
+/// 文件头：文件名、文件摘要和会话密钥
+struct Header {
+    name: String,
+    digest: String,
+    /// 分块校验时的 Merkle 根
+    root: Option<String>,
+    key: FileKey,
+    /// 文件头原始字节，用于计算附加认证数据
+    raw: Vec<u8>,
+}
+
+/// 文件头中的会话密钥
+enum FileKey {
+    /// 已解出的会话密钥
+    Session(cryptography::SessionKey),
+    /// 加密给其他接收者的会话密钥，本机无法解密
+    Foreign(sealed::WrappedKey),
+}
+
+/// 数据流模式下摘要在末尾给出，文件头中该字段以全 0 占位
+fn deferred_digest(hash: HashAlgorithm) -> String {
+    "0".repeat(hash.hex_len())
+}
+
+/// 文件头中加密后的会话密钥（十六进制）长度：32 字节密钥 + 16 字节认证标签
+const SESSION_KEY_CT_HEX_LEN: usize = 96;
+
+/// 会话密钥的保护方式，收发两端须一致
+#[derive(Clone, Copy)]
+enum KeyProtection<'a> {
+    /// 双方事先共享的口令，按 `ClientHello::kdf` 派生保护密钥
+    Passphrase(&'a str),
+    /// 传输码经 PAKE 配对得到的共享密钥，不经网络传输
+    Paired(&'a cryptography::SessionKey),
+    /// 握手中经签名的临时 X25519 密钥交换得到的共享密钥，不经网络传输
+    Exchanged(&'a cryptography::SessionKey),
+}
+
+impl<'a> KeyProtection<'a> {
+    /// 指定了口令时优先使用口令，其次是传输码配对得到的密钥，再次是握手密钥交换得到的密钥
+    fn new(
+        passphrase: Option<&'a str>,
+        code_key: Option<&'a cryptography::SessionKey>,
+        shared: &'a cryptography::SessionKey,
+    ) -> Self {
+        match (passphrase, code_key) {
+            (Some(p), _) => KeyProtection::Passphrase(p),
+            (None, Some(key)) => KeyProtection::Paired(key),
+            (None, None) => KeyProtection::Exchanged(shared),
+        }
+    }
+}
+
+/// 读取文件头：`[u32 文件名长度][文件名][摘要][32 salt][24 nonce][96 密文]`，
+/// 协商了分块校验时摘要之后紧跟同样长度的 Merkle 根；
+/// 加密给接收者公钥时为 `[u32 文件名长度][文件名][摘要][64 临时公钥][24 nonce][96 密文]`
+async fn read_header<S>(
+    socket: &mut S,
+    protection: KeyProtection<'_>,
+    hello: &protocol::ClientHello,
+    agreed: &protocol::ServerHello,
+) -> anyhow::Result<Header>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut raw = Vec::new();
+    // 1. 接收文件名长度及文件名
+    let len_buf = read_field(socket, &mut raw, 4).await?;
+    let filename_len = u32::from_be_bytes(len_buf.try_into().unwrap()) as usize;
+    let filename_buf = read_field(socket, &mut raw, filename_len).await?;
+    let name = String::from_utf8(filename_buf).unwrap_or_default();
+
+    // 2. 接收文件摘要（十六进制，长度取决于协商的算法）
+    let digest = String::from_utf8(read_field(socket, &mut raw, agreed.hash.hex_len()).await?).unwrap_or_default();
+    let root = if agreed.chunk_hashes {
+        Some(String::from_utf8(read_field(socket, &mut raw, agreed.hash.hex_len()).await?).unwrap_or_default())
+    } else {
+        None
+    };
+
+    let ct_len = SESSION_KEY_CT_HEX_LEN;
+    if let Some(recipient) = &hello.recipient {
+        let ephemeral = read_field(socket, &mut raw, 64).await?;
+        let nonce = read_field(socket, &mut raw, 24).await?;
+        let ct = read_field(socket, &mut raw, ct_len).await?;
+        let wrapped = sealed::WrappedKey {
+            recipient: recipient.to_string(),
+            ephemeral: String::from_utf8(ephemeral).unwrap_or_default(),
+            nonce: String::from_utf8(nonce).unwrap_or_default(),
+            ct: String::from_utf8(ct).unwrap_or_default(),
+        };
+        let key = if wrapped.is_for_me()? {
+            FileKey::Session(wrapped.unwrap_key()?)
+        } else {
+            FileKey::Foreign(wrapped)
+        };
+        return Ok(Header { name, digest, root, key, raw });
+    }
+
+    // 3. 接收加密参数：salt、nonce、ciphertext（均为十六进制）
+    let salt_hex = String::from_utf8(read_field(socket, &mut raw, 32).await?).unwrap_or_default();
+    let nonce_hex = String::from_utf8(read_field(socket, &mut raw, 24).await?).unwrap_or_default();
+    let ct_hex = String::from_utf8(read_field(socket, &mut raw, ct_len).await?).unwrap_or_default();
+
+    // 4. 解密得到会话密钥：共享口令按发送端声明的算法派生密钥
+    let pt = match protection {
+        KeyProtection::Passphrase(p) => {
+            // 对端给出的参数按接收端的上限检查，避免每个连接都按超大参数派生
+            let kdf = hello.kdf.ok_or_else(|| anyhow::anyhow!("发送端未使用口令，请去掉接收端的 --password"))?;
+            kdf.validate_peer().map_err(|e| anyhow::anyhow!("发送端的口令派生参数无效: {}", e))?;
+            cryptography::decrypt_data_kdf(&kdf, p, &salt_hex, &nonce_hex, &ct_hex)
+                .map_err(|e| match e.kind() {
+                    std::io::ErrorKind::InvalidInput => anyhow::anyhow!("发送端的口令派生参数无效: {}", e),
+                    _ => anyhow::anyhow!("无法解密会话密钥：口令错误或数据损坏"),
+                })?
+        }
+        _ if hello.kdf.is_some() => anyhow::bail!("发送端使用口令保护文件，请以 --password 启动接收端"),
+        KeyProtection::Paired(key) | KeyProtection::Exchanged(key) => cryptography::decrypt_data(key, &salt_hex, &nonce_hex, &ct_hex)
+            .map_err(|_| anyhow::anyhow!("无法解密会话密钥：数据损坏"))?,
+    };
+    let pt = zeroize::Zeroizing::new(pt);
+    let key = cryptography::SessionKey::from_bytes(&pt)?;
+    Ok(Header { name, digest, root, key: FileKey::Session(key), raw })
+}
+
+/// 读取文件头中的一个字段，并追加到文件头原始字节中
+async fn read_field<S>(socket: &mut S, raw: &mut Vec<u8>, len: usize) -> std::io::Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = vec![0u8; len];
+    socket.read_exact(&mut buf).await?;
+    raw.extend_from_slice(&buf);
+    Ok(buf)
+}
+
+/// 发送文件头，返回本次传输随机生成的会话密钥；指定 `recipient` 时会话密钥用接收者公钥加密，
+/// 否则按 `protection` 加密，口令模式下经 `kdf` 派生密钥。
+/// 分块校验时 `root` 为 Merkle 根，随文件头一起受附加认证数据保护
+#[allow(clippy::too_many_arguments)]
+async fn write_header<S>(
+    stream: &mut S,
+    filename: &str,
+    digest: &str,
+    root: Option<&str>,
+    protection: KeyProtection<'_>,
+    kdf: &Kdf,
+    recipient: Option<&x25519_dalek::PublicKey>,
+) -> anyhow::Result<(cryptography::SessionKey, Vec<u8>)>
+where
+    S: AsyncWrite + Unpin,
+{
+    let mut raw = Vec::new();
+    let filename_bytes = filename.as_bytes();
+    let filename_len = filename_bytes.len() as u32;
+    // 文件名长度和文件名
+    raw.extend_from_slice(&filename_len.to_be_bytes());
+    raw.extend_from_slice(filename_bytes);
+    // 文件摘要和 Merkle 根
+    raw.extend_from_slice(digest.as_bytes());
+    if let Some(root) = root {
+        raw.extend_from_slice(root.as_bytes());
+    }
+
+    // 生成会话密钥并加密
+    let key = cryptography::SessionKey::generate();
+    if let Some(recipient) = recipient {
+        let wrapped = sealed::WrappedKey::wrap(recipient, &key);
+        raw.extend_from_slice(wrapped.ephemeral.as_bytes());
+        raw.extend_from_slice(wrapped.nonce.as_bytes());
+        raw.extend_from_slice(wrapped.ct.as_bytes());
+    } else {
+        // 保护密钥都不经网络传输
+        let (salt_hex, nonce_hex, ct_hex) = match protection {
+            KeyProtection::Passphrase(p) => cryptography::encrypt_data_kdf(kdf, p, key.as_bytes())?,
+            KeyProtection::Paired(shared) | KeyProtection::Exchanged(shared) => cryptography::encrypt_data(shared, key.as_bytes()),
+        };
+        // 加密参数
+        raw.extend_from_slice(salt_hex.as_bytes());
+        raw.extend_from_slice(nonce_hex.as_bytes());
+        raw.extend_from_slice(ct_hex.as_bytes());
+    }
+    // 文件头整体发送，原始字节同时用于计算附加认证数据
+    stream.write_all(&raw).await?;
+    Ok((key, raw))
+}
+
+/// 校验收到的数据长度与发送端声明的大小一致；未声明大小时不校验
+fn check_size(declared: Option<u64>, actual: u64) -> anyhow::Result<()> {
+    match declared {
+        Some(size) if size != actual => anyhow::bail!("文件大小不符：声明 {} 字节，实际收到 {} 字节", size, actual),
+        _ => Ok(()),
+    }
+}
+
+/// 等待接收端确认数据已完整保存
+async fn read_receipt<S>(stream: &mut S) -> anyhow::Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    let receipt: protocol::Receipt = protocol::read_frame(stream).await
+        .map_err(|e| anyhow::anyhow!("未收到接收端确认: {}", e))?;
+    if let Some(err) = receipt.error {
+        anyhow::bail!("接收端处理失败: {}", err);
+    }
+    Ok(())
+}
+
+/// 校验整个文件的摘要
+fn verify_digest(hash: HashAlgorithm, data: &[u8], expected: &str) -> anyhow::Result<()> {
+    let calculated = hashing::hash_hex(hash, data);
+    if calculated != expected.to_lowercase() {
+        return Err(metrics::integrity_failure(format!("{} 校验失败: {} != {}", hash, expected, calculated)));
+    }
+    Ok(())
+}
+
+/// 读取发送端在文件头之后给出的分块摘要，并用文件头中的 Merkle 根校验；未协商分块校验时为 `None`
+async fn read_chunk_hashes<S>(
+    socket: &mut S,
+    key: &cryptography::SessionKey,
+    agreed: &protocol::ServerHello,
+    root: Option<&str>,
+) -> anyhow::Result<Option<hashing::MerkleTree>>
+where
+    S: AsyncRead + Unpin,
+{
+    let Some(root) = root.filter(|_| agreed.chunk_hashes) else {
+        return Ok(None);
+    };
+    let frame: protocol::ChunkHashes = protocol::read_sealed_frame(socket, key).await?;
+    let tree = hashing::MerkleTree::from_leaves(agreed.hash, &frame.hashes)
+        .ok_or_else(|| anyhow::anyhow!("发送端的分块摘要格式错误"))?;
+    if tree.root() != root.to_lowercase() {
+        return Err(metrics::integrity_failure("分块摘要与文件头中的 Merkle 根不符"));
+    }
+    Ok(Some(tree))
+}
+
+/// 重传损坏数据块的最大轮数
+const MAX_REPAIR_ROUNDS: usize = 2;
+/// 一次传输最多重传的块数：重传的块在内存中校验后写回，损坏更多时直接失败
+const MAX_REPAIR_CHUNKS: usize = 16;
+
+/// 接收端：只为损坏的块向发送端请求重传，重传的块逐个凭路径对照 Merkle 根校验。
+/// 返回块序号到明文的映射，由调用方写回
+async fn fetch_repairs<S>(
+    socket: &mut S,
+    key: &cryptography::SessionKey,
+    hash: HashAlgorithm,
+    tree: &hashing::MerkleTree,
+    size: u64,
+    damaged: &[u64],
+) -> anyhow::Result<std::collections::HashMap<u64, Vec<u8>>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if damaged.len() > MAX_REPAIR_CHUNKS {
+        return Err(metrics::integrity_failure(format!("{} 个数据块校验失败，超过可重传的上限 {}", damaged.len(), MAX_REPAIR_CHUNKS)));
+    }
+    let root = tree.root();
+    let mut repaired = std::collections::HashMap::new();
+    for _ in 0..MAX_REPAIR_ROUNDS {
+        let missing: Vec<u64> = damaged.iter().copied().filter(|i| !repaired.contains_key(i)).collect();
+        if missing.is_empty() {
+            break;
+        }
+        say!("{} 个数据块校验失败，请求发送端重传", missing.len());
+        protocol::write_frame(socket, &protocol::Receipt::repair(missing.clone())).await?;
+        let chunks: Vec<protocol::RepairedChunk> = protocol::read_sealed_frame(socket, key).await?;
+        for chunk in chunks {
+            let bytes = hex::decode(&chunk.data).unwrap_or_default();
+            let valid = missing.contains(&chunk.index)
+                && bytes.len() == chunked::chunk_len(size, chunk.index)
+                && hashing::verify_proof(hash, &root, tree.leaf_count(), chunk.index, &bytes, &chunk.proof);
+            if !valid {
+                say!("重传的第 {} 块校验失败，已丢弃", chunk.index);
+                continue;
             }
-        });
+            repaired.insert(chunk.index, bytes);
+        }
     }
+    let left = damaged.len() - repaired.len();
+    if left > 0 {
+        return Err(metrics::integrity_failure(format!("重传后仍有 {} 个数据块校验失败", left)));
+    }
+    Ok(repaired)
+}
 
-    // （循环永不结束，不返回 Ok）
+/// 把重传的块写回临时文件中的对应位置
+fn write_repaired(part: &std::path::Path, repaired: &std::collections::HashMap<u64, Vec<u8>>) -> std::io::Result<()> {
+    use std::io::{Seek, Write};
+
+    let mut file = std::fs::OpenOptions::new().write(true).open(part)?;
+    for (index, chunk) in repaired {
+        file.seek(std::io::SeekFrom::Start(index * chunked::CHUNK_SIZE as u64))?;
+        file.write_all(chunk)?;
+    }
+    file.sync_data()
+}
+
+/// 发送端：等待接收端确认，期间按请求从源文件读取并重传损坏的块，每块附上到 Merkle 根的路径
+async fn read_receipt_with_repairs<S>(
+    stream: &mut S,
+    key: &cryptography::SessionKey,
+    source: &std::path::Path,
+    tree: &hashing::MerkleTree,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    use tokio::io::AsyncSeekExt;
+
+    loop {
+        let receipt: protocol::Receipt = protocol::read_frame(stream).await
+            .map_err(|e| anyhow::anyhow!("未收到接收端确认: {}", e))?;
+        if receipt.repair.is_empty() {
+            if let Some(err) = receipt.error {
+                anyhow::bail!("接收端处理失败: {}", err);
+            }
+            return Ok(());
+        }
+        if receipt.repair.len() > MAX_REPAIR_CHUNKS || receipt.repair.iter().any(|&i| i >= tree.leaf_count()) {
+            anyhow::bail!("接收端请求重传的数据块无效");
+        }
+        say!("接收端请求重传 {} 个数据块", receipt.repair.len());
+        let mut file = File::open(source).await?;
+        let mut chunks = Vec::with_capacity(receipt.repair.len());
+        for index in receipt.repair {
+            let mut data = Vec::new();
+            file.seek(std::io::SeekFrom::Start(index.saturating_mul(hashing::VERIFY_CHUNK_SIZE as u64))).await?;
+            (&mut file).take(hashing::VERIFY_CHUNK_SIZE as u64).read_to_end(&mut data).await?;
+            chunks.push(protocol::RepairedChunk { index, data: hex::encode(&data), proof: tree.proof(index) });
+        }
+        protocol::write_sealed_frame(stream, key, &chunks).await?;
+    }
 }
 
 /// 异步：TCP 模式下发送文件
-pub(crate) async fn tcp_send(server: &str, port: u16, file_path: &str) -> anyhow::Result<()> {
+pub(crate) async fn tcp_send(server: &str, port: u16, file_path: &str, opts: &SendOptions) -> anyhow::Result<()> {
     let address = (server, port);
-    let mut stream = TcpStream::connect(address).await?;
-    println!("已通过 TCP 连接到 {}:{}", server, port);
+    let stream = TcpStream::connect(address).await?;
+    say!("已通过 TCP 连接到 {}:{}", server, port);
+    let peer = format!("{}:{}", server, port);
+    send_stream(stream, file_path, None, Some(&peer), opts).await
+}
+
+/// 异步：通过中继发送文件，`server`/`port` 为中继地址
+pub(crate) async fn relay_send(
+    server: &str,
+    port: u16,
+    file_path: &str,
+    code: &str,
+    opts: &SendOptions,
+) -> anyhow::Result<()> {
+    let mut stream = relay::connect(server, port, code, relay::Role::Sender).await?;
+    let code_key = pake::exchange(&mut stream, code, &code::channel_id(code), pake::Role::Sender).await?;
+    send_stream(stream, file_path, Some(&code_key), None, opts).await
+}
+
+/// 异步：传输码模式下发送文件。
+/// 未指定传输码时自动生成；指定中继时经中继配对，否则在局域网内广播等待接收端连接。
+pub(crate) async fn code_send(
+    file_path: &str,
+    code: Option<&str>,
+    relay: Option<(&str, u16)>,
+    opts: &SendOptions,
+) -> anyhow::Result<()> {
+    let code = code.map_or_else(code::generate, str::to_string);
+    say!("传输码：{}", code);
+    output::event("code", json!({ "code": code }));
+    say!("请在接收端运行：recv {}", code);
+
+    if let Some((server, port)) = relay {
+        return relay_send(server, port, file_path, &code, opts).await;
+    }
+
+    let listener = TcpListener::bind(("0.0.0.0", 0)).await?;
+    let tcp_port = listener.local_addr()?.port();
+    let channel = code::channel_id(&code);
+    let beacon = {
+        let channel = channel.clone();
+        tokio::spawn(async move { discovery::announce(&channel, tcp_port).await })
+    };
+    let (mut stream, addr) = listener.accept().await?;
+    beacon.abort();
+    say!("接收端 {} 已连接", addr);
+    let code_key = pake::exchange(&mut stream, &code, &channel, pake::Role::Sender).await?;
+    send_stream(stream, file_path, Some(&code_key), None, opts).await
+}
 
-    // 准备文件名和 SHA256
+/// 在已建立的连接上发送一个文件，`code_key` 的含义与 [`recv_stream`] 相同；
+/// `peer` 为接收端地址，用于首次信任校验，经中继或传输码连接时为 `None`
+#[tracing::instrument(name = "transfer", skip_all, fields(id = tracing::field::Empty, file = file_path, peer))]
+async fn send_stream<S>(
+    mut stream: S,
+    file_path: &str,
+    code_key: Option<&cryptography::SessionKey>,
+    peer: Option<&str>,
+    opts: &SendOptions,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if file_path == STDIN_PATH {
+        return send_stdin(stream, code_key, peer, opts).await;
+    }
     let path = std::path::Path::new(file_path);
-    let filename = path.file_name().unwrap().to_string_lossy().to_string();
-    let filename_bytes = filename.as_bytes();
-    let filename_len = filename_bytes.len() as u32;
-    // 发送文件名长度和文件名
-    stream.write_all(&filename_len.to_be_bytes()).await?;
-    stream.write_all(filename_bytes).await?;
-    // 发送 SHA256 校验值
-    let sha256_str = cryptography::calculate_sha256(file_path)?;
-    let mut sha_buf = vec![0u8; 64];
-    sha_buf.copy_from_slice(sha256_str.as_bytes());
-    stream.write_all(&sha_buf).await?;
-
-    // 加密文件和加密密码
-    let password = cryptography::password_creat();
-    let password_b = cryptography::password_creat();
-    let enc_path = "encrypt.enc";
-    cryptography::encrypt_file(file_path, &*password, enc_path)?;
-    let (salt_hex, nonce_hex, ct_hex) = cryptography::encrypt_data(&*password_b, password.as_ref());
-    // 发送加密参数
-    let mut salt_buf = [0u8; 32];
-    let mut nonce_buf = [0u8; 24];
-    let mut ct_buf = [0u8; 64];
-    let mut pass_b_buf = [0u8; 16];
-    salt_buf.copy_from_slice(salt_hex.as_bytes());
-    nonce_buf.copy_from_slice(nonce_hex.as_bytes());
-    ct_buf.copy_from_slice(ct_hex.as_bytes());
-    pass_b_buf.copy_from_slice(password_b.as_bytes());
-    stream.write_all(&salt_buf).await?;
-    stream.write_all(&nonce_buf).await?;
-    stream.write_all(&ct_buf).await?;
-    stream.write_all(&pass_b_buf).await?;
-
-    // 读取加密文件并发送
-    let mut file = File::open(enc_path).await?;
-    let mut buffer = [0u8; 4096];
-    loop {
-        let n = file.read(&mut buffer).await?;
-        if n == 0 { break; }
-        stream.write_all(&buffer[..n]).await?;
+    let recipient = opts.recipient.as_deref().map(identity::decode_recipient).transpose()?;
+
+    // 握手：提出压缩偏好，已是压缩格式的文件直接跳过压缩
+    let offered = if opts.compression == Compression::None || compression::is_already_compressed(path) {
+        vec![Compression::None]
+    } else {
+        vec![opts.compression, Compression::None]
+    };
+    let size = fs::metadata(file_path).await?.len();
+    let mut hello = protocol::ClientHello {
+        compression: offered,
+        level: opts.level.unwrap_or(opts.compression.default_level()),
+        delta: opts.delta,
+        mode: if opts.sync_path.is_some() { protocol::Mode::SyncFile } else { protocol::Mode::File },
+        nonce: String::new(),
+        recipient: opts.recipient.clone(),
+        ciphers: opts.ciphers()?,
+        kdf: opts.peer_kdf()?,
+        size: Some(size),
+        hashes: opts.hashes(),
+        signed: opts.sign,
+        ephemeral: String::new(),
+    };
+    let (agreed, shared) = client_handshake(&mut stream, &mut hello, peer).await?;
+    let protection = KeyProtection::new(opts.password.as_deref(), code_key, &shared);
+    if opts.delta && !agreed.delta {
+        say!("接收端未启用增量传输，发送完整文件");
     }
-    println!("文件 '{}' 发送成功。", file_path);
-    fs::remove_file(enc_path).await?;
+
+    // 发送文件名、文件摘要和受保护的会话密钥。先按固定大小分块读一遍文件，得到摘要和 Merkle 树，
+    // 数据随后逐块读取发送，不把整个文件读入内存
+    let filename = match &opts.sync_path {
+        Some(rel) => rel.clone(),
+        None => path.file_name().unwrap().to_string_lossy().to_string(),
+    };
+    let (digest, tree) = hashing::hash_chunks(agreed.hash, std::fs::File::open(path)?)?;
+    // 分块校验：Merkle 根写入文件头，接收端据此逐块校验、找出损坏的块并请求重传
+    let tree = agreed.chunk_hashes.then_some(tree);
+    let root = tree.as_ref().map(|tree| tree.root());
+    let (key, header) = write_header(&mut stream, &filename, &digest, root.as_deref(), protection, &opts.kdf, recipient.as_ref()).await?;
+    // 文件头和传输参数作为附加认证数据，篡改任一项都会导致解密失败
+    let aad = protocol::header_aad(&header, &hello, &agreed);
+    // 文件签名：用本机身份密钥对文件摘要签名
+    if opts.sign {
+        let signature = signing::FileSignature::sign(&identity::load_or_create()?, agreed.hash, &digest);
+        protocol::write_frame(&mut stream, &signature).await?;
+    }
+    if let Some(tree) = &tree {
+        protocol::write_sealed_frame(&mut stream, &key, &protocol::ChunkHashes { hashes: tree.leaves() }).await?;
+    }
+
+    let mut progress = progress::Progress::new(&filename, Some(size), opts.quiet)
+        .with_batch(opts.batch.clone(), size);
+    if agreed.delta {
+        // 增量模式：根据接收端现有文件的块签名，只发送缺少的数据；比对需要整个文件，只有这种情况读入内存
+        let plaintext = fs::read(file_path).await?;
+        if hashing::hash_hex(agreed.hash, &plaintext) != digest {
+            anyhow::bail!("文件在发送过程中被修改，请重试");
+        }
+        let sigs: delta::Signatures = protocol::read_sealed_frame(&mut stream, &key).await?;
+        let (encoded, stats) = delta::diff(&sigs, &plaintext);
+        say!("增量传输：复用 {} 个块，发送 {} 字节新数据", stats.copied_blocks, stats.literal_bytes);
+        progress.set_total(encoded.len() as u64);
+        chunked::send_chunks(
+            &mut &encoded[..], &mut stream, &key, &aad, agreed.cipher, agreed.compression, agreed.level, agreed.hash, &mut progress,
+        ).await?;
+    } else {
+        // 每块单独压缩加密，与 Merkle 树的叶子一一对应
+        let mut file = File::open(file_path).await?;
+        let (_, streamed) = chunked::send_chunks(
+            &mut file, &mut stream, &key, &aad, agreed.cipher, agreed.compression, agreed.level, agreed.hash, &mut progress,
+        ).await?;
+        if streamed != digest {
+            anyhow::bail!("文件在发送过程中被修改，请重试");
+        }
+    }
+    // 等待接收端确认文件已完整保存，期间按请求重传损坏的块
+    match &tree {
+        Some(tree) => read_receipt_with_repairs(&mut stream, &key, path, tree).await?,
+        None => read_receipt(&mut stream).await?,
+    }
+    stream.shutdown().await.ok();
+    info!(size, "文件发送完成");
+    say!("文件 '{}' 发送成功。", file_path);
+    output::file("sent", FileRecord::new(file_path).size(size).digest(agreed.hash, &digest));
     Ok(())
 }
 
-/// 异步：WebSocket 模式下接收文件
-pub(crate) async fn ws_recv(output_dir: &str, port: u16) -> anyhow::Result<()> {
-    use tokio::net::TcpListener;
-    use futures_util::StreamExt;
-    use tokio_tungstenite::accept_async;
-    use std::path::Path;
-    use std::fs::File;
-    use std::io::Write;
-    use cryptography;
-    use anyhow::Context;
+/// 从标准输入读取数据流，分块加密发送
+async fn send_stdin<S>(
+    mut stream: S,
+    code_key: Option<&cryptography::SessionKey>,
+    peer: Option<&str>,
+    opts: &SendOptions,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if opts.recipient.is_some() {
+        anyhow::bail!("从标准输入发送时不支持 --to");
+    }
+    if opts.sign {
+        anyhow::bail!("从标准输入发送时不支持 --sign");
+    }
+    let mut hello = protocol::ClientHello {
+        compression: vec![opts.compression, Compression::None],
+        level: opts.level.unwrap_or(opts.compression.default_level()),
+        delta: false,
+        mode: protocol::Mode::Stream,
+        nonce: String::new(),
+        recipient: None,
+        ciphers: opts.ciphers()?,
+        kdf: opts.peer_kdf()?,
+        size: None,
+        hashes: opts.hashes(),
+        signed: false,
+        ephemeral: String::new(),
+    };
+    let (agreed, shared) = client_handshake(&mut stream, &mut hello, peer).await?;
+    let protection = KeyProtection::new(opts.password.as_deref(), code_key, &shared);
+
+    let filename = opts.sync_path.as_deref().unwrap_or(STDIN_NAME);
+    let (key, header) = write_header(&mut stream, filename, &deferred_digest(agreed.hash), None, protection, &opts.kdf, None).await?;
+    let aad = protocol::header_aad(&header, &hello, &agreed);
+    let mut stdin = tokio::io::stdin();
+    let mut progress = progress::Progress::new(filename, None, opts.quiet);
+    let (total, digest) = chunked::send_chunks(
+        &mut stdin, &mut stream, &key, &aad, agreed.cipher, agreed.compression, agreed.level, agreed.hash, &mut progress,
+    ).await?;
+    stream.shutdown().await?;
+
+    read_receipt(&mut stream).await?;
+    info!(size = total, "数据流发送完成");
+    say!("标准输入的 {} 字节已发送成功（{} {}）。", total, agreed.hash, digest);
+    output::file("sent", FileRecord::new(STDIN_PATH).size(total).digest(agreed.hash, &digest));
+    Ok(())
+}
 
-    // 绑定监听 WebSocket 端口
+/// 异步：TCP 模式下接收一个连接，把收到的数据写到标准输出，提示信息改为输出到标准错误
+pub(crate) async fn tcp_recv_stdout(port: u16, opts: &RecvOptions) -> anyhow::Result<()> {
+    if output::is_json() {
+        anyhow::bail!("--stdout 时标准输出用于写出数据，不能与 --output json 同时使用");
+    }
+    output::reserve_stdout();
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    eprintln!("TCP 模式：监听端口 {}，收到的数据将写到标准输出...", port);
+    let (mut socket, addr) = listener.accept().await?;
+    eprintln!("已与 {} 建立 TCP 连接", addr);
+
+    // 没有可供对比的旧文件，不使用增量传输
+    let Accepted { hello, agreed, shared } =
+        server_handshake(&mut socket, false, false, opts.allow_insecure).await?;
+
+    let result = async {
+        if hello.mode == protocol::Mode::Manifest {
+            anyhow::bail!("标准输出模式不支持目录同步");
+        }
+        let header = read_header(&mut socket, KeyProtection::new(opts.password.as_deref(), None, &shared), &hello, &agreed).await?;
+        let aad = protocol::header_aad(&header.raw, &hello, &agreed);
+        if let Some((_, signer)) = read_signature(&mut socket, &hello, &agreed, &header.digest).await? {
+            eprintln!("文件签名有效，签名者 {}", signer);
+        }
+        let FileKey::Session(key) = header.key else {
+            anyhow::bail!("数据加密给了其他接收者，无法输出到标准输出");
+        };
+        let mut stdout = std::io::stdout();
+        if hello.mode == protocol::Mode::Stream {
+            let mut progress = progress::Progress::new(&header.name, hello.size, opts.quiet);
+            let (total, _) = chunked::recv_chunks(&mut socket, &key, &aad, agreed.cipher, agreed.compression, agreed.hash, &mut stdout, &mut progress).await?;
+            check_size(hello.size, total)?;
+            eprintln!("已接收 {} 字节", total);
+            return Ok(());
+        }
+        // 普通文件：逐块认证后输出，无法回头修补，任何一块损坏都中止。
+        // 分块摘要照常读出校验，但已输出的数据无法修补，不据此请求重传
+        read_chunk_hashes(&mut socket, &key, &agreed, header.root.as_deref()).await?;
+        let mut progress = progress::Progress::new(&header.name, hello.size, opts.quiet);
+        let size = declared_size(&hello)?;
+        let verified = chunked::recv_verified(
+            &mut socket, &key, &aad, agreed.cipher, agreed.compression, agreed.hash, size, None, &mut stdout, &mut progress,
+        ).await?;
+        check_claimed_digest(agreed.hash, &verified.digest, &header.digest)?;
+        eprintln!("已接收文件 {}（{} 字节）", header.name, size);
+        Ok(())
+    }.await;
+    protocol::write_frame(&mut socket, &protocol::Receipt::from_result(&result)).await.ok();
+    result
+}
+
+/// 异步：WebSocket 模式下接收文件。握手、认证和加密与 TCP 模式完全相同，WebSocket 只作为承载连接
+pub(crate) async fn ws_recv(output_dir: &str, port: u16, opts: &RecvOptions) -> anyhow::Result<()> {
     let listener = TcpListener::bind(("0.0.0.0", port)).await?;
-    println!("WebSocket 模式：监听端口 {}...", port);
+    say!("WebSocket 模式：监听端口 {}...", port);
+    output::event("listening", json!({ "mode": "ws", "port": port }));
+    transfer_api::health::set_listening(true);
 
     loop {
-        // 接受新的 TCP 连接
-        let (stream, addr) = listener.accept().await?;
-        println!("{} 已请求 WebSocket 连接", addr);
+        // 按 Ctrl+C 时停止接受，等进行中的传输完成后退出
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        say!("{} 已请求 WebSocket 连接", addr);
+        output::event("connected", json!({ "peer": addr.to_string() }));
 
         let output_dir = output_dir.to_string();
+        let opts = opts.clone();
+        let span = tracing::info_span!("connection", peer = %addr);
         tokio::spawn(async move {
-            let client_addr = addr;
-            let result: anyhow::Result<()> = async {
-                // 完成 WebSocket 握手
-                let ws_stream = accept_async(stream).await
-                    .context("WebSocket 握手失败")?;
-                let (_write, mut read) = ws_stream.split();
-
-                // 读取所有二进制消息并累积到缓冲区
-                let mut data_buffer: Vec<u8> = Vec::new();
-                while let Some(msg) = read.next().await {
-                    let msg = msg?;
-                    if msg.is_binary() {
-                        data_buffer.extend_from_slice(&msg.into_data());
-                    }
-                }
-
-                // 解析数据（与 TCP 方式相同：文件名、SHA、加密参数等）
-                let mut offset = 0;
-                // 文件名长度
-                let filename_len = u32::from_be_bytes(
-                    data_buffer[offset..offset+4].try_into().unwrap()
-                ) as usize;
-                offset += 4;
-                // 文件名
-                let filename = String::from_utf8(
-                    data_buffer[offset..offset+filename_len].to_vec()
-                ).unwrap_or_else(|_| "received.bin".to_string());
-                offset += filename_len;
-                // SHA256 校验值
-                let sha_hex = String::from_utf8(
-                    data_buffer[offset..offset+64].to_vec()
-                ).unwrap_or_default();
-                offset += 64;
-                // salt (hex)
-                let salt_hex = String::from_utf8(
-                    data_buffer[offset..offset+32].to_vec()
-                ).unwrap_or_default();
-                offset += 32;
-                // nonce (hex)
-                let nonce_hex = String::from_utf8(
-                    data_buffer[offset..offset+24].to_vec()
-                ).unwrap_or_default();
-                offset += 24;
-                // ciphertext (hex)
-                let ct_hex = String::from_utf8(
-                    data_buffer[offset..offset+64].to_vec()
-                ).unwrap_or_default();
-                offset += 64;
-                // 密码文本 (16 字节)
-                let password_b = String::from_utf8(
-                    data_buffer[offset..offset+16].to_vec()
-                ).unwrap_or_default();
-                offset += 16;
-
-                // 解密得到实际文件加密密码
-                let pt = cryptography::decrypt_data(&password_b, &salt_hex, &nonce_hex, &ct_hex).unwrap();
-                let password = String::from_utf8(pt).unwrap_or_default();
-
-                // 文件加密数据为余下部分
-                let file_data = &data_buffer[offset..];
-
-                // 生成唯一文件名
-                let unique_name = generate_unique_filename(&output_dir, &filename, &client_addr);
-
-                // 写入临时加密文件
-                let enc_path = Path::new(&output_dir).join(format!("{}.enc", unique_name));
-                let mut enc_file = File::create(&enc_path)
-                    .with_context(|| format!("无法创建文件 {}", enc_path.display()))?;
-                enc_file.write_all(file_data)
-                    .with_context(|| "写入加密数据失败")?;
-
-                // 解密到目标文件
-                let decrypted_path = Path::new(&output_dir).join(&unique_name);
-                cryptography::decrypt_file(
-                    enc_path.to_str().unwrap(),
-                    &password,
-                    decrypted_path.to_str().unwrap(),
-                ).with_context(|| "文件解密失败")?;
-
-                // 校验 SHA256
-                let sha_calculated = cryptography::calculate_sha256(decrypted_path.to_str().unwrap())
-                    .with_context(|| "计算 SHA256 失败")?;
-                if sha_calculated == sha_hex.to_lowercase() {
-                    println!("WebSocket 文件已保存为 {}", decrypted_path.display());
-                } else {
-                    println!("SHA256 校验失败: {} != {}", sha_hex, sha_calculated);
+            let _active = transfer_api::metrics::transfer_started();
+            let result = match tokio_tungstenite::accept_async(stream).await {
+                Ok(ws) => {
+                    info!("已建立 WebSocket 连接");
+                    let socket = metrics::Counted::new(websocket::WsStream::new(ws));
+                    recv_stream(socket, &output_dir, &addr, None, &opts).await
                 }
-
-                // 清理临时加密文件
-                std::fs::remove_file(enc_path).ok();
-
-                Ok(())
-            }.await;
-
+                Err(e) => Err(anyhow::Error::new(e).context("WebSocket 握手失败")),
+            };
+            metrics::record_result(&result);
             if let Err(e) = result {
-                eprintln!("处理 WebSocket 客户端 {} 时出错: {:?}", client_addr, e);
+                error!("处理 WebSocket 客户端时出错: {:#}", e);
             }
-        });
+        }.instrument(span));
     }
 
-    // （循环永不结束，不返回 Ok）
+    drop(listener);
+    drain().await
 }
 
-/// 异步：WebSocket 模式下发送文件
-pub(crate) async fn ws_send(server: &str, port: u16, file_path: &str) -> anyhow::Result<()> {
+/// 异步：WebSocket 模式下发送文件，协议与 TCP 模式相同
+pub(crate) async fn ws_send(server: &str, port: u16, file_path: &str, opts: &SendOptions) -> anyhow::Result<()> {
     let url = format!("ws://{}:{}/", server, port);
-    let (ws_stream, _) = connect_async(url).await?;
-    println!("已通过 WebSocket 连接到 {}:{}", server, port);
-
-    let (mut write, _) = ws_stream.split();
-    // 准备元数据 JSON
-    let path = std::path::Path::new(file_path);
-    let filename = path.file_name().unwrap().to_string_lossy().to_string();
-    let sha256_str = cryptography::calculate_sha256(file_path)?;
-    let password = cryptography::password_creat();
-    let password_b = cryptography::password_creat();
-    let enc_path = "encrypt.enc";
-    cryptography::encrypt_file(file_path, &*password, enc_path)?;
-    let (salt_hex, nonce_hex, ct_hex) = cryptography::encrypt_data(&*password_b, password.as_ref());
-
-    let metadata = json!({
-        "filename": filename,
-        "sha256": sha256_str,
-        "salt": salt_hex,
-        "nonce": nonce_hex,
-        "ct": ct_hex,
-        "password_b": password_b
-    });
-    let metadata_str = serde_json::to_string(&metadata)?;
-    write.send(Message::Text(metadata_str)).await?;
-
-    // 发送加密后的文件内容（二进制帧）
-    let mut file = File::open(enc_path).await?;
-    let mut buffer = [0u8; 4096];
-    loop {
-        let n = file.read(&mut buffer).await?;
-        if n == 0 { break; }
-        write.send(Message::Binary(buffer[..n].to_vec())).await?;
-    }
-    write.close().await?;
-    println!("文件已通过 WebSocket 发送完成");
-    fs::remove_file(enc_path).await?;
-    Ok(())
+    let (ws, _) = tokio_tungstenite::connect_async(url).await?;
+    say!("已通过 WebSocket 连接到 {}:{}", server, port);
+    let peer = format!("{}:{}", server, port);
+    send_stream(websocket::WsStream::new(ws), file_path, None, Some(&peer), opts).await
 }
 
 /// 生成唯一文件名：若同名文件已存在，则添加客户端地址和时间戳后缀
@@ -369,3 +1400,58 @@ fn generate_unique_filename(
     }
     final_name
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use progress::Progress;
+
+    #[tokio::test]
+    async fn repair_resends_only_the_damaged_chunk() {
+        let data: Vec<u8> = (0..chunked::CHUNK_SIZE * 7 / 2).map(|i| (i % 251) as u8).collect();
+        let source = std::env::temp_dir().join(format!("uft-repair-{}", std::process::id()));
+        std::fs::write(&source, &data).unwrap();
+        let (digest, tree) = hashing::hash_chunks(HashAlgorithm::Sha256, &data[..]).unwrap();
+        let key = cryptography::SessionKey::from_bytes(&[3u8; cryptography::KEY_LENGTH]).unwrap();
+        let data_for_sender = data.clone();
+
+        // 加密发送后篡改第 1 块
+        let mut stream = Vec::new();
+        chunked::send_chunks(
+            &mut &data[..], &mut stream, &key, b"aad", CipherSuite::Aes256Gcm, Compression::None, 0, HashAlgorithm::Sha256,
+            &mut Progress::new("test", None, true),
+        ).await.unwrap();
+        let mut rest = &stream[cryptography::STREAM_HEADER_LENGTH..];
+        let (_, first) = chunked::read_chunk_sync(&mut rest).unwrap();
+        let second = cryptography::STREAM_HEADER_LENGTH + 5 + first.len();
+        stream[second + 5 + 100] ^= 1;
+
+        let mut received = Vec::new();
+        let verified = chunked::recv_verified(
+            &mut &stream[..], &key, b"aad", CipherSuite::Aes256Gcm, Compression::None, HashAlgorithm::Sha256,
+            data.len() as u64, Some(&tree), &mut received, &mut Progress::new("test", None, true),
+        ).await.unwrap();
+        assert_eq!(verified.damaged, vec![1]);
+        assert_eq!(verified.digest, digest);
+
+        let (mut receiver, mut sender) = tokio::io::duplex(64 * 1024);
+        let sender_source = source.clone();
+        let sender = tokio::spawn(async move {
+            let (_, tree) = hashing::hash_chunks(HashAlgorithm::Sha256, &data_for_sender[..]).unwrap();
+            let key = cryptography::SessionKey::from_bytes(&[3u8; cryptography::KEY_LENGTH]).unwrap();
+            read_receipt_with_repairs(&mut sender, &key, &sender_source, &tree).await
+        });
+        let repaired = fetch_repairs(&mut receiver, &key, HashAlgorithm::Sha256, &tree, data.len() as u64, &verified.damaged)
+            .await
+            .unwrap();
+        protocol::write_frame(&mut receiver, &protocol::Receipt::from_result(&Ok(()))).await.unwrap();
+        sender.await.unwrap().unwrap();
+        std::fs::remove_file(&source).unwrap();
+
+        assert_eq!(repaired.keys().copied().collect::<Vec<_>>(), vec![1]);
+        let range = chunked::CHUNK_SIZE..2 * chunked::CHUNK_SIZE;
+        assert_eq!(repaired[&1], data[range.clone()]);
+        received[range.clone()].copy_from_slice(&repaired[&1]);
+        assert_eq!(received, data);
+    }
+}
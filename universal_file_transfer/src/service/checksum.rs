@@ -0,0 +1,63 @@
+use std::path::Path;
+use anyhow::Context;
+use super::hashing::{self, HashAlgorithm};
+use serde_json::json;
+use super::output::{self, FileRecord, say};
+
+/// 计算文件的摘要，按 `sha256sum` 的格式输出：`<十六进制摘要>  <路径>`
+pub(crate) fn print(files: &[String], algo: HashAlgorithm) -> anyhow::Result<()> {
+    for file in files {
+        let digest = hashing::hash_file(algo, Path::new(file))
+            .with_context(|| format!("无法读取 {}", file))?;
+        say!("{}  {}", digest, file);
+        output::file("hashed", FileRecord::new(file.as_str()).digest(algo, &digest));
+    }
+    Ok(())
+}
+
+/// 按 `sha256sum` 格式的校验文件逐个校验，路径相对于当前目录；有文件不匹配或无法读取时返回错误
+pub(crate) fn check(sum_file: &str, algo: HashAlgorithm) -> anyhow::Result<()> {
+    let contents = std::fs::read_to_string(sum_file)
+        .map_err(|e| anyhow::anyhow!("无法读取校验文件 {}: {}", sum_file, e))?;
+    let mut failed = 0;
+    let mut checked = 0;
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (expected, file) = parse_line(line, algo)
+            .ok_or_else(|| anyhow::anyhow!("{} 第 {} 行格式错误", sum_file, index + 1))?;
+        checked += 1;
+        let status = match hashing::hash_file(algo, Path::new(file)) {
+            Ok(actual) if actual.eq_ignore_ascii_case(expected) => {
+                say!("{}: 通过", file);
+                "ok"
+            }
+            Ok(_) => {
+                failed += 1;
+                say!("{}: 不匹配", file);
+                "mismatch"
+            }
+            Err(e) => {
+                failed += 1;
+                say!("{}: 无法读取（{}）", file, e);
+                "unreadable"
+            }
+        };
+        output::event("checked", json!({ "path": file, "hash": algo, "expected": expected, "status": status }));
+    }
+    if failed > 0 {
+        anyhow::bail!("{} 个文件中有 {} 个校验失败", checked, failed);
+    }
+    say!("{} 个文件全部校验通过", checked);
+    Ok(())
+}
+
+/// 解析一行 `<摘要>  <路径>`，兼容 `sha256sum -b` 的 `<摘要> *<路径>` 写法
+fn parse_line(line: &str, algo: HashAlgorithm) -> Option<(&str, &str)> {
+    let (digest, rest) = line.split_once(' ')?;
+    let file = rest.strip_prefix(' ').or_else(|| rest.strip_prefix('*'))?;
+    let valid = digest.len() == algo.hex_len() && digest.bytes().all(|b| b.is_ascii_hexdigit());
+    (valid && !file.is_empty()).then_some((digest, file))
+}
@@ -0,0 +1,504 @@
+use std::io::{Read, Write};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use super::compression::{self, Compression};
+use super::cryptography::{self, CipherSuite, SessionKey, StreamCipher};
+use super::hashing::{self, HashAlgorithm, Hasher, MerkleTree};
+use super::metrics;
+use super::progress::Progress;
+
+/// 每个数据块的明文大小，与分块校验的块大小一致：文件模式下每个数据块对应 Merkle 树的一个叶子
+pub(crate) const CHUNK_SIZE: usize = hashing::VERIFY_CHUNK_SIZE;
+/// 单个加密块允许的最大长度（压缩可能使数据略微变大）
+const MAX_CHUNK_FRAME: usize = 2 * CHUNK_SIZE;
+
+/// 块标记：普通数据块
+const FLAG_DATA: u8 = 0;
+/// 块标记：最后一块，内容为全部明文的摘要（十六进制）
+const FLAG_LAST: u8 = 1;
+
+/// 一个块的帧：`[u8 标记][u32 密文长度][密文]`
+fn frame(flag: u8, ciphertext: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(5 + ciphertext.len());
+    out.push(flag);
+    out.extend_from_slice(&(ciphertext.len() as u32).to_be_bytes());
+    out.extend_from_slice(ciphertext);
+    out
+}
+
+/// 发送端的逐块加密：每块先压缩再加密，同时计算全部明文的摘要
+pub(crate) struct Sealer {
+    cipher: StreamCipher,
+    algo: Compression,
+    level: i32,
+    hasher: Hasher,
+    total: u64,
+}
+
+impl Sealer {
+    /// 返回加密器和需要最先写出的流头部
+    pub(crate) fn new(
+        cipher: CipherSuite,
+        key: &SessionKey,
+        aad: &[u8],
+        algo: Compression,
+        level: i32,
+        hash: HashAlgorithm,
+    ) -> (Sealer, Vec<u8>) {
+        let (cipher, header) = StreamCipher::new_sender(cipher, key, aad);
+        (Sealer { cipher, algo, level, hasher: hash.hasher(), total: 0 }, header)
+    }
+
+    /// 加密一块明文，返回完整的帧
+    pub(crate) fn data(&mut self, chunk: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.hasher.update(chunk);
+        self.total += chunk.len() as u64;
+        let packed = compression::compress(chunk.to_vec(), self.algo, self.level)?;
+        Ok(frame(FLAG_DATA, &self.cipher.seal(&packed, false)?))
+    }
+
+    /// 结束数据流：返回包含摘要的尾块帧、明文总长度和摘要
+    pub(crate) fn last(mut self) -> anyhow::Result<(Vec<u8>, u64, String)> {
+        let digest = self.hasher.finalize_hex();
+        let frame = frame(FLAG_LAST, &self.cipher.seal(digest.as_bytes(), true)?);
+        Ok((frame, self.total, digest))
+    }
+}
+
+/// 接收端的逐块解密，块序号和结束标记都在 nonce 中，块被重排、删除或截断时解密失败
+pub(crate) struct Opener {
+    cipher: StreamCipher,
+    algo: Compression,
+}
+
+impl Opener {
+    pub(crate) fn new(
+        cipher: CipherSuite,
+        key: &SessionKey,
+        header: &[u8; cryptography::STREAM_HEADER_LENGTH],
+        aad: &[u8],
+        algo: Compression,
+    ) -> Opener {
+        Opener { cipher: StreamCipher::new_receiver(cipher, key, header, aad), algo }
+    }
+
+    /// 解密并解压一个数据块，明文最多 `limit` 字节；失败时块序号照常前进，后续的块仍可解密
+    pub(crate) fn data(&mut self, ciphertext: &[u8], limit: usize) -> anyhow::Result<Vec<u8>> {
+        let packed = self.cipher.open(ciphertext, false)?;
+        Ok(compression::decompress(packed, self.algo, limit)?)
+    }
+
+    /// 解密尾块，返回发送端给出的摘要
+    pub(crate) fn last(&mut self, ciphertext: &[u8]) -> anyhow::Result<String> {
+        Ok(String::from_utf8(self.cipher.open(ciphertext, true)?).unwrap_or_default())
+    }
+}
+
+/// 尽量读满缓冲区，返回实际读取的字节数（小于缓冲区长度说明已到末尾）
+async fn read_full<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = reader.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<[u8; cryptography::STREAM_HEADER_LENGTH]> {
+    let mut header = [0u8; cryptography::STREAM_HEADER_LENGTH];
+    stream.read_exact(&mut header).await?;
+    Ok(header)
+}
+
+/// 读取一个块的标记和密文
+async fn read_chunk<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<(u8, Vec<u8>)> {
+    let flag = stream.read_u8().await
+        .map_err(|_| anyhow::anyhow!("数据流在结束标记前中断"))?;
+    let len = stream.read_u32().await? as usize;
+    if len > MAX_CHUNK_FRAME {
+        anyhow::bail!("数据块过长: {} 字节", len);
+    }
+    let mut ciphertext = vec![0u8; len];
+    stream.read_exact(&mut ciphertext).await?;
+    Ok((flag, ciphertext))
+}
+
+/// 同步版本的 [`read_chunk`]，用于读取本地的加密文件
+pub(crate) fn read_chunk_sync<R: Read>(reader: &mut R) -> anyhow::Result<(u8, Vec<u8>)> {
+    let mut prefix = [0u8; 5];
+    reader.read_exact(&mut prefix)
+        .map_err(|_| anyhow::anyhow!("数据流在结束标记前中断"))?;
+    let len = u32::from_be_bytes(prefix[1..].try_into().unwrap()) as usize;
+    if len > MAX_CHUNK_FRAME {
+        anyhow::bail!("数据块过长: {} 字节", len);
+    }
+    let mut ciphertext = vec![0u8; len];
+    reader.read_exact(&mut ciphertext)?;
+    Ok((prefix[0], ciphertext))
+}
+
+/// 块是否为数据块，标记未知时报错
+pub(crate) fn is_data(flag: u8) -> anyhow::Result<bool> {
+    match flag {
+        FLAG_DATA => Ok(true),
+        FLAG_LAST => Ok(false),
+        _ => anyhow::bail!("未知的数据块标记: {}", flag),
+    }
+}
+
+/// 发送端：边读边压缩加密，按块发送，同时按 `hash` 计算摘要；结束时发送包含摘要的尾块。
+/// 返回明文总长度和摘要
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn send_chunks<R, S>(
+    reader: &mut R,
+    stream: &mut S,
+    key: &SessionKey,
+    aad: &[u8],
+    cipher: CipherSuite,
+    algo: Compression,
+    level: i32,
+    hash: HashAlgorithm,
+    progress: &mut Progress,
+) -> anyhow::Result<(u64, String)>
+where
+    R: AsyncRead + Unpin,
+    S: AsyncWrite + Unpin,
+{
+    let (mut sealer, header) = Sealer::new(cipher, key, aad, algo, level, hash);
+    stream.write_all(&header).await?;
+
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    loop {
+        let n = read_full(reader, &mut buf).await?;
+        if n > 0 {
+            stream.write_all(&sealer.data(&buf[..n])?).await?;
+            progress.inc(n as u64);
+        }
+        if n < buf.len() {
+            break;
+        }
+    }
+
+    let (last, total, digest) = sealer.last()?;
+    stream.write_all(&last).await?;
+    progress.finish();
+    Ok((total, digest))
+}
+
+/// 接收端：逐块解密解压并写入 `out`，同时按 `hash` 计算摘要，最后与尾块中的摘要比对。
+/// 任何一块解密失败都中止。返回明文总长度和摘要
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn recv_chunks<S, W>(
+    stream: &mut S,
+    key: &SessionKey,
+    aad: &[u8],
+    cipher: CipherSuite,
+    algo: Compression,
+    hash: HashAlgorithm,
+    out: &mut W,
+    progress: &mut Progress,
+) -> anyhow::Result<(u64, String)>
+where
+    S: AsyncRead + Unpin,
+    W: Write,
+{
+    let mut opener = Opener::new(cipher, key, &read_header(stream).await?, aad, algo);
+    let mut hasher = hash.hasher();
+    let mut total = 0u64;
+    loop {
+        let (flag, ciphertext) = read_chunk(stream).await?;
+        if !is_data(flag)? {
+            let expected = opener.last(&ciphertext)?;
+            let digest = hasher.finalize_hex();
+            if digest != expected {
+                return Err(metrics::integrity_failure(format!("{} 校验失败: {} != {}", hash, expected, digest)));
+            }
+            out.flush()?;
+            progress.finish();
+            return Ok((total, digest));
+        }
+        let plain = opener.data(&ciphertext, CHUNK_SIZE)?;
+        hasher.update(&plain);
+        total += plain.len() as u64;
+        out.write_all(&plain)?;
+        progress.inc(plain.len() as u64);
+    }
+}
+
+/// 同步版本的 [`recv_chunks`]：从本地加密文件中逐块解密并写入 `out`
+pub(crate) fn open_chunks<R, W>(
+    reader: &mut R,
+    key: &SessionKey,
+    aad: &[u8],
+    cipher: CipherSuite,
+    algo: Compression,
+    hash: HashAlgorithm,
+    out: &mut W,
+) -> anyhow::Result<(u64, String)>
+where
+    R: Read,
+    W: Write,
+{
+    let mut header = [0u8; cryptography::STREAM_HEADER_LENGTH];
+    reader.read_exact(&mut header)?;
+    let mut opener = Opener::new(cipher, key, &header, aad, algo);
+    let mut hasher = hash.hasher();
+    let mut total = 0u64;
+    loop {
+        let (flag, ciphertext) = read_chunk_sync(reader)?;
+        if !is_data(flag)? {
+            let expected = opener.last(&ciphertext)?;
+            let digest = hasher.finalize_hex();
+            if digest != expected {
+                anyhow::bail!("{} 校验失败: {} != {}", hash, expected, digest);
+            }
+            out.flush()?;
+            return Ok((total, digest));
+        }
+        let plain = opener.data(&ciphertext, CHUNK_SIZE)?;
+        hasher.update(&plain);
+        total += plain.len() as u64;
+        out.write_all(&plain)?;
+    }
+}
+
+/// [`recv_verified`] 的结果
+pub(crate) struct Verified {
+    /// 解密失败或与分块摘要不符的块序号，已在输出中以同样长度的 0 占位
+    pub(crate) damaged: Vec<u64>,
+    /// 尾块中发送端给出的摘要；没有损坏的块时已与收到的数据核对
+    pub(crate) digest: String,
+}
+
+/// 第 `index` 块的明文长度
+pub(crate) fn chunk_len(size: u64, index: u64) -> usize {
+    size.saturating_sub(index * CHUNK_SIZE as u64).min(CHUNK_SIZE as u64) as usize
+}
+
+/// 接收端（文件模式）：逐块解密，并在每块到达时对照 `tree` 中对应的叶子校验，
+/// 解密失败或校验不符的块记为损坏并写入占位数据，其余的块照常写入 `out`，之后由调用方请求重传。
+/// `size` 为文件头之前声明的文件大小；没有分块摘要（`tree` 为 `None`）时无法重传，任何一块损坏都中止
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn recv_verified<S, W>(
+    stream: &mut S,
+    key: &SessionKey,
+    aad: &[u8],
+    cipher: CipherSuite,
+    algo: Compression,
+    hash: HashAlgorithm,
+    size: u64,
+    tree: Option<&MerkleTree>,
+    out: &mut W,
+    progress: &mut Progress,
+) -> anyhow::Result<Verified>
+where
+    S: AsyncRead + Unpin,
+    W: Write,
+{
+    let count = size.div_ceil(CHUNK_SIZE as u64);
+    if tree.is_some_and(|tree| tree.leaf_count() != count.max(1)) {
+        anyhow::bail!("分块摘要数量与文件大小不符");
+    }
+    let mut opener = Opener::new(cipher, key, &read_header(stream).await?, aad, algo);
+    let mut hasher = hash.hasher();
+    let mut damaged = Vec::new();
+    let mut index = 0u64;
+    loop {
+        let (flag, ciphertext) = read_chunk(stream).await?;
+        if !is_data(flag)? {
+            let digest = opener.last(&ciphertext)?;
+            if index != count {
+                anyhow::bail!("文件大小不符：声明 {} 字节，实际收到 {} 个数据块", size, index);
+            }
+            if damaged.is_empty() {
+                let calculated = hasher.finalize_hex();
+                if calculated != digest {
+                    return Err(metrics::integrity_failure(format!("{} 校验失败: {} != {}", hash, digest, calculated)));
+                }
+            }
+            out.flush()?;
+            progress.finish();
+            return Ok(Verified { damaged, digest });
+        }
+        if index >= count {
+            anyhow::bail!("文件大小不符：数据块多于声明的 {} 字节", size);
+        }
+
+        let expected_len = chunk_len(size, index);
+        let plain = opener.data(&ciphertext, expected_len).ok()
+            .filter(|plain| plain.len() == expected_len && tree.is_none_or(|tree| tree.verify_chunk(index, plain)));
+        let plain = match (plain, tree) {
+            (Some(plain), _) => plain,
+            (None, Some(_)) => {
+                transfer_api::metrics::integrity_failure();
+                damaged.push(index);
+                vec![0u8; expected_len]
+            }
+            (None, None) => return Err(metrics::integrity_failure(format!("第 {} 块解密失败：数据已被篡改", index))),
+        };
+        hasher.update(&plain);
+        out.write_all(&plain)?;
+        progress.inc(plain.len() as u64);
+        index += 1;
+    }
+}
+
+/// 中转存储：不解密，把流头部和所有块原样写入 `out`，直到尾块为止。返回写入的字节数
+pub(crate) async fn copy_chunks<S, W>(stream: &mut S, out: &mut W, progress: &mut Progress) -> anyhow::Result<u64>
+where
+    S: AsyncRead + Unpin,
+    W: Write,
+{
+    let header = read_header(stream).await?;
+    out.write_all(&header)?;
+    let mut written = header.len() as u64;
+    loop {
+        let (flag, ciphertext) = read_chunk(stream).await?;
+        let data = is_data(flag)?;
+        let frame = frame(flag, &ciphertext);
+        out.write_all(&frame)?;
+        written += frame.len() as u64;
+        progress.inc(frame.len() as u64);
+        if !data {
+            out.flush()?;
+            progress.finish();
+            return Ok(written);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AAD: &[u8] = b"header";
+
+    fn key() -> SessionKey {
+        SessionKey::from_bytes(&[7u8; cryptography::KEY_LENGTH]).unwrap()
+    }
+
+    fn progress() -> Progress {
+        Progress::new("test", None, true)
+    }
+
+    /// 2.5 块的数据加密后的完整数据流
+    async fn sealed_stream(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        send_chunks(
+            &mut &data[..], &mut out, &key(), AAD, CipherSuite::Aes256Gcm, Compression::None, 0, HashAlgorithm::Sha256, &mut progress(),
+        ).await.unwrap();
+        out
+    }
+
+    fn sample() -> Vec<u8> {
+        (0..CHUNK_SIZE * 5 / 2).map(|i| (i % 253) as u8).collect()
+    }
+
+    /// 拆出流头部和各块
+    fn split(stream: &[u8]) -> (Vec<u8>, Vec<(u8, Vec<u8>)>) {
+        let (header, mut rest) = stream.split_at(cryptography::STREAM_HEADER_LENGTH);
+        let mut frames = Vec::new();
+        while !rest.is_empty() {
+            frames.push(read_chunk_sync(&mut rest).unwrap());
+        }
+        (header.to_vec(), frames)
+    }
+
+    fn join(header: &[u8], frames: &[(u8, Vec<u8>)]) -> Vec<u8> {
+        let mut out = header.to_vec();
+        for (flag, ct) in frames {
+            out.extend_from_slice(&frame(*flag, ct));
+        }
+        out
+    }
+
+    async fn receive(stream: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        recv_chunks(
+            &mut &stream[..], &key(), AAD, CipherSuite::Aes256Gcm, Compression::None, HashAlgorithm::Sha256, &mut out, &mut progress(),
+        ).await?;
+        Ok(out)
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let data = sample();
+        let stream = sealed_stream(&data).await;
+        let (_, frames) = split(&stream);
+        assert_eq!(frames.len(), 4);
+        assert_eq!(receive(&stream).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn nonce_counter_rejects_reordered_chunks() {
+        let stream = sealed_stream(&sample()).await;
+        let (header, mut frames) = split(&stream);
+        frames.swap(0, 1);
+        assert!(receive(&join(&header, &frames)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn last_flag_is_authenticated() {
+        let stream = sealed_stream(&sample()).await;
+        let (header, mut frames) = split(&stream);
+        // 去掉尾块并把最后一个数据块标为尾块：结束标记在 nonce 中，解密失败
+        frames.pop();
+        frames.last_mut().unwrap().0 = FLAG_LAST;
+        assert!(receive(&join(&header, &frames)).await.is_err());
+        // 尾块标为数据块同样失败
+        let (header, mut frames) = split(&stream);
+        frames.last_mut().unwrap().0 = FLAG_DATA;
+        assert!(receive(&join(&header, &frames)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn truncation_is_rejected() {
+        let stream = sealed_stream(&sample()).await;
+        let (header, frames) = split(&stream);
+        for keep in 0..frames.len() {
+            let err = receive(&join(&header, &frames[..keep])).await.unwrap_err();
+            assert!(err.to_string().contains("结束标记前中断"), "{}", err);
+        }
+        // 块中间截断
+        assert!(receive(&stream[..stream.len() - 10]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_chunk_is_rejected() {
+        // 发送端正常不会产生超过一块大小的块；压缩后很小的块在解压时即被拒绝
+        let (mut sealer, mut stream) = Sealer::new(CipherSuite::Aes256Gcm, &key(), AAD, Compression::Zstd, 3, HashAlgorithm::Sha256);
+        stream.extend_from_slice(&sealer.data(&vec![0u8; CHUNK_SIZE + 1]).unwrap());
+        stream.extend_from_slice(&sealer.last().unwrap().0);
+        let result = recv_chunks(
+            &mut &stream[..], &key(), AAD, CipherSuite::Aes256Gcm, Compression::Zstd, HashAlgorithm::Sha256, &mut Vec::new(), &mut progress(),
+        ).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn verified_receive_marks_only_the_damaged_chunk() {
+        let data = sample();
+        let (_, tree) = hashing::hash_chunks(HashAlgorithm::Sha256, &data[..]).unwrap();
+        let stream = sealed_stream(&data).await;
+        let (header, mut frames) = split(&stream);
+        frames[1].1[100] ^= 1;
+
+        let mut out = Vec::new();
+        let verified = recv_verified(
+            &mut &join(&header, &frames)[..], &key(), AAD, CipherSuite::Aes256Gcm, Compression::None, HashAlgorithm::Sha256,
+            data.len() as u64, Some(&tree), &mut out, &mut progress(),
+        ).await.unwrap();
+        assert_eq!(verified.damaged, vec![1]);
+        assert_eq!(out.len(), data.len());
+        assert_eq!(tree.corrupt_chunks(&out), vec![1]);
+
+        // 没有分块摘要时无法重传，直接失败
+        let result = recv_verified(
+            &mut &join(&header, &frames)[..], &key(), AAD, CipherSuite::Aes256Gcm, Compression::None, HashAlgorithm::Sha256,
+            data.len() as u64, None, &mut Vec::new(), &mut progress(),
+        ).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use rand::Rng;
+use rand::seq::SliceRandom;
+use sha2::{Digest, Sha256};
+
+/// 传输码中编号部分的取值上限
+const NAMEPLATE_MAX: u32 = 999;
+
+/// 传输码中使用的单词数量
+const WORD_COUNT: usize = 2;
+
+/// 传输码词表（256 个易读易拼的英文单词）
+const WORDS: [&str; 256] = [
+    "acrobat", "adrift", "almanac", "amber", "anchor", "antler", "apollo", "arcade", "armada",
+    "aspen", "atlas", "autumn", "avenue", "bagpipe", "balloon", "bamboo", "banjo", "banner",
+    "barley", "basalt", "beacon", "beehive", "bellows", "bicycle", "bison", "blizzard",
+    "blossom", "bonfire", "bramble", "breeze", "brigade", "bronze", "buckle", "buffalo",
+    "bugle", "cabin", "cactus", "caravan", "cargo", "carnival", "cascade", "castle", "cedar",
+    "celery", "chalk", "chapel", "chariot", "cherry", "chimney", "cinder", "citadel",
+    "clockwork", "clover", "cobalt", "cobweb", "comet", "compass", "copper", "coral", "cornet",
+    "cosmos", "cottage", "cowboy", "crater", "crayon", "cricket", "crossover", "crystal",
+    "cupboard", "cyclone", "dagger", "daisy", "dolphin", "domino", "dragon", "drizzle",
+    "dynamo", "eagle", "easel", "echo", "eclipse", "ember", "emerald", "engine", "falcon",
+    "feather", "fennel", "ferry", "fiddle", "firefly", "flannel", "flint", "fossil",
+    "fountain", "fox", "gadget", "galaxy", "garnet", "gazelle", "geyser", "ginger", "glacier",
+    "goblet", "goldfish", "gondola", "granite", "gravel", "guitar", "hammock", "harbor",
+    "harvest", "hazel", "helmet", "hermit", "hickory", "horizon", "hornet", "iceberg", "igloo",
+    "indigo", "island", "ivory", "jackal", "jasmine", "jester", "jigsaw", "jungle", "kayak",
+    "kettle", "kiwi", "lagoon", "lantern", "larch", "lemon", "lighthouse", "lilac", "lobster",
+    "locket", "lotus", "magnet", "mallet", "mango", "maple", "marble", "meadow", "meerkat",
+    "meteor", "mimosa", "mirror", "monsoon", "mosaic", "muffin", "mustang", "nectar", "nickel",
+    "nomad", "nugget", "nutmeg", "oasis", "obsidian", "octopus", "olive", "onyx", "orbit",
+    "orchard", "otter", "oyster", "paddle", "pagoda", "panther", "papaya", "parrot", "pebble",
+    "pelican", "pepper", "pewter", "pilgrim", "pinecone", "pioneer", "pistachio", "planet",
+    "plume", "pocket", "pollen", "poppy", "prairie", "prism", "puffin", "pumpkin", "quartz",
+    "quill", "quiver", "rabbit", "radar", "raisin", "rapids", "raven", "reef", "ribbon",
+    "ripple", "rocket", "saddle", "saffron", "sapphire", "satchel", "scarab", "seagull",
+    "sequoia", "shamrock", "sherpa", "silo", "sketch", "sleigh", "sparrow", "spindle",
+    "sprocket", "squirrel", "stallion", "summit", "sunbeam", "swallow", "tambourine",
+    "tangerine", "teapot", "thimble", "thistle", "thunder", "tiger", "timber", "tornado",
+    "tortoise", "trellis", "trumpet", "tulip", "tundra", "turbine", "turnip", "umbrella",
+    "unicorn", "valley", "velvet", "violin", "volcano", "voyage", "waffle", "walnut", "walrus",
+    "weaver", "whistle", "willow", "windmill", "wizard", "yodel", "zenith", "zephyr", "zigzag",
+];
+
+/// 生成一次性传输码，形如 `7-crossover-clockwork`。
+/// 编号部分用于在中继或局域网内寻找对端，整个传输码作为 PAKE 的口令协商共享密钥并做密钥确认；
+/// 旁听者无法离线穷举，冒充者每次连接只能猜一次，猜错即中止，因此适合短时一次性传输。
+pub(crate) fn generate() -> String {
+    let mut rng = rand::thread_rng();
+    let mut parts = vec![rng.gen_range(1..=NAMEPLATE_MAX).to_string()];
+    for _ in 0..WORD_COUNT {
+        parts.push(WORDS.choose(&mut rng).unwrap().to_string());
+    }
+    parts.join("-")
+}
+
+/// 判断字符串是否为 [`generate`] 生成格式的传输码
+pub(crate) fn is_valid(code: &str) -> bool {
+    let mut parts = code.split('-');
+    let nameplate_ok = parts.next()
+        .and_then(|n| n.parse::<u32>().ok())
+        .is_some_and(|n| (1..=NAMEPLATE_MAX).contains(&n));
+    let words: Vec<&str> = parts.collect();
+    nameplate_ok && words.len() == WORD_COUNT && words.iter().all(|w| WORDS.contains(w))
+}
+
+/// 由传输码派生配对通道 ID。
+/// 标准格式的传输码只使用公开的编号部分，避免中继或局域网旁听者据此穷举单词；
+/// 自定义传输码则对整体做哈希，其保密性取决于传输码本身的强度。
+pub(crate) fn channel_id(code: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"uft-relay-channel:");
+    if is_valid(code) {
+        hasher.update(code.split('-').next().unwrap_or_default().as_bytes());
+    } else {
+        hasher.update(code.as_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_codes_are_valid() {
+        for _ in 0..100 {
+            assert!(is_valid(&generate()));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_codes() {
+        let word = WORDS[0];
+        assert!(is_valid(&format!("7-{}-{}", word, word)));
+        assert!(!is_valid(&format!("0-{}-{}", word, word)));
+        assert!(!is_valid(&format!("1000-{}-{}", word, word)));
+        assert!(!is_valid(&format!("x-{}-{}", word, word)));
+        assert!(!is_valid(&format!("7-{}", word)));
+        assert!(!is_valid(&format!("7-{}-{}-{}", word, word, word)));
+        assert!(!is_valid(&format!("7-{}-notaword", word)));
+        assert!(!is_valid(""));
+    }
+
+    #[test]
+    fn channel_id_uses_only_nameplate_of_valid_codes() {
+        let a = format!("42-{}-{}", WORDS[0], WORDS[1]);
+        let b = format!("42-{}-{}", WORDS[2], WORDS[3]);
+        let c = format!("43-{}-{}", WORDS[0], WORDS[1]);
+        assert_eq!(channel_id(&a), channel_id(&b));
+        assert_ne!(channel_id(&a), channel_id(&c));
+    }
+
+    #[test]
+    fn channel_id_hashes_custom_codes_whole() {
+        assert_ne!(channel_id("42-custom-secret"), channel_id("42-custom-other"));
+        assert_eq!(channel_id("my secret"), channel_id("my secret"));
+        assert_eq!(channel_id("my secret").len(), 64);
+    }
+}
@@ -0,0 +1,123 @@
+use std::io::{self, Read, Write};
+use std::path::Path;
+use flate2::Compression as GzLevel;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+
+/// 压缩算法（在加密之前对明文进行压缩）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Compression {
+    #[default]
+    Zstd,
+    Gzip,
+    None,
+}
+
+impl Compression {
+    /// 本端支持的全部算法
+    pub(crate) const SUPPORTED: [Compression; 3] = [Compression::Zstd, Compression::Gzip, Compression::None];
+
+    /// 未指定压缩级别时使用的默认值
+    pub(crate) fn default_level(self) -> i32 {
+        match self {
+            Compression::Zstd => 3,
+            Compression::Gzip => 6,
+            Compression::None => 0,
+        }
+    }
+
+    /// 将压缩级别限制在算法允许的范围内
+    pub(crate) fn clamp_level(self, level: i32) -> i32 {
+        match self {
+            Compression::Zstd => level.clamp(1, 22),
+            Compression::Gzip => level.clamp(0, 9),
+            Compression::None => 0,
+        }
+    }
+}
+
+/// 已经是压缩格式、再压缩收益很小的文件扩展名
+const COMPRESSED_EXTENSIONS: [&str; 22] = [
+    "zip", "gz", "tgz", "bz2", "xz", "zst", "7z", "rar", "lz4", "br",
+    "jpg", "jpeg", "png", "gif", "webp", "mp3", "mp4", "mkv", "avi", "mov",
+    "docx", "xlsx",
+];
+
+/// 常见压缩格式的文件头魔数
+const COMPRESSED_MAGICS: [&[u8]; 9] = [
+    &[0x1f, 0x8b],                         // gzip
+    &[0x28, 0xb5, 0x2f, 0xfd],             // zstd
+    b"PK\x03\x04",                          // zip
+    &[0xfd, b'7', b'z', b'X', b'Z', 0x00], // xz
+    b"BZh",                                 // bzip2
+    &[b'7', b'z', 0xbc, 0xaf, 0x27, 0x1c], // 7z
+    b"Rar!",                                // rar
+    &[0x89, b'P', b'N', b'G'],             // png
+    &[0xff, 0xd8, 0xff],                   // jpeg
+];
+
+/// 根据扩展名和文件头判断文件是否已经是压缩格式
+pub(crate) fn is_already_compressed(path: &Path) -> bool {
+    let by_ext = path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| COMPRESSED_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()));
+    if by_ext {
+        return true;
+    }
+
+    let mut head = [0u8; 8];
+    let n = std::fs::File::open(path)
+        .and_then(|mut f| f.read(&mut head))
+        .unwrap_or(0);
+    COMPRESSED_MAGICS.iter().any(|m| head[..n].starts_with(m))
+}
+
+/// 按协商好的算法压缩数据
+pub(crate) fn compress(data: Vec<u8>, algo: Compression, level: i32) -> io::Result<Vec<u8>> {
+    match algo {
+        Compression::Zstd => zstd::encode_all(data.as_slice(), level),
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), GzLevel::new(level as u32));
+            encoder.write_all(&data)?;
+            encoder.finish()
+        }
+        Compression::None => Ok(data),
+    }
+}
+
+/// 按协商好的算法解压数据，结果超过 `limit` 字节时失败：
+/// 很小的压缩数据就能解压出任意多的内容，只读取到超出上限的第一个字节为止，不会先把它们全部放进内存
+pub(crate) fn decompress(data: Vec<u8>, algo: Compression, limit: usize) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let bound = limit as u64 + 1;
+    match algo {
+        Compression::Zstd => zstd::Decoder::new(data.as_slice())?.take(bound).read_to_end(&mut out)?,
+        Compression::Gzip => GzDecoder::new(data.as_slice()).take(bound).read_to_end(&mut out)?,
+        Compression::None => {
+            out = data;
+            out.len()
+        }
+    };
+    if out.len() > limit {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("解压后的数据超过 {} 字节", limit)));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decompress_stops_at_limit() {
+        let data = vec![0u8; 1 << 20];
+        for algo in Compression::SUPPORTED {
+            let packed = compress(data.clone(), algo, algo.default_level()).unwrap();
+            assert_eq!(decompress(packed.clone(), algo, data.len()).unwrap(), data);
+            let err = decompress(packed, algo, data.len() - 1).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", algo);
+        }
+    }
+}
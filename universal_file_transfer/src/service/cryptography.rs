@@ -1,30 +1,57 @@
 use std::fs::File;
 use std::io;
-use std::io::{ErrorKind, Read, Write};
+use std::io::{ErrorKind, Read};
 use aead::Key;
 use aes_gcm::{Aes256Gcm, Nonce};
+use chacha20poly1305::ChaCha20Poly1305;
 use hex::{decode, encode};
-use hmac::Hmac;
-use pbkdf2::pbkdf2;
 use rand::Rng;
 use rand_core::RngCore;
 use sha2::{Digest, Sha256};
-use aes_gcm::{aead::{Aead, KeyInit}};
+use aes_gcm::{aead::{Aead, KeyInit, Payload}};
+use hkdf::Hkdf;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
 
 
 
-//生成密钥
-pub(crate) fn password_creat() -> String {
-    use rand::{distributions::Alphanumeric, Rng};
-    let random_string: String = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(16)
-        .map(char::from)
-        .collect();
-    random_string
+// 会话密钥长度 (Session key length)
+pub(crate) const KEY_LENGTH: usize = 32;
+// 由会话密钥派生单次加密子密钥的 HKDF 域分隔信息 (HKDF info string for per-message subkeys of a session key)
+const SESSION_KDF_INFO: &[u8] = b"uft-session-v1";
+
+// 会话密钥：每次传输由 OsRng 随机生成 32 字节，离开作用域时清零 (Session key: 32 random bytes from OsRng per transfer, zeroed on drop)
+pub(crate) struct SessionKey(Zeroizing<[u8; KEY_LENGTH]>);
+
+impl SessionKey {
+    // 生成新的随机密钥 (Generate a new random key)
+    pub(crate) fn generate() -> SessionKey {
+        let mut key = Zeroizing::new([0u8; KEY_LENGTH]);
+        rand_core::OsRng.fill_bytes(key.as_mut());
+        SessionKey(key)
+    }
+    // 从解密得到的 32 字节恢复随机密钥 (Restore a random key from its 32 decrypted bytes)
+    pub(crate) fn from_bytes(bytes: &[u8]) -> io::Result<SessionKey> {
+        let mut key = Zeroizing::new([0u8; KEY_LENGTH]);
+        if bytes.len() != KEY_LENGTH {
+            return Err(io::Error::new(ErrorKind::InvalidData, "会话密钥长度错误"));
+        }
+        key.copy_from_slice(bytes);
+        Ok(SessionKey(key))
+    }
+    // 密钥的原始字节，用于再次加密传输 (Raw key bytes, for wrapping)
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+    // 用 HKDF 为一次加密派生子密钥 (Derive a per-message subkey with HKDF)
+    fn subkey(&self, salt: &[u8]) -> Zeroizing<[u8; KEY_LENGTH]> {
+        let mut out = Zeroizing::new([0u8; KEY_LENGTH]);
+        Hkdf::<Sha256>::new(Some(salt), self.0.as_ref()).expand(SESSION_KDF_INFO, out.as_mut()).unwrap();
+        out
+    }
 }
-//使用 PBKDF2 对文本进行加密（salt）和散列（hash）运算 (Encrypt (salt) and hash text using PBKDF2.)
-const PBKDF2_ITERATIONS: u32 = 100_000; //设置 PBKDF2 的迭代次数 (Set the number of iterations for PBKDF2.)
+
 const SALT_LENGTH: usize = 16; // 盐的长度 (length of salt)
 const NONCE_LENGTH: usize = 12;
 // 生成随机盐 （Generating Random Salt）
@@ -34,121 +61,385 @@ fn generate_salt() -> Vec<u8> {
     rng.fill(&mut salt[..]);
     salt
 }
-// 使用 PBKDF2 派生出 32 字节密钥 (Use PBKDF2 to derive 32-byte keys)
-fn pbkdf2_hash(password: &str, salt: &[u8]) -> Vec<u8> {
-    let mut output = vec![0u8; 32];
-    // 这里 unwrap 是因为 pbkdf2 返回 ()
-    pbkdf2::<Hmac<Sha256>>(password.as_bytes(), salt, PBKDF2_ITERATIONS, &mut output).unwrap();
-    output
+// 口令派生密钥的算法及参数，由发送端选择并随握手发给接收端 (Passphrase KDF and parameters, chosen by the sender and sent in the handshake)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "lowercase")]
+pub(crate) enum Kdf {
+    // 内存困难的 Argon2id，默认算法 (Memory-hard Argon2id, the default)
+    Argon2id { memory_kib: u32, iterations: u32, parallelism: u32 },
+}
+// Argon2id 默认参数：19 MiB 内存、2 轮、1 线程 (Argon2id defaults: 19 MiB memory, 2 passes, 1 lane)
+pub(crate) const ARGON2_MEMORY_KIB: u32 = 19 * 1024;
+pub(crate) const ARGON2_ITERATIONS: u32 = 2;
+pub(crate) const ARGON2_PARALLELISM: u32 = 1;
+// 口令派生参数的上限 (Upper bounds for passphrase KDF parameters)
+struct KdfLimits {
+    argon2_memory_kib: u32,
+    argon2_iterations: u32,
+    argon2_parallelism: u32,
+}
+// 本机加解密 .uft 文件时接受的上限，参数由用户自己指定 (Limits for local .uft files, where the user chooses the parameters)
+const LOCAL_LIMITS: KdfLimits = KdfLimits {
+    argon2_memory_kib: 1024 * 1024,
+    argon2_iterations: 64,
+    argon2_parallelism: 16,
+};
+// 接收端接受的网络对端参数上限：每个连接都要按对端给出的参数派生一次，须远低于本机上限，
+// 防止对端用大参数耗尽接收端的内存或 CPU
+// (Limits for parameters sent by a network peer: each connection derives once with them,
+//  so they must stay far below the local limits to keep a peer from exhausting the receiver's memory or CPU)
+const PEER_LIMITS: KdfLimits = KdfLimits {
+    argon2_memory_kib: 64 * 1024,
+    argon2_iterations: 8,
+    argon2_parallelism: 4,
+};
+
+impl Default for Kdf {
+    fn default() -> Kdf {
+        Kdf::Argon2id {
+            memory_kib: ARGON2_MEMORY_KIB,
+            iterations: ARGON2_ITERATIONS,
+            parallelism: ARGON2_PARALLELISM,
+        }
+    }
 }
-// 加密函数
-pub(crate) fn encrypt_data(password: &str, plaintext: &[u8]) -> (String, String, String) {
-    let salt = generate_salt(); // salt 生成函数 
-    let key_bytes = pbkdf2_hash(password, &salt); // PBKDF2 派生函数
 
+impl Kdf {
+    // 检查参数是否在本机允许的范围内 (Check that the parameters are within the local limits)
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        self.check(&LOCAL_LIMITS)
+    }
+    // 检查网络对端给出的参数是否在接收端允许的范围内 (Check parameters sent by a network peer against the receiver's limits)
+    pub(crate) fn validate_peer(&self) -> io::Result<()> {
+        self.check(&PEER_LIMITS).map_err(|e| {
+            let limits = &PEER_LIMITS;
+            io::Error::new(e.kind(), format!(
+                "{}；经网络传输时 Argon2id 最多 {} MiB 内存、{} 轮、{} 线程",
+                e, limits.argon2_memory_kib / 1024, limits.argon2_iterations, limits.argon2_parallelism,
+            ))
+        })
+    }
+    fn check(&self, limits: &KdfLimits) -> io::Result<()> {
+        let Kdf::Argon2id { memory_kib, iterations, parallelism } = *self;
+        let ok = (1..=limits.argon2_iterations).contains(&iterations)
+            && (1..=limits.argon2_parallelism).contains(&parallelism)
+            && (8 * parallelism..=limits.argon2_memory_kib).contains(&memory_kib);
+        if ok {
+            Ok(())
+        } else {
+            Err(io::Error::new(ErrorKind::InvalidInput, format!("口令派生参数超出允许范围: {:?}", self)))
+        }
+    }
+
+    // 由口令和 salt 派生 32 字节密钥 (Derive a 32-byte key from a passphrase and salt)
+    pub(crate) fn derive(&self, password: &str, salt: &[u8]) -> io::Result<[u8; 32]> {
+        self.validate()?;
+        let mut key = [0u8; 32];
+        let Kdf::Argon2id { memory_kib, iterations, parallelism } = *self;
+        let params = argon2::Params::new(memory_kib, iterations, parallelism, Some(key.len()))
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+        argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+            .hash_password_into(password.as_bytes(), salt, &mut key)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+        Ok(key)
+    }
+}
+
+// 用会话密钥加密，返回十六进制的 salt、nonce 和密文 (Encrypt with a session key; returns hex salt, nonce and ciphertext)
+pub(crate) fn encrypt_data(key: &SessionKey, plaintext: &[u8]) -> (String, String, String) {
+    let salt = generate_salt();
+    seal_hex(&key.subkey(&salt), salt, plaintext).expect("encryption failure!")
+}
+// 用指定的口令派生算法加密 (Encrypt with the given passphrase KDF)
+pub(crate) fn encrypt_data_kdf(kdf: &Kdf, password: &str, plaintext: &[u8]) -> io::Result<(String, String, String)> {
+    let salt = generate_salt(); // salt 生成函数 
+    let key_bytes = Zeroizing::new(kdf.derive(password, &salt)?); // 口令派生函数
+    seal_hex(&key_bytes, salt, plaintext)
+}
+fn seal_hex(key_bytes: &[u8; KEY_LENGTH], salt: Vec<u8>, plaintext: &[u8]) -> io::Result<(String, String, String)> {
     // 显式指定 key 类型 (Specify key type explicitly)
-    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key_bytes));
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key_bytes));
 
     let mut nonce_bytes = [0u8; NONCE_LENGTH];
     rand::thread_rng().fill(&mut nonce_bytes);
     let nonce = Nonce::from_slice(&nonce_bytes);
 
     let ciphertext = cipher.encrypt(nonce, plaintext)
-        .expect("encryption failure!");
+        .map_err(|_| io::Error::other("加密失败"))?;
 
-    (
+    Ok((
         encode(&salt),
-        encode(&nonce_bytes),
+        encode(nonce_bytes),
         encode(&ciphertext),
-    )
+    ))
 }
-// 解密函数 (decrypt function)
-pub(crate) fn decrypt_data(
+// 用会话密钥解密 encrypt_data 的结果 (Decrypt the output of encrypt_data with a session key)
+pub(crate) fn decrypt_data(key: &SessionKey, salt_hex: &str, nonce_hex: &str, ct_hex: &str) -> io::Result<Vec<u8>> {
+    open_hex(salt_hex, nonce_hex, ct_hex, |salt| Ok(key.subkey(salt)))
+}
+// 用指定的口令派生算法解密 (Decrypt with the given passphrase KDF)
+pub(crate) fn decrypt_data_kdf(
+    kdf: &Kdf,
     password: &str,
     salt_hex: &str,
     nonce_hex: &str,
     ct_hex: &str
-) -> Result<Vec<u8>, aes_gcm::Error> {
-    let salt        = decode(salt_hex).expect("invalid salt hex");
-    let nonce_bytes = decode(nonce_hex).expect("invalid nonce hex");
-    let ciphertext  = decode(ct_hex).expect("invalid ciphertext hex");
+) -> io::Result<Vec<u8>> {
+    open_hex(salt_hex, nonce_hex, ct_hex, |salt| Ok(Zeroizing::new(kdf.derive(password, salt)?)))
+}
+fn open_hex(
+    salt_hex: &str,
+    nonce_hex: &str,
+    ct_hex: &str,
+    derive: impl FnOnce(&[u8]) -> io::Result<Zeroizing<[u8; KEY_LENGTH]>>,
+) -> io::Result<Vec<u8>> {
+    let invalid = || io::Error::new(ErrorKind::InvalidData, "解密失败：密钥错误或数据损坏");
+    let salt        = decode(salt_hex).map_err(|_| invalid())?;
+    let nonce_bytes = decode(nonce_hex).map_err(|_| invalid())?;
+    let ciphertext  = decode(ct_hex).map_err(|_| invalid())?;
+    if nonce_bytes.len() != NONCE_LENGTH {
+        return Err(invalid());
+    }
 
-    let key_bytes = pbkdf2_hash(password, &salt);
+    let key_bytes = derive(&salt)?;
 
     // 显式指定 key 类型 (Specify key type explicitly)
-    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key_bytes));
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key_bytes.as_ref()));
     let nonce  = Nonce::from_slice(&nonce_bytes);
 
-    cipher.decrypt(nonce, ciphertext.as_ref())
+    cipher.decrypt(nonce, ciphertext.as_ref()).map_err(|_| invalid())
 }
 
+// 接收者公钥加密的 HKDF 域分隔信息 (HKDF info string for recipient public-key encryption)
+const RECIPIENT_KDF_INFO: &[u8] = b"uft-x25519-v1";
+// 由 X25519 共享密钥派生 32 字节密钥，salt 为临时公钥 + 接收者公钥 (Derive a 32-byte key from the X25519 shared secret, salted with ephemeral + recipient public keys)
+fn recipient_key(shared: &[u8], ephemeral: &PublicKey, recipient: &PublicKey) -> [u8; 32] {
+    let mut salt = ephemeral.as_bytes().to_vec();
+    salt.extend_from_slice(recipient.as_bytes());
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared).expand(RECIPIENT_KDF_INFO, &mut key).unwrap();
+    key
+}
+// 加密给指定接收者（age 风格）：临时 X25519 密钥与接收者公钥协商出密钥，无需接收者在线；
+// 返回值与 encrypt_data 对应，第一项为临时公钥而非 salt
+// (Encrypt to a recipient, age-style: an ephemeral X25519 key agrees a key with the recipient's public key,
+//  so the recipient need not be online; returns the same triple as encrypt_data, with the ephemeral public key instead of the salt)
+pub(crate) fn encrypt_data_to(recipient: &PublicKey, plaintext: &[u8]) -> (String, String, String) {
+    let secret = EphemeralSecret::random_from_rng(rand_core::OsRng);
+    let ephemeral = PublicKey::from(&secret);
+    let shared = secret.diffie_hellman(recipient);
+    let key_bytes = recipient_key(shared.as_bytes(), &ephemeral, recipient);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key_bytes));
 
-// 使用 AES-256-GCM 加密文件内容 (Encrypting File Contents with AES-256-GCM)
-pub(crate) fn encrypt_file(path: &str, password: &str, out_path: &str) -> io::Result<()> {
-    let mut file = File::open(path)?;
-    let mut plaintext = Vec::new();
-    file.read_to_end(&mut plaintext)?;
+    let mut nonce_bytes = [0u8; NONCE_LENGTH];
+    rand_core::OsRng.fill_bytes(&mut nonce_bytes);
+    let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce_bytes), plaintext)
+        .expect("encryption failure!");
 
-    // 盐 + 密钥生成 (Salt + Key Generation)
-    let salt = generate_salt();
-    let mut key_bytes = [0u8; 32];
-    pbkdf2::<Hmac<Sha256>>(password.as_bytes(), &salt, PBKDF2_ITERATIONS, &mut key_bytes).unwrap();
-    let key = Key::<Aes256Gcm>::from_slice(&key_bytes); //  显式指定类型
+    (
+        encode(ephemeral.as_bytes()),
+        encode(nonce_bytes),
+        encode(&ciphertext),
+    )
+}
+// 接收者用私钥解密 encrypt_data_to 的结果 (The recipient decrypts the output of encrypt_data_to with its private key)
+pub(crate) fn decrypt_data_with(
+    secret: &StaticSecret,
+    ephemeral_hex: &str,
+    nonce_hex: &str,
+    ct_hex: &str,
+) -> Result<Vec<u8>, aes_gcm::Error> {
+    let ephemeral: [u8; 32] = decode(ephemeral_hex).ok()
+        .and_then(|b| b.try_into().ok())
+        .ok_or(aes_gcm::Error)?;
+    let nonce_bytes = decode(nonce_hex).map_err(|_| aes_gcm::Error)?;
+    let ciphertext = decode(ct_hex).map_err(|_| aes_gcm::Error)?;
+    if nonce_bytes.len() != NONCE_LENGTH {
+        return Err(aes_gcm::Error);
+    }
 
-    // Nonce 生成 (Nonce Generation)
-    let mut nonce_bytes = [0u8; 12];
-    rand_core::OsRng.fill_bytes(&mut nonce_bytes);
-    let nonce = Nonce::from_slice(&nonce_bytes); //  12字节 nonce
+    let ephemeral = PublicKey::from(ephemeral);
+    let shared = secret.diffie_hellman(&ephemeral);
+    let key_bytes = recipient_key(shared.as_bytes(), &ephemeral, &PublicKey::from(secret));
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key_bytes));
+    cipher.decrypt(Nonce::from_slice(&nonce_bytes), ciphertext.as_ref())
+}
 
-    // 加密 (encrypted)
-    let cipher = Aes256Gcm::new(key);
-    let ciphertext = cipher.encrypt(nonce, plaintext.as_ref()).expect("加密失败");
 
-    // 输出到文件：salt + nonce + ciphertext  (Output to file: salt + nonce + ciphertext)
-    let mut output = File::create(out_path)?;
-    output.write_all(&salt)?;
-    output.write_all(&nonce_bytes)?;
-    output.write_all(&ciphertext)?;
+// 文件数据使用的加密算法，在握手时协商 (Cipher suite for file data, negotiated in the handshake)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+pub(crate) enum CipherSuite {
+    #[default]
+    #[serde(rename = "aes-256-gcm")]
+    #[value(name = "aes-256-gcm")]
+    Aes256Gcm,
+    #[serde(rename = "chacha20-poly1305")]
+    #[value(name = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+    // 不加密，仅用于可信的实验网络，需显式指定 --insecure (No encryption, trusted lab networks only, requires --insecure)
+    #[serde(rename = "none")]
+    #[value(name = "none")]
+    None,
+}
 
-    Ok(())
+impl CipherSuite {
+    // 本端的默认偏好：CPU 有 AES 指令时优先 AES-256-GCM，否则优先 ChaCha20-Poly1305；不包含 None
+    // (Default preference: AES-256-GCM first when the CPU has AES instructions, otherwise ChaCha20-Poly1305; never None)
+    pub(crate) fn preferred() -> Vec<CipherSuite> {
+        if has_aes_instructions() {
+            vec![CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305]
+        } else {
+            vec![CipherSuite::ChaCha20Poly1305, CipherSuite::Aes256Gcm]
+        }
+    }
+    // 由会话密钥和 salt 派生子密钥并创建加密器 (Derive a subkey from the session key and salt, and create the cipher)
+    fn derive(self, key: &SessionKey, salt: &[u8]) -> Box<dyn Cipher> {
+        match self {
+            CipherSuite::Aes256Gcm => Box::new(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.subkey(salt).as_ref()))),
+            CipherSuite::ChaCha20Poly1305 => Box::new(ChaCha20Poly1305::new(Key::<ChaCha20Poly1305>::from_slice(key.subkey(salt).as_ref()))),
+            // 明文传输无需派生密钥 (No key needed for plaintext)
+            CipherSuite::None => Box::new(NullCipher),
+        }
+    }
 }
-//解密文件内容 (Decrypting the contents of a file)
-pub(crate) fn decrypt_file(enc_path: &str, password: &str, out_path: &str) -> io::Result<()> {
-    let mut file = File::open(enc_path)?;
-    let mut contents = Vec::new();
-    file.read_to_end(&mut contents)?;
 
-    // 检查内容长度是否合法 (Check for legal content length)
-    if contents.len() < SALT_LENGTH + NONCE_LENGTH {
-        return Err(io::Error::new(ErrorKind::InvalidData, "加密文件格式错误"));
+impl std::fmt::Display for CipherSuite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CipherSuite::Aes256Gcm => "AES-256-GCM",
+            CipherSuite::ChaCha20Poly1305 => "ChaCha20-Poly1305",
+            CipherSuite::None => "none",
+        })
     }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn has_aes_instructions() -> bool {
+    std::arch::is_x86_feature_detected!("aes")
+}
+#[cfg(target_arch = "aarch64")]
+fn has_aes_instructions() -> bool {
+    std::arch::is_aarch64_feature_detected!("aes")
+}
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn has_aes_instructions() -> bool {
+    false
+}
 
-    // 提取 salt (前 16 字节) (Extract salt (first 16 bytes) )
-    let salt = &contents[..SALT_LENGTH];
-    // 提取 nonce (接下来的 12 字节) (Extract nonce (next 12 bytes) )
-    let nonce_bytes = &contents[SALT_LENGTH..SALT_LENGTH + NONCE_LENGTH];
-    // 剩下的是密文 (The rest is ciphertext.)
-    let ciphertext = &contents[SALT_LENGTH + NONCE_LENGTH..];
+// 加密算法接口：12 字节 nonce 的 AEAD，`aad` 为只认证不加密的附加数据
+// (Cipher interface: an AEAD with 12-byte nonces; `aad` is authenticated but not encrypted)
+pub(crate) trait Cipher: Send + Sync {
+    fn seal(&self, nonce: &[u8; NONCE_LENGTH], plaintext: &[u8], aad: &[u8]) -> io::Result<Vec<u8>>;
+    fn open(&self, nonce: &[u8; NONCE_LENGTH], ciphertext: &[u8], aad: &[u8]) -> io::Result<Vec<u8>>;
+}
 
-    // 派生密钥 (derived key)
-    let mut key_bytes = [0u8; 32];
-    pbkdf2::<Hmac<Sha256>>(password.as_bytes(), salt, PBKDF2_ITERATIONS, &mut key_bytes).unwrap();
-    let key = Key::<Aes256Gcm>::from_slice(&key_bytes);
+impl Cipher for Aes256Gcm {
+    fn seal(&self, nonce: &[u8; NONCE_LENGTH], plaintext: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        self.encrypt(Nonce::from_slice(nonce), Payload { msg: plaintext, aad })
+            .map_err(|_| io::Error::other("加密失败"))
+    }
+    fn open(&self, nonce: &[u8; NONCE_LENGTH], ciphertext: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        self.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "解密失败：密钥错误、文件头被篡改或数据损坏"))
+    }
+}
 
-    // 初始化 AES-GCM (Initialise AES-GCM)
-    let cipher = Aes256Gcm::new(key);
-    let nonce = Nonce::from_slice(nonce_bytes);
+impl Cipher for ChaCha20Poly1305 {
+    fn seal(&self, nonce: &[u8; NONCE_LENGTH], plaintext: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        self.encrypt(chacha20poly1305::Nonce::from_slice(nonce), Payload { msg: plaintext, aad })
+            .map_err(|_| io::Error::other("加密失败"))
+    }
+    fn open(&self, nonce: &[u8; NONCE_LENGTH], ciphertext: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+        self.decrypt(chacha20poly1305::Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "解密失败：密钥错误、文件头被篡改或数据损坏"))
+    }
+}
 
-    // 解密密文 (decrypted dense text)
-    let decrypted_data = cipher
-        .decrypt(nonce, ciphertext)
-        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "解密失败：密码错误或数据损坏"))?;
+// 不加密：原样返回数据，完整性只由 SHA256 保证，文件头不受保护
+// (No encryption: data passes through, integrity relies on SHA256 only and the header is unprotected)
+struct NullCipher;
 
-    // 写入输出文件 (Write to output file)
-    let mut output_file = File::create(out_path)?;
-    output_file.write_all(&decrypted_data)?;
-    
-    Ok(())
+impl Cipher for NullCipher {
+    fn seal(&self, _nonce: &[u8; NONCE_LENGTH], plaintext: &[u8], _aad: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(plaintext.to_vec())
+    }
+    fn open(&self, _nonce: &[u8; NONCE_LENGTH], ciphertext: &[u8], _aad: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(ciphertext.to_vec())
+    }
+}
+
+
+// 分块流式加密的 nonce 随机前缀长度 (Length of the random nonce prefix for chunked streaming encryption)
+const STREAM_PREFIX_LENGTH: usize = 7;
+// 流头部：salt + nonce 前缀 (Stream header: salt + nonce prefix)
+pub(crate) const STREAM_HEADER_LENGTH: usize = SALT_LENGTH + STREAM_PREFIX_LENGTH;
+
+// 分块流式加密：密钥只派生一次，每块的 nonce = 7 字节随机前缀 + 4 字节块序号 + 1 字节结束标记，
+// 块被重排、删除或截断都会导致解密失败
+// (Chunked streaming encryption: the key is derived once, each chunk's nonce = 7-byte random prefix
+//  + 4-byte chunk counter + 1-byte last flag, so reordered, dropped or truncated chunks fail to decrypt)
+pub(crate) struct StreamCipher {
+    cipher: Box<dyn Cipher>,
+    // 每块都认证的文件头 (Header authenticated with every chunk)
+    aad: Vec<u8>,
+    prefix: [u8; STREAM_PREFIX_LENGTH],
+    counter: u32,
+    finished: bool,
+}
+
+impl StreamCipher {
+    // 发送端：生成随机 salt 和前缀，返回需要先发给对端的流头部 (Sender: returns the stream header to send first)
+    pub(crate) fn new_sender(suite: CipherSuite, key: &SessionKey, aad: &[u8]) -> (StreamCipher, Vec<u8>) {
+        let salt = generate_salt();
+        let mut prefix = [0u8; STREAM_PREFIX_LENGTH];
+        rand_core::OsRng.fill_bytes(&mut prefix);
+        let mut header = salt.clone();
+        header.extend_from_slice(&prefix);
+        (StreamCipher::with_cipher(suite.derive(key, &salt), prefix, aad), header)
+    }
+    // 接收端：根据对端发来的流头部初始化 (Receiver: initialise from the peer's stream header)
+    pub(crate) fn new_receiver(
+        suite: CipherSuite,
+        key: &SessionKey,
+        header: &[u8; STREAM_HEADER_LENGTH],
+        aad: &[u8],
+    ) -> StreamCipher {
+        let (salt, prefix) = header.split_at(SALT_LENGTH);
+        StreamCipher::with_cipher(suite.derive(key, salt), prefix.try_into().unwrap(), aad)
+    }
+    fn with_cipher(cipher: Box<dyn Cipher>, prefix: [u8; STREAM_PREFIX_LENGTH], aad: &[u8]) -> StreamCipher {
+        StreamCipher {
+            cipher,
+            aad: aad.to_vec(),
+            prefix,
+            counter: 0,
+            finished: false,
+        }
+    }
+    fn next_nonce(&mut self, last: bool) -> io::Result<[u8; NONCE_LENGTH]> {
+        if self.finished {
+            return Err(io::Error::new(ErrorKind::InvalidData, "数据流已结束"));
+        }
+        let mut nonce = [0u8; NONCE_LENGTH];
+        nonce[..STREAM_PREFIX_LENGTH].copy_from_slice(&self.prefix);
+        nonce[STREAM_PREFIX_LENGTH..NONCE_LENGTH - 1].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[NONCE_LENGTH - 1] = last as u8;
+        self.counter = self.counter.checked_add(1)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "数据块数量超出上限"))?;
+        self.finished = last;
+        Ok(nonce)
+    }
+    // 加密一个数据块，`last` 标记最后一块 (Encrypt one chunk, `last` marks the final chunk)
+    pub(crate) fn seal(&mut self, chunk: &[u8], last: bool) -> io::Result<Vec<u8>> {
+        let nonce = self.next_nonce(last)?;
+        self.cipher.seal(&nonce, chunk, &self.aad)
+    }
+    // 解密一个数据块 (Decrypt one chunk)
+    pub(crate) fn open(&mut self, ciphertext: &[u8], last: bool) -> io::Result<Vec<u8>> {
+        let nonce = self.next_nonce(last)?;
+        self.cipher.open(&nonce, ciphertext, &self.aad)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "数据块解密失败：密钥错误或数据损坏"))
+    }
 }
 
 
@@ -174,3 +465,99 @@ pub(crate) fn calculate_sha256(file_path: &str) -> io::Result<String> {
     let result = hasher.finalize();
     Ok(format!("{:x}", result))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SUITES: [CipherSuite; 3] = [CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305, CipherSuite::None];
+
+    fn key(byte: u8) -> SessionKey {
+        SessionKey::from_bytes(&[byte; KEY_LENGTH]).unwrap()
+    }
+
+    // 加密两块数据，返回流头部和密文 (Seal two chunks; returns the stream header and ciphertexts)
+    fn seal_stream(suite: CipherSuite, key: &SessionKey, aad: &[u8]) -> (Vec<u8>, Vec<Vec<u8>>) {
+        let (mut sealer, header) = StreamCipher::new_sender(suite, key, aad);
+        let chunks = vec![sealer.seal(b"first chunk", false).unwrap(), sealer.seal(b"last chunk", true).unwrap()];
+        (header, chunks)
+    }
+
+    fn open_stream(suite: CipherSuite, key: &SessionKey, aad: &[u8], header: &[u8], chunks: &[Vec<u8>]) -> io::Result<Vec<Vec<u8>>> {
+        let mut opener = StreamCipher::new_receiver(suite, key, header.try_into().unwrap(), aad);
+        Ok(vec![opener.open(&chunks[0], false)?, opener.open(&chunks[1], true)?])
+    }
+
+    #[test]
+    fn every_suite_round_trips() {
+        for suite in SUITES {
+            let (header, chunks) = seal_stream(suite, &key(1), b"aad");
+            assert_eq!(header.len(), STREAM_HEADER_LENGTH);
+            let plain = open_stream(suite, &key(1), b"aad", &header, &chunks).unwrap();
+            assert_eq!(plain, [b"first chunk".to_vec(), b"last chunk".to_vec()], "{}", suite);
+            if suite != CipherSuite::None {
+                assert_ne!(chunks[0][..11], b"first chunk"[..], "{}", suite);
+            }
+        }
+    }
+
+    #[test]
+    fn wrong_key_or_aad_is_rejected() {
+        // 不加密时没有认证，不在此列 (No authentication without encryption, so None is left out)
+        for suite in [CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305] {
+            let (header, chunks) = seal_stream(suite, &key(1), b"aad");
+            assert!(open_stream(suite, &key(2), b"aad", &header, &chunks).is_err(), "{}", suite);
+            assert!(open_stream(suite, &key(1), b"other", &header, &chunks).is_err(), "{}", suite);
+            // 用另一算法解密同样失败 (Opening with the other suite fails as well)
+            let other = if suite == CipherSuite::Aes256Gcm { CipherSuite::ChaCha20Poly1305 } else { CipherSuite::Aes256Gcm };
+            assert!(open_stream(other, &key(1), b"aad", &header, &chunks).is_err(), "{}", suite);
+        }
+    }
+
+    #[test]
+    fn protected_key_needs_the_right_secret() {
+        let (salt, nonce, ct) = encrypt_data(&key(1), b"file key");
+        assert_eq!(decrypt_data(&key(1), &salt, &nonce, &ct).unwrap(), b"file key");
+        assert!(decrypt_data(&key(2), &salt, &nonce, &ct).is_err());
+
+        let secret = StaticSecret::random_from_rng(rand_core::OsRng);
+        let (ephemeral, nonce, ct) = encrypt_data_to(&PublicKey::from(&secret), b"file key");
+        assert_eq!(decrypt_data_with(&secret, &ephemeral, &nonce, &ct).unwrap(), b"file key");
+        let other = StaticSecret::random_from_rng(rand_core::OsRng);
+        assert!(decrypt_data_with(&other, &ephemeral, &nonce, &ct).is_err());
+    }
+
+    #[test]
+    fn argon2id_matches_reference_vector() {
+        // Argon2 参考实现的 Argon2id v1.3 测试向量：t=2、m=64 MiB、p=1
+        // (Argon2id v1.3 vector from the reference implementation: t=2, m=64 MiB, p=1)
+        let kdf = Kdf::Argon2id { memory_kib: 64 * 1024, iterations: 2, parallelism: 1 };
+        let key = kdf.derive("password", b"somesalt").unwrap();
+        assert_eq!(hex::encode(key), "09316115d5cf24ed5a15a31a3ba326e5cf32edc24702987c02b6566f61913cf7");
+    }
+
+    #[test]
+    fn passphrase_key_depends_on_parameters() {
+        let kdf = Kdf::Argon2id { memory_kib: 64, iterations: 1, parallelism: 1 };
+        let key = kdf.derive("pw", b"salt-salt-salt-1").unwrap();
+        assert_eq!(key, kdf.derive("pw", b"salt-salt-salt-1").unwrap());
+        assert_ne!(key, kdf.derive("pw", b"salt-salt-salt-2").unwrap());
+        assert_ne!(key, kdf.derive("pv", b"salt-salt-salt-1").unwrap());
+        let slower = Kdf::Argon2id { memory_kib: 64, iterations: 2, parallelism: 1 };
+        assert_ne!(key, slower.derive("pw", b"salt-salt-salt-1").unwrap());
+
+        let (salt, nonce, ct) = encrypt_data_kdf(&kdf, "pw", b"file key").unwrap();
+        assert_eq!(decrypt_data_kdf(&kdf, "pw", &salt, &nonce, &ct).unwrap(), b"file key");
+        assert!(decrypt_data_kdf(&kdf, "pv", &salt, &nonce, &ct).is_err());
+        assert!(decrypt_data_kdf(&slower, "pw", &salt, &nonce, &ct).is_err());
+    }
+
+    #[test]
+    fn peer_kdf_limits_are_stricter_than_local() {
+        assert!(Kdf::default().validate_peer().is_ok());
+        let large = Kdf::Argon2id { memory_kib: 1024 * 1024, iterations: 2, parallelism: 1 };
+        assert!(large.validate().is_ok());
+        assert!(large.validate_peer().is_err());
+        assert!(Kdf::Argon2id { memory_kib: 64 * 1024, iterations: 9, parallelism: 1 }.validate_peer().is_err());
+    }
+}
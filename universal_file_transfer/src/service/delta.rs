@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// 最小块大小
+const MIN_BLOCK_SIZE: usize = 2048;
+
+/// 指令标记：复用接收端已有的块
+const OP_COPY: u8 = b'C';
+/// 指令标记：随后是一段新数据
+const OP_LITERAL: u8 = b'L';
+
+/// 单个块的签名：弱校验（滚动校验和）+ 强校验（SHA256）
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct BlockSignature {
+    pub(crate) weak: u32,
+    pub(crate) strong: String,
+}
+
+/// 接收端现有文件的块签名表
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Signatures {
+    pub(crate) block_size: usize,
+    pub(crate) blocks: Vec<BlockSignature>,
+}
+
+/// 生成增量数据时的统计信息
+#[derive(Debug, Default)]
+pub(crate) struct DeltaStats {
+    pub(crate) copied_blocks: usize,
+    pub(crate) literal_bytes: usize,
+}
+
+/// 按文件大小选择块大小：约为长度的平方根，块数与块大小保持平衡
+fn block_size_for(len: usize) -> usize {
+    ((len as f64).sqrt() as usize).next_power_of_two().max(MIN_BLOCK_SIZE)
+}
+
+/// rsync 风格的滚动校验和
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(block: &[u8]) -> Rolling {
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        let len = block.len() as u32;
+        for (i, &x) in block.iter().enumerate() {
+            a = a.wrapping_add(x as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(x as u32));
+        }
+        Rolling { a, b, len }
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | ((self.b & 0xffff) << 16)
+    }
+
+    /// 窗口右移一个字节：移出 `out`，移入 `inp`
+    fn roll(&mut self, out: u8, inp: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(inp as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+}
+
+fn strong_hash(block: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(block))
+}
+
+/// 接收端：为现有文件的每个完整块计算签名
+pub(crate) fn signatures(base: &[u8]) -> Signatures {
+    let block_size = block_size_for(base.len());
+    let blocks = base.chunks_exact(block_size)
+        .map(|block| BlockSignature {
+            weak: Rolling::new(block).digest(),
+            strong: strong_hash(block),
+        })
+        .collect();
+    Signatures { block_size, blocks }
+}
+
+fn push_literal(out: &mut Vec<u8>, literal: &[u8], stats: &mut DeltaStats) {
+    if literal.is_empty() {
+        return;
+    }
+    out.push(OP_LITERAL);
+    out.extend_from_slice(&(literal.len() as u32).to_be_bytes());
+    out.extend_from_slice(literal);
+    stats.literal_bytes += literal.len();
+}
+
+/// 发送端：对照接收端签名，把新文件编码为 "复用块 / 新数据" 指令序列
+pub(crate) fn diff(sigs: &Signatures, data: &[u8]) -> (Vec<u8>, DeltaStats) {
+    let mut out = Vec::new();
+    let mut stats = DeltaStats::default();
+    let bs = sigs.block_size;
+
+    let mut index: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, sig) in sigs.blocks.iter().enumerate() {
+        index.entry(sig.weak).or_default().push(i);
+    }
+    if index.is_empty() || data.len() < bs {
+        push_literal(&mut out, data, &mut stats);
+        return (out, stats);
+    }
+
+    let mut literal_start = 0;
+    let mut pos = 0;
+    let mut rolling = Rolling::new(&data[..bs]);
+    while pos + bs <= data.len() {
+        let window = &data[pos..pos + bs];
+        let matched = index.get(&rolling.digest()).and_then(|candidates| {
+            let strong = strong_hash(window);
+            candidates.iter().copied().find(|&i| sigs.blocks[i].strong == strong)
+        });
+
+        match matched {
+            Some(block) => {
+                push_literal(&mut out, &data[literal_start..pos], &mut stats);
+                out.push(OP_COPY);
+                out.extend_from_slice(&(block as u32).to_be_bytes());
+                stats.copied_blocks += 1;
+                pos += bs;
+                literal_start = pos;
+                if pos + bs <= data.len() {
+                    rolling = Rolling::new(&data[pos..pos + bs]);
+                }
+            }
+            None => {
+                if pos + bs < data.len() {
+                    rolling.roll(data[pos], data[pos + bs]);
+                }
+                pos += 1;
+            }
+        }
+    }
+    push_literal(&mut out, &data[literal_start..], &mut stats);
+    (out, stats)
+}
+
+/// 接收端：根据现有文件和指令序列重建新文件
+pub(crate) fn apply(base: &[u8], block_size: usize, delta: &[u8]) -> io::Result<Vec<u8>> {
+    let invalid = |msg: &str| io::Error::new(ErrorKind::InvalidData, msg.to_string());
+    let read_u32 = |at: usize| -> io::Result<usize> {
+        delta.get(at..at + 4)
+            .map(|b| u32::from_be_bytes(b.try_into().unwrap()) as usize)
+            .ok_or_else(|| invalid("增量数据被截断"))
+    };
+
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < delta.len() {
+        let op = delta[pos];
+        let arg = read_u32(pos + 1)?;
+        pos += 5;
+        match op {
+            OP_COPY => {
+                let start = arg * block_size;
+                let block = base.get(start..start + block_size)
+                    .ok_or_else(|| invalid("增量数据引用了不存在的块"))?;
+                out.extend_from_slice(block);
+            }
+            OP_LITERAL => {
+                let literal = delta.get(pos..pos + arg)
+                    .ok_or_else(|| invalid("增量数据被截断"))?;
+                out.extend_from_slice(literal);
+                pos += arg;
+            }
+            _ => return Err(invalid("未知的增量指令")),
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 确定性的伪随机数据，避免内容重复导致块意外匹配
+    fn data(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(2654435761).wrapping_add(1);
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn round_trip(base: &[u8], new: &[u8]) -> DeltaStats {
+        let sigs = signatures(base);
+        let (delta, stats) = diff(&sigs, new);
+        assert_eq!(apply(base, sigs.block_size, &delta).unwrap(), new);
+        stats
+    }
+
+    #[test]
+    fn unchanged_file_copies_every_block() {
+        let base = data(MIN_BLOCK_SIZE * 8, 1);
+        let stats = round_trip(&base, &base);
+        assert_eq!(stats.copied_blocks, 8);
+        assert_eq!(stats.literal_bytes, 0);
+    }
+
+    #[test]
+    fn insertion_reuses_surrounding_blocks() {
+        let base = data(MIN_BLOCK_SIZE * 8, 2);
+        let mut new = base[..MIN_BLOCK_SIZE * 3 + 100].to_vec();
+        new.extend_from_slice(b"inserted bytes");
+        new.extend_from_slice(&base[MIN_BLOCK_SIZE * 3 + 100..]);
+        let stats = round_trip(&base, &new);
+        assert_eq!(stats.copied_blocks, 7);
+    }
+
+    #[test]
+    fn deletion_reuses_remaining_blocks() {
+        let base = data(MIN_BLOCK_SIZE * 8, 3);
+        let mut new = base[..MIN_BLOCK_SIZE * 2].to_vec();
+        new.extend_from_slice(&base[MIN_BLOCK_SIZE * 2 + 500..]);
+        let stats = round_trip(&base, &new);
+        // 被删去一部分的第 2 块无法复用，其余块都能找到
+        assert_eq!(stats.copied_blocks, 7);
+    }
+
+    #[test]
+    fn empty_base_sends_everything_as_literal() {
+        let new = data(MIN_BLOCK_SIZE * 3, 4);
+        let stats = round_trip(&[], &new);
+        assert_eq!(stats.copied_blocks, 0);
+        assert_eq!(stats.literal_bytes, new.len());
+        round_trip(&[], &[]);
+    }
+
+    #[test]
+    fn tail_shorter_than_block_is_literal() {
+        let base = data(MIN_BLOCK_SIZE * 4 + 300, 5);
+        let stats = round_trip(&base, &base);
+        assert_eq!(stats.copied_blocks, 4);
+        assert_eq!(stats.literal_bytes, 300);
+
+        // 新文件整体短于一个块
+        let short = &base[..MIN_BLOCK_SIZE / 2];
+        let stats = round_trip(&base, short);
+        assert_eq!(stats.literal_bytes, short.len());
+    }
+
+    #[test]
+    fn rejects_malformed_delta() {
+        let base = data(MIN_BLOCK_SIZE, 6);
+        assert!(apply(&base, MIN_BLOCK_SIZE, &[OP_COPY, 0, 0, 0, 1]).is_err());
+        assert!(apply(&base, MIN_BLOCK_SIZE, &[OP_LITERAL, 0, 0, 0, 9, 1]).is_err());
+        assert!(apply(&base, MIN_BLOCK_SIZE, &[OP_COPY, 0]).is_err());
+        assert!(apply(&base, MIN_BLOCK_SIZE, &[b'X', 0, 0, 0, 0]).is_err());
+    }
+}
@@ -0,0 +1,52 @@
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use super::output::say;
+use tracing::warn;
+
+/// 局域网发现使用的 UDP 端口
+pub(crate) const DISCOVERY_PORT: u16 = 9797;
+
+/// 广播报文前缀
+const BEACON_PREFIX: &str = "UFT-DISCOVER";
+
+/// 广播间隔
+const BEACON_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 发送端：周期性在局域网内广播 "通道 ID + TCP 端口"，直到任务被取消
+pub(crate) async fn announce(channel: &str, tcp_port: u16) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    let beacon = format!("{} {} {}", BEACON_PREFIX, channel, tcp_port);
+    loop {
+        // 同时发往本机，便于在同一台机器上收发
+        for target in [Ipv4Addr::BROADCAST, Ipv4Addr::LOCALHOST] {
+            if let Err(e) = socket.send_to(beacon.as_bytes(), (target, DISCOVERY_PORT)).await {
+                warn!("局域网广播失败: {}", e);
+            }
+        }
+        tokio::time::sleep(BEACON_INTERVAL).await;
+    }
+}
+
+/// 接收端：监听局域网广播，返回通道 ID 匹配的发送端 TCP 地址
+pub(crate) async fn find(channel: &str, timeout: Duration) -> anyhow::Result<SocketAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT)).await?;
+    say!("正在局域网内查找发送端...");
+    let search = async {
+        let mut buf = [0u8; 256];
+        loop {
+            let (n, from) = socket.recv_from(&mut buf).await?;
+            let text = String::from_utf8_lossy(&buf[..n]);
+            let mut parts = text.split_whitespace();
+            if parts.next() != Some(BEACON_PREFIX) || parts.next() != Some(channel) {
+                continue;
+            }
+            if let Some(port) = parts.next().and_then(|p| p.parse::<u16>().ok()) {
+                return anyhow::Ok(SocketAddr::new(from.ip(), port));
+            }
+        }
+    };
+    tokio::time::timeout(timeout, search).await
+        .map_err(|_| anyhow::anyhow!("在 {} 秒内未发现发送端", timeout.as_secs()))?
+}
@@ -0,0 +1,306 @@
+use std::io::{self, Read};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+
+/// 分块校验时每块的明文大小
+pub(crate) const VERIFY_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// 完整性校验使用的摘要算法，在握手时协商
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum HashAlgorithm {
+    /// 较快，作为默认偏好
+    Blake3,
+    /// 发送端未声明摘要算法时按它处理
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+impl HashAlgorithm {
+    /// 本端的默认偏好：BLAKE3 优先，需要合规时可指定 SHA-256
+    pub(crate) const PREFERRED: [HashAlgorithm; 3] = [HashAlgorithm::Blake3, HashAlgorithm::Sha256, HashAlgorithm::Sha512];
+
+    /// 十六进制摘要的长度
+    pub(crate) fn hex_len(self) -> usize {
+        match self {
+            HashAlgorithm::Blake3 | HashAlgorithm::Sha256 => 64,
+            HashAlgorithm::Sha512 => 128,
+        }
+    }
+
+    pub(crate) fn hasher(self) -> Hasher {
+        match self {
+            HashAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+        }
+    }
+}
+
+impl std::fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            HashAlgorithm::Blake3 => "BLAKE3",
+            HashAlgorithm::Sha256 => "SHA256",
+            HashAlgorithm::Sha512 => "SHA512",
+        })
+    }
+}
+
+/// 增量计算摘要
+pub(crate) enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Blake3(h) => { h.update(data); }
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha512(h) => h.update(data),
+        }
+    }
+
+    /// 结束计算，返回摘要
+    pub(crate) fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Blake3(h) => h.finalize().as_bytes().to_vec(),
+            Hasher::Sha256(h) => h.finalize().to_vec(),
+            Hasher::Sha512(h) => h.finalize().to_vec(),
+        }
+    }
+
+    /// 结束计算，返回十六进制摘要
+    pub(crate) fn finalize_hex(self) -> String {
+        hex::encode(self.finalize())
+    }
+}
+
+/// 计算内存数据的摘要
+pub(crate) fn hash_hex(algo: HashAlgorithm, data: &[u8]) -> String {
+    let mut hasher = algo.hasher();
+    hasher.update(data);
+    hasher.finalize_hex()
+}
+
+/// 流式计算文件的摘要
+pub(crate) fn hash_file(algo: HashAlgorithm, path: &Path) -> io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = algo.hasher();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hasher.finalize_hex())
+}
+
+/// 按 [`VERIFY_CHUNK_SIZE`] 分块读取数据，一次读取同时得到整体摘要和 Merkle 树
+pub(crate) fn hash_chunks<R: Read>(algo: HashAlgorithm, mut reader: R) -> io::Result<(String, MerkleTree)> {
+    let mut hasher = algo.hasher();
+    let mut leaves = Vec::new();
+    let mut buffer = vec![0u8; VERIFY_CHUNK_SIZE];
+    loop {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match reader.read(&mut buffer[filled..])? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        if filled > 0 {
+            hasher.update(&buffer[..filled]);
+            leaves.push(leaf_hash(algo, &buffer[..filled]));
+        }
+        if filled < buffer.len() {
+            break;
+        }
+    }
+    // 空数据视为一个空块
+    if leaves.is_empty() {
+        leaves.push(leaf_hash(algo, &[]));
+    }
+    Ok((hasher.finalize_hex(), MerkleTree::from_leaf_bytes(algo, leaves)))
+}
+
+/// Merkle 树叶子节点的域分隔前缀
+const MERKLE_LEAF: u8 = 0;
+/// Merkle 树内部节点的域分隔前缀
+const MERKLE_NODE: u8 = 1;
+
+/// 按 `VERIFY_CHUNK_SIZE` 固定分块的 Merkle 树。
+/// 叶子为 `H(0x00 || 块)`，内部节点为 `H(0x01 || 左 || 右)`，落单的节点直接升到上一层；空数据视为一个空块。
+/// 知道根即可逐块校验：完整的叶子列表可整体对照根，单个块则用 [`MerkleTree::proof`] 给出的路径校验
+pub(crate) struct MerkleTree {
+    algo: HashAlgorithm,
+    /// 各层节点，`levels[0]` 为叶子，最后一层只有根
+    levels: Vec<Vec<Vec<u8>>>,
+}
+
+impl MerkleTree {
+    /// 由十六进制的叶子摘要建树，格式不符时返回 `None`
+    pub(crate) fn from_leaves(algo: HashAlgorithm, leaves: &[String]) -> Option<MerkleTree> {
+        let leaves = leaves.iter()
+            .map(|leaf| hex::decode(leaf).ok().filter(|b| b.len() * 2 == algo.hex_len()))
+            .collect::<Option<Vec<_>>>()?;
+        (!leaves.is_empty()).then(|| MerkleTree::from_leaf_bytes(algo, leaves))
+    }
+
+    fn from_leaf_bytes(algo: HashAlgorithm, leaves: Vec<Vec<u8>>) -> MerkleTree {
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let next = levels.last().unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(algo, left, right),
+                    [single] => single.clone(),
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        MerkleTree { algo, levels }
+    }
+
+    /// 根摘要（十六进制）
+    pub(crate) fn root(&self) -> String {
+        hex::encode(&self.levels.last().unwrap()[0])
+    }
+
+    pub(crate) fn leaf_count(&self) -> u64 {
+        self.levels[0].len() as u64
+    }
+
+    /// 第 `index` 块是否与对应的叶子一致
+    pub(crate) fn verify_chunk(&self, index: u64, chunk: &[u8]) -> bool {
+        self.levels[0].get(index as usize).is_some_and(|leaf| *leaf == leaf_hash(self.algo, chunk))
+    }
+
+    /// 叶子摘要（十六进制）
+    pub(crate) fn leaves(&self) -> Vec<String> {
+        self.levels[0].iter().map(hex::encode).collect()
+    }
+
+    /// 第 `index` 块到根的路径：自下而上的兄弟节点，落单时该层为空字符串
+    pub(crate) fn proof(&self, index: u64) -> Vec<String> {
+        let mut index = index as usize;
+        let mut path = Vec::new();
+        for level in &self.levels[..self.levels.len() - 1] {
+            path.push(level.get(index ^ 1).map(hex::encode).unwrap_or_default());
+            index /= 2;
+        }
+        path
+    }
+
+    /// 找出与本树的叶子不符的块序号，用于判断部分接收的文件中哪些块已经可信；
+    /// 长度不符时多出或缺少的块都视为损坏
+    pub(crate) fn corrupt_chunks(&self, data: &[u8]) -> Vec<u64> {
+        let leaves = &self.levels[0];
+        let actual: Vec<&[u8]> = data.chunks(VERIFY_CHUNK_SIZE).collect();
+        (0..leaves.len().max(actual.len()))
+            .filter(|&i| match (leaves.get(i), actual.get(i)) {
+                (Some(leaf), Some(chunk)) => *leaf != leaf_hash(self.algo, chunk),
+                // 空文件只有一个空块
+                (Some(leaf), None) => !(i == 0 && *leaf == leaf_hash(self.algo, &[])),
+                _ => true,
+            })
+            .map(|i| i as u64)
+            .collect()
+    }
+}
+
+/// 只凭根校验单个块：按路径自下而上重算，`leaf_count` 为树的叶子总数
+pub(crate) fn verify_proof(algo: HashAlgorithm, root: &str, leaf_count: u64, index: u64, chunk: &[u8], proof: &[String]) -> bool {
+    if index >= leaf_count {
+        return false;
+    }
+    let mut hash = leaf_hash(algo, chunk);
+    let (mut index, mut width) = (index, leaf_count);
+    for sibling in proof {
+        let has_sibling = index ^ 1 < width;
+        match (has_sibling, hex::decode(sibling)) {
+            (true, Ok(sibling)) if index % 2 == 0 => hash = node_hash(algo, &hash, &sibling),
+            (true, Ok(sibling)) => hash = node_hash(algo, &sibling, &hash),
+            (false, _) if sibling.is_empty() => {}
+            _ => return false,
+        }
+        index /= 2;
+        width = width.div_ceil(2);
+    }
+    width == 1 && hex::encode(hash) == root.to_lowercase()
+}
+
+fn leaf_hash(algo: HashAlgorithm, chunk: &[u8]) -> Vec<u8> {
+    let mut hasher = algo.hasher();
+    hasher.update(&[MERKLE_LEAF]);
+    hasher.update(chunk);
+    hasher.finalize()
+}
+
+fn node_hash(algo: HashAlgorithm, left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut hasher = algo.hasher();
+    hasher.update(&[MERKLE_NODE]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proofs_verify_every_leaf_for_odd_and_even_counts() {
+        for algo in HashAlgorithm::PREFERRED {
+            for count in 1..=7u64 {
+                let chunks: Vec<Vec<u8>> = (0..count).map(|i| format!("chunk-{}", i).into_bytes()).collect();
+                let tree = MerkleTree::from_leaf_bytes(algo, chunks.iter().map(|c| leaf_hash(algo, c)).collect());
+                let root = tree.root();
+                assert_eq!(MerkleTree::from_leaves(algo, &tree.leaves()).unwrap().root(), root);
+                for (i, chunk) in chunks.iter().enumerate() {
+                    let i = i as u64;
+                    let proof = tree.proof(i);
+                    assert!(verify_proof(algo, &root, count, i, chunk, &proof), "{} leaves, index {}", count, i);
+                    assert!(!verify_proof(algo, &root, count, i, b"tampered", &proof));
+                    assert!(!verify_proof(algo, &root, count, count, chunk, &proof));
+                    if count > 1 {
+                        assert!(!verify_proof(algo, &root, count, (i + 1) % count, chunk, &proof));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hash_chunks_matches_whole_digest_and_leaves() {
+        // 2.5 块：叶子数为奇数，最后一块不满
+        let mut data: Vec<u8> = (0..VERIFY_CHUNK_SIZE * 5 / 2).map(|i| (i % 251) as u8).collect();
+        let (digest, tree) = hash_chunks(HashAlgorithm::Blake3, &data[..]).unwrap();
+        assert_eq!(digest, hash_hex(HashAlgorithm::Blake3, &data));
+        assert_eq!(tree.leaf_count(), 3);
+        for (i, chunk) in data.chunks(VERIFY_CHUNK_SIZE).enumerate() {
+            assert!(tree.verify_chunk(i as u64, chunk));
+        }
+        assert!(tree.corrupt_chunks(&data).is_empty());
+
+        data[VERIFY_CHUNK_SIZE + 1] ^= 1;
+        assert_eq!(tree.corrupt_chunks(&data), vec![1]);
+        assert!(!tree.verify_chunk(1, &data[VERIFY_CHUNK_SIZE..2 * VERIFY_CHUNK_SIZE]));
+    }
+
+    #[test]
+    fn empty_data_is_one_empty_chunk() {
+        let (digest, tree) = hash_chunks(HashAlgorithm::Sha256, &[][..]).unwrap();
+        assert_eq!(digest, hash_hex(HashAlgorithm::Sha256, &[]));
+        assert_eq!(tree.leaf_count(), 1);
+        assert!(verify_proof(HashAlgorithm::Sha256, &tree.root(), 1, 0, &[], &tree.proof(0)));
+        assert!(tree.corrupt_chunks(&[]).is_empty());
+    }
+}
@@ -0,0 +1,392 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+use fs2::FileExt;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand_core::{OsRng, RngCore};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use serde_json::json;
+use super::cryptography::SessionKey;
+use super::output::{self, say};
+
+/// 本机身份私钥文件名（位于数据目录下）
+const IDENTITY_FILE_NAME: &str = "identity.ed25519";
+/// 本机接收私钥文件名（X25519，用于 `--to` 加密），位于数据目录下
+const RECIPIENT_FILE_NAME: &str = "identity.x25519";
+/// 已信任对端公钥文件名（位于数据目录下）
+const KNOWN_PEERS_FILE_NAME: &str = "known_peers";
+/// 握手签名的域分隔前缀，避免签名被挪作他用
+const HANDSHAKE_CONTEXT: &[u8] = b"uft-handshake-v2";
+/// 由握手密钥交换派生共享密钥的 HKDF 域分隔信息
+const HANDSHAKE_KEY_INFO: &[u8] = b"uft-handshake-key-v1";
+
+/// 握手签名中的角色标记
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Role {
+    Sender,
+    Receiver,
+}
+
+fn known_peers_path() -> PathBuf {
+    super::data_dir().join(KNOWN_PEERS_FILE_NAME)
+}
+
+/// 读取数据目录下的 32 字节私钥种子，文件不存在时生成并保存；第二项表示是否为新生成
+fn load_or_create_seed(file_name: &str) -> io::Result<([u8; 32], bool)> {
+    let path = super::data_dir().join(file_name);
+    match std::fs::read_to_string(&path) {
+        Ok(text) => {
+            let seed: [u8; 32] = hex::decode(text.trim()).ok()
+                .and_then(|b| b.try_into().ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("密钥文件 {} 已损坏", path.display())))?;
+            Ok((seed, false))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let mut seed = [0u8; 32];
+            OsRng.fill_bytes(&mut seed);
+            std::fs::create_dir_all(super::data_dir())?;
+            write_private(&path, hex::encode(seed).as_bytes())?;
+            Ok((seed, true))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// 读取本机长期身份密钥，首次使用时生成并保存
+pub(crate) fn load_or_create() -> io::Result<SigningKey> {
+    let (seed, created) = load_or_create_seed(IDENTITY_FILE_NAME)?;
+    let key = SigningKey::from_bytes(&seed);
+    if created {
+        eprintln!("已生成本机身份密钥，指纹 {}", fingerprint(&key.verifying_key()));
+    }
+    Ok(key)
+}
+
+/// 读取本机接收私钥，首次使用时生成并保存
+pub(crate) fn recipient_secret() -> io::Result<StaticSecret> {
+    let (seed, created) = load_or_create_seed(RECIPIENT_FILE_NAME)?;
+    let secret = StaticSecret::from(seed);
+    if created {
+        eprintln!("已生成本机接收密钥，公钥 {}", encode_recipient(&PublicKey::from(&secret)));
+    }
+    Ok(secret)
+}
+
+/// 接收公钥的十六进制表示，发送端通过 `--to` 指定
+pub(crate) fn encode_recipient(key: &PublicKey) -> String {
+    hex::encode(key.as_bytes())
+}
+
+pub(crate) fn decode_recipient(hex_key: &str) -> anyhow::Result<PublicKey> {
+    let bytes: [u8; 32] = hex::decode(hex_key).ok()
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| anyhow::anyhow!("无效的接收公钥: {}", hex_key))?;
+    Ok(PublicKey::from(bytes))
+}
+
+/// 写入仅当前用户可读的文件
+fn write_private(path: &std::path::Path, data: &[u8]) -> io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    io::Write::write_all(&mut options.open(path)?, data)
+}
+
+/// 公钥的十六进制表示，用于握手和 known_peers 文件
+pub(crate) fn encode_key(key: &VerifyingKey) -> String {
+    hex::encode(key.as_bytes())
+}
+
+pub(crate) fn decode_key(hex_key: &str) -> anyhow::Result<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(hex_key).ok()
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| anyhow::anyhow!("无效的公钥: {}", hex_key))?;
+    Ok(VerifyingKey::from_bytes(&bytes)?)
+}
+
+/// 便于人工比对的公钥指纹：SHA256 前 16 字节，每 2 字节以 `:` 分隔
+pub(crate) fn fingerprint(key: &VerifyingKey) -> String {
+    let digest = hex::encode(&Sha256::digest(key.as_bytes())[..16]);
+    digest.as_bytes()
+        .chunks(4)
+        .map(|c| std::str::from_utf8(c).unwrap())
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// 握手随机数（十六进制）
+pub(crate) fn new_nonce() -> String {
+    let mut nonce = [0u8; 32];
+    OsRng.fill_bytes(&mut nonce);
+    hex::encode(nonce)
+}
+
+/// 握手记录摘要：双方握手帧的原始字节按收发顺序带长度前缀计入，
+/// 协商的参数、随机数和临时公钥都被身份签名和共享密钥绑定，中间人改动任何一帧都会导致校验失败
+pub(crate) fn transcript_hash(client_hello: &[u8], server_hello: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(HANDSHAKE_CONTEXT);
+    for frame in [client_hello, server_hello] {
+        hasher.update((frame.len() as u64).to_be_bytes());
+        hasher.update(frame);
+    }
+    hasher.finalize().into()
+}
+
+/// 被签名的握手内容：前缀 + 角色 + 握手记录摘要 + 签名方公钥
+fn signed_message(role: Role, transcript: &[u8; 32], key: &VerifyingKey) -> Vec<u8> {
+    let mut msg = HANDSHAKE_CONTEXT.to_vec();
+    msg.push(match role {
+        Role::Sender => b'S',
+        Role::Receiver => b'R',
+    });
+    msg.extend_from_slice(transcript);
+    msg.extend_from_slice(key.as_bytes());
+    msg
+}
+
+/// 对本次握手签名，返回十六进制签名
+pub(crate) fn sign_handshake(key: &SigningKey, role: Role, transcript: &[u8; 32]) -> String {
+    let msg = signed_message(role, transcript, &key.verifying_key());
+    hex::encode(key.sign(&msg).to_bytes())
+}
+
+/// 校验对端的握手签名，成功时返回对端公钥
+pub(crate) fn verify_handshake(
+    public_key: &str,
+    signature: &str,
+    role: Role,
+    transcript: &[u8; 32],
+) -> anyhow::Result<VerifyingKey> {
+    let key = decode_key(public_key)?;
+    let sig_bytes: [u8; 64] = hex::decode(signature).ok()
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| anyhow::anyhow!("无效的握手签名"))?;
+    key.verify(&signed_message(role, transcript, &key), &Signature::from_bytes(&sig_bytes))
+        .map_err(|_| anyhow::anyhow!("对端握手签名校验失败"))?;
+    Ok(key)
+}
+
+/// 生成本次握手的临时 X25519 密钥，返回私钥和十六进制公钥
+pub(crate) fn handshake_secret() -> (EphemeralSecret, String) {
+    let secret = EphemeralSecret::random_from_rng(OsRng);
+    let public = encode_recipient(&PublicKey::from(&secret));
+    (secret, public)
+}
+
+/// 由本端临时私钥和对端临时公钥协商共享密钥，以握手记录摘要为 salt 派生，
+/// 只有通过了双方身份签名的这次握手才能得到同一个密钥
+pub(crate) fn handshake_key(secret: EphemeralSecret, peer_public: &str, transcript: &[u8; 32]) -> anyhow::Result<SessionKey> {
+    let shared = secret.diffie_hellman(&decode_recipient(peer_public)?);
+    if !shared.was_contributory() {
+        anyhow::bail!("对端的握手临时公钥无效");
+    }
+    let mut key = zeroize::Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(transcript), shared.as_bytes())
+        .expand(HANDSHAKE_KEY_INFO, key.as_mut())
+        .map_err(|_| anyhow::anyhow!("握手密钥派生失败"))?;
+    Ok(SessionKey::from_bytes(key.as_ref())?)
+}
+
+/// 已信任的对端：对端名称（主机:端口）-> 公钥，格式与 ssh 的 known_hosts 类似，每行 `<对端> ed25519 <公钥>`
+pub(crate) struct KnownPeers {
+    peers: BTreeMap<String, String>,
+}
+
+impl KnownPeers {
+    pub(crate) fn load() -> io::Result<KnownPeers> {
+        let text = match std::fs::read_to_string(known_peers_path()) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let peers = text.lines()
+            .filter(|l| !l.trim().is_empty() && !l.starts_with('#'))
+            .filter_map(|l| {
+                let mut parts = l.split_whitespace();
+                match (parts.next(), parts.next(), parts.next()) {
+                    (Some(peer), Some("ed25519"), Some(key)) => Some((peer.to_string(), key.to_string())),
+                    _ => None,
+                }
+            })
+            .collect();
+        Ok(KnownPeers { peers })
+    }
+
+    /// 在 known_peers 的文件锁内重新读取、修改并保存，避免同时运行的收发进程互相覆盖对方刚写入的记录
+    pub(crate) fn update<T>(f: impl FnOnce(&mut KnownPeers) -> T) -> io::Result<T> {
+        std::fs::create_dir_all(super::data_dir())?;
+        let lock = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(known_peers_path().with_extension("lock"))?;
+        lock.lock_exclusive()?;
+        let mut known = KnownPeers::load()?;
+        let result = f(&mut known);
+        known.save()?;
+        Ok(result)
+    }
+
+    /// 先写入唯一命名的临时文件再改名，读取方不会看到写了一半的文件
+    fn save(&self) -> io::Result<()> {
+        let text: String = self.peers.iter()
+            .map(|(peer, key)| format!("{} ed25519 {}\n", peer, key))
+            .collect();
+        let path = known_peers_path();
+        let tmp = path.with_extension(format!("{}.{:08x}.tmp", std::process::id(), OsRng.next_u32()));
+        std::fs::write(&tmp, text).inspect_err(|_| { std::fs::remove_file(&tmp).ok(); })?;
+        std::fs::rename(&tmp, path)
+    }
+
+    pub(crate) fn get(&self, peer: &str) -> Option<&str> {
+        self.peers.get(peer).map(String::as_str)
+    }
+
+    /// 按公钥查找已记录的对端名称
+    pub(crate) fn find_by_key(&self, key: &str) -> Option<&str> {
+        self.peers.iter().find(|(_, k)| k.as_str() == key).map(|(p, _)| p.as_str())
+    }
+
+    pub(crate) fn insert(&mut self, peer: &str, key: &str) {
+        self.peers.insert(peer.to_string(), key.to_string());
+    }
+
+    pub(crate) fn remove(&mut self, peer: &str) -> bool {
+        self.peers.remove(peer).is_some()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.peers.iter()
+    }
+}
+
+/// 首次信任（TOFU）：首次见到的对端记录其公钥；之后公钥变化时拒绝继续传输
+pub(crate) fn check_peer(peer: &str, key: &VerifyingKey) -> anyhow::Result<()> {
+    let key_hex = encode_key(key);
+    let pinned = match KnownPeers::load()?.get(peer) {
+        Some(pinned) => Some(pinned.to_string()),
+        // 在锁内重新读取后再记录：其他进程可能刚记录了该对端
+        None => KnownPeers::update(|known| match known.get(peer) {
+            Some(pinned) => Some(pinned.to_string()),
+            None => {
+                known.insert(peer, &key_hex);
+                None
+            }
+        })?,
+    };
+    match pinned {
+        Some(pinned) if pinned == key_hex => Ok(()),
+        Some(pinned) => {
+            let pinned_fp = decode_key(&pinned).map(|k| fingerprint(&k)).unwrap_or_else(|_| pinned.clone());
+            eprintln!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+            eprintln!("@  警告：对端身份公钥已改变！");
+            eprintln!("@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@");
+            eprintln!("可能有人正在进行中间人攻击，也可能对端重新安装后生成了新密钥。");
+            eprintln!("对端：{}", peer);
+            eprintln!("已记录的指纹：{}", pinned_fp);
+            eprintln!("本次收到的指纹：{}", fingerprint(key));
+            eprintln!("确认新密钥可信后，可运行：keys trust {} {}", peer, key_hex);
+            anyhow::bail!("对端 {} 的身份公钥与记录不符，已中止传输", peer)
+        }
+        None => {
+            say!("首次连接 {}，已记录其身份指纹 {}", peer, fingerprint(key));
+            Ok(())
+        }
+    }
+}
+
+/// 显示本机身份和已信任的对端
+pub(crate) fn show() -> anyhow::Result<()> {
+    let key = load_or_create()?.verifying_key();
+    say!("本机公钥：{}", encode_key(&key));
+    say!("本机指纹：{}", fingerprint(&key));
+    let recipient = encode_recipient(&PublicKey::from(&recipient_secret()?));
+    say!("接收公钥（供发送端 --to 使用）：{}", recipient);
+    output::event("identity", json!({
+        "public_key": encode_key(&key),
+        "fingerprint": fingerprint(&key),
+        "recipient": recipient,
+    }));
+    let known = KnownPeers::load()?;
+    say!("已信任的对端：");
+    let mut empty = true;
+    for (peer, key_hex) in known.iter() {
+        let fp = decode_key(key_hex).map(|k| fingerprint(&k)).ok();
+        say!("  {}  {}", peer, fp.as_deref().unwrap_or("（无效公钥）"));
+        output::event("peer", json!({ "peer": peer, "public_key": key_hex, "fingerprint": fp }));
+        empty = false;
+    }
+    if empty {
+        say!("  （无）");
+    }
+    Ok(())
+}
+
+/// 手动信任对端公钥，替换已有记录
+pub(crate) fn trust(peer: &str, public_key: &str) -> anyhow::Result<()> {
+    let key = decode_key(public_key)?;
+    KnownPeers::update(|known| known.insert(peer, &encode_key(&key)))?;
+    say!("已信任 {}，指纹 {}", peer, fingerprint(&key));
+    output::event("trusted", json!({ "peer": peer, "fingerprint": fingerprint(&key) }));
+    Ok(())
+}
+
+/// 删除对端记录，下次连接时重新按首次信任处理
+pub(crate) fn revoke(peer: &str) -> anyhow::Result<()> {
+    if !KnownPeers::update(|known| known.remove(peer))? {
+        anyhow::bail!("known_peers 中没有 {}", peer);
+    }
+    say!("已移除 {} 的记录", peer);
+    output::event("revoked", json!({ "peer": peer }));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_binds_transcript_and_role() {
+        let key = SigningKey::from_bytes(&[7u8; 32]);
+        let public = encode_key(&key.verifying_key());
+        let transcript = transcript_hash(b"client hello", b"server hello");
+        let signature = sign_handshake(&key, Role::Receiver, &transcript);
+        assert!(verify_handshake(&public, &signature, Role::Receiver, &transcript).is_ok());
+        assert!(verify_handshake(&public, &signature, Role::Sender, &transcript).is_err());
+        let altered = transcript_hash(b"client hello", b"server hello with other cipher");
+        assert!(verify_handshake(&public, &signature, Role::Receiver, &altered).is_err());
+    }
+
+    #[test]
+    fn transcript_frames_are_length_prefixed() {
+        assert_ne!(transcript_hash(b"ab", b"c"), transcript_hash(b"a", b"bc"));
+    }
+
+    #[test]
+    fn handshake_key_agrees_only_on_same_transcript() {
+        let transcript = transcript_hash(b"client", b"server");
+        let (a, a_public) = handshake_secret();
+        let (b, b_public) = handshake_secret();
+        let ka = handshake_key(a, &b_public, &transcript).unwrap();
+        let kb = handshake_key(b, &a_public, &transcript).unwrap();
+        assert_eq!(ka.as_bytes(), kb.as_bytes());
+
+        // 同一对临时密钥，双方看到的握手记录不同时得到不同的密钥
+        let (c, c_public) = handshake_secret();
+        let (d, d_public) = handshake_secret();
+        let kc = handshake_key(c, &d_public, &transcript).unwrap();
+        let kd = handshake_key(d, &c_public, &transcript_hash(b"client", b"tampered")).unwrap();
+        assert_ne!(kc.as_bytes(), kd.as_bytes());
+    }
+
+    #[test]
+    fn rejects_low_order_ephemeral() {
+        let (secret, _) = handshake_secret();
+        let transcript = transcript_hash(b"client", b"server");
+        assert!(handshake_key(secret, &hex::encode([0u8; 32]), &transcript).is_err());
+    }
+}
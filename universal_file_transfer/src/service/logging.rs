@@ -0,0 +1,122 @@
+use std::path::{Path, PathBuf};
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+/// 日志文件切分后最多保留的文件数
+const MAX_LOG_FILES: usize = 14;
+
+/// 日志格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub(crate) enum LogFormat {
+    /// 每行一条可读的日志
+    #[default]
+    Text,
+    /// 每行一个 JSON 对象，包含所在连接和传输的字段，便于日志系统采集
+    Json,
+}
+
+/// 日志文件的切分周期
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub(crate) enum LogRotation {
+    /// 每小时一个文件
+    Hourly,
+    /// 每天一个文件
+    #[default]
+    Daily,
+    /// 不切分，始终写入同一个文件
+    Never,
+}
+
+impl From<LogRotation> for Rotation {
+    fn from(rotation: LogRotation) -> Rotation {
+        match rotation {
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        }
+    }
+}
+
+/// 日志设置，来自全局的 `-v`/`-q` 和 `--log-*` 参数
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LogOptions {
+    /// `-v` 的个数：1 为 info，2 为 debug，3 及以上为 trace
+    pub(crate) verbose: u8,
+    /// `-q`：只输出错误
+    pub(crate) quiet: bool,
+    pub(crate) format: LogFormat,
+    /// 写入该文件而非标准错误，按 `rotation` 切分，文件名带日期后缀
+    pub(crate) file: Option<PathBuf>,
+    pub(crate) rotation: LogRotation,
+}
+
+impl LogOptions {
+    /// 未设置 `RUST_LOG` 时本程序的日志级别。
+    /// 默认只输出警告和错误；写入日志文件时（常驻接收端）默认记录每个连接和传输
+    fn level(&self) -> &'static str {
+        match self.verbose {
+            _ if self.quiet => "error",
+            0 if self.file.is_some() => "info",
+            0 => "warn",
+            1 => "info",
+            2 => "debug",
+            _ => "trace",
+        }
+    }
+}
+
+/// 初始化全局日志。`RUST_LOG` 优先于 `-v`/`-q`，写法同 `tracing` 的过滤指令，如 `universal_file_transfer=debug`。
+/// 写入日志文件时返回的守卫须保留到程序结束，以便退出前写出缓冲的日志
+pub(crate) fn init(opts: &LogOptions) -> anyhow::Result<Option<WorkerGuard>> {
+    let filter = match std::env::var(EnvFilter::DEFAULT_ENV) {
+        Ok(directives) => EnvFilter::try_new(&directives)
+            .map_err(|e| anyhow::anyhow!("无效的 {}: {}", EnvFilter::DEFAULT_ENV, e))?,
+        Err(_) => EnvFilter::new(format!(
+            "warn,{}={level},transfer_api={level}",
+            env!("CARGO_CRATE_NAME"),
+            level = opts.level(),
+        )),
+    };
+
+    let (writer, guard, ansi) = match &opts.file {
+        Some(file) => {
+            let (dir, name) = split_log_path(file)?;
+            std::fs::create_dir_all(dir)?;
+            let appender = RollingFileAppender::builder()
+                .rotation(opts.rotation.into())
+                .filename_prefix(name)
+                .max_log_files(MAX_LOG_FILES)
+                .build(dir)
+                .map_err(|e| anyhow::anyhow!("无法打开日志文件 {}: {}", file.display(), e))?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (BoxMakeWriter::new(writer), Some(guard), false)
+        }
+        None => {
+            use std::io::IsTerminal;
+            (BoxMakeWriter::new(std::io::stderr), None, std::io::stderr().is_terminal())
+        }
+    };
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(ansi)
+        .with_target(false);
+    match opts.format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(false).with_span_list(true).init(),
+    }
+    Ok(guard)
+}
+
+/// 拆分日志文件路径为所在目录和文件名，切分后的文件为 `<文件名>.<日期>`
+fn split_log_path(file: &Path) -> anyhow::Result<(&Path, String)> {
+    let name = file.file_name()
+        .ok_or_else(|| anyhow::anyhow!("{} 不是有效的日志文件路径", file.display()))?
+        .to_string_lossy()
+        .to_string();
+    let dir = file.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    Ok((dir, name))
+}
@@ -0,0 +1,83 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use transfer_api::health::{self, Readiness};
+use transfer_api::metrics;
+use super::output::{self, say};
+
+/// 在 `port` 上启动 HTTP 接口，提供 `/metrics` 指标和 `/healthz`、`/readyz` 探测，随进程退出。
+/// `readiness` 为 `/readyz` 要检查的内容
+pub(crate) fn serve(port: u16, readiness: Readiness) -> anyhow::Result<()> {
+    health::configure(readiness);
+    transfer_api::init_ws_server(port)
+        .map_err(|e| anyhow::anyhow!("无法在端口 {} 启动 HTTP 接口: {}", port, e))?;
+    say!("HTTP 接口：监听端口 {}（/metrics、/healthz、/readyz）", port);
+    Ok(())
+}
+
+/// 完整性校验失败：解密失败、摘要或分块校验不符。
+/// 用 [`integrity_failure`] 构造，失败的传输据此归为 `integrity` 类
+#[derive(Debug)]
+pub(crate) struct IntegrityError(String);
+
+impl std::fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+/// 计入一次完整性校验失败，并返回对应的错误
+pub(crate) fn integrity_failure(message: impl Into<String>) -> anyhow::Error {
+    metrics::integrity_failure();
+    IntegrityError(message.into()).into()
+}
+
+/// 按传输结果计入成功或失败数，失败时按错误类型区分
+pub(crate) fn record_result(result: &anyhow::Result<()>) {
+    match result {
+        Ok(()) => metrics::transfer_completed(),
+        Err(e) if e.chain().any(|e| e.is::<IntegrityError>()) => metrics::transfer_failed("integrity"),
+        Err(e) => metrics::transfer_failed(output::error_code(e)),
+    }
+}
+
+/// 统计收发字节数的连接包装
+pub(crate) struct Counted<S> {
+    inner: S,
+}
+
+impl<S> Counted<S> {
+    pub(crate) fn new(inner: S) -> Counted<S> {
+        Counted { inner }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        metrics::add_bytes_received((buf.filled().len() - before) as u64);
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            metrics::add_bytes_sent(n as u64);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
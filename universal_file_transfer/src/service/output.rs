@@ -0,0 +1,142 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+use serde::Serialize;
+use serde_json::{Value, json};
+use super::hashing::HashAlgorithm;
+
+/// 命令行输出格式，由全局的 `--output` 指定，进程内只设置一次
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub(crate) enum OutputFormat {
+    /// 人类可读的提示
+    #[default]
+    Text,
+    /// 标准输出每行一个 JSON 对象，供脚本和控制端解析；提示信息改写到标准错误
+    Json,
+}
+
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+/// 标准输出用于写出收到的数据（`recv --stdout`），提示信息改写到标准错误
+static STDOUT_RESERVED: AtomicBool = AtomicBool::new(false);
+/// 本次运行中处理过的文件，随最终结果一起输出
+static FILES: Mutex<Vec<FileRecord>> = Mutex::new(Vec::new());
+
+pub(crate) fn init(format: OutputFormat) {
+    FORMAT.set(format).ok();
+}
+
+pub(crate) fn is_json() -> bool {
+    FORMAT.get() == Some(&OutputFormat::Json)
+}
+
+/// 之后的提示信息都写到标准错误，标准输出只留给数据
+pub(crate) fn reserve_stdout() {
+    STDOUT_RESERVED.store(true, Ordering::Relaxed);
+}
+
+/// 输出一行提示：文本模式写到标准输出，JSON 模式或标准输出用于数据时写到标准错误，保证标准输出只有 JSON 或数据
+macro_rules! say {
+    ($($arg:tt)*) => {
+        $crate::service::output::say_fmt(format_args!($($arg)*))
+    };
+}
+pub(crate) use say;
+
+pub(crate) fn say_fmt(args: std::fmt::Arguments) {
+    if is_json() || STDOUT_RESERVED.load(Ordering::Relaxed) {
+        eprintln!("{}", args);
+    } else {
+        println!("{}", args);
+    }
+}
+
+/// JSON 模式下输出一个事件：`{"event": <名称>, ...字段}`；文本模式下不输出
+pub(crate) fn event(name: &str, fields: Value) {
+    if !is_json() {
+        return;
+    }
+    let mut line = json!({ "event": name });
+    if let (Some(line), Value::Object(fields)) = (line.as_object_mut(), fields) {
+        line.extend(fields);
+    }
+    let mut stdout = std::io::stdout().lock();
+    writeln!(stdout, "{}", line).ok();
+    stdout.flush().ok();
+}
+
+/// 一个已处理的文件：发送、接收、加解密或校验的结果
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct FileRecord {
+    pub(crate) path: String,
+    /// 文件明文大小（字节）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) hash: Option<HashAlgorithm>,
+    /// 文件摘要（十六进制）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) digest: Option<String>,
+}
+
+impl FileRecord {
+    pub(crate) fn new(path: impl Into<String>) -> FileRecord {
+        FileRecord { path: path.into(), size: None, hash: None, digest: None }
+    }
+
+    pub(crate) fn size(mut self, size: impl Into<Option<u64>>) -> FileRecord {
+        self.size = size.into();
+        self
+    }
+
+    pub(crate) fn digest(mut self, hash: HashAlgorithm, digest: &str) -> FileRecord {
+        self.hash = Some(hash);
+        self.digest = Some(digest.to_string());
+        self
+    }
+}
+
+/// 输出文件事件，并记入最终结果
+pub(crate) fn file(name: &str, record: FileRecord) {
+    if !is_json() {
+        return;
+    }
+    event(name, serde_json::to_value(&record).unwrap_or_default());
+    FILES.lock().unwrap().push(record);
+}
+
+/// 命令结束时输出最终结果：成功与否、错误信息和错误码、耗时及处理过的文件
+pub(crate) fn finish(command: &str, started: Instant, result: &anyhow::Result<()>) {
+    let files = std::mem::take(&mut *FILES.lock().unwrap());
+    event("result", json!({
+        "command": command,
+        "ok": result.is_ok(),
+        "error": result.as_ref().err().map(|e| format!("{:#}", e)),
+        "code": result.as_ref().err().map(error_code),
+        "duration_ms": started.elapsed().as_millis() as u64,
+        "files": files,
+    }));
+}
+
+/// 供脚本区分失败原因的错误码：按错误链中的 I/O 错误分类，其余为 `failed`
+pub(crate) fn error_code(err: &anyhow::Error) -> &'static str {
+    use std::io::ErrorKind;
+
+    let Some(io) = err.chain().find_map(|e| e.downcast_ref::<std::io::Error>()) else {
+        return "failed";
+    };
+    match io.kind() {
+        ErrorKind::ConnectionRefused
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::NotConnected
+        | ErrorKind::BrokenPipe
+        | ErrorKind::TimedOut
+        | ErrorKind::UnexpectedEof => "network",
+        ErrorKind::NotFound => "not_found",
+        ErrorKind::PermissionDenied => "permission_denied",
+        ErrorKind::AlreadyExists => "already_exists",
+        ErrorKind::InvalidData => "invalid_data",
+        _ => "io",
+    }
+}
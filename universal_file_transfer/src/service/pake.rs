@@ -0,0 +1,180 @@
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::IsIdentity;
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use tokio::io::{AsyncRead, AsyncWrite};
+use zeroize::Zeroizing;
+use super::cryptography::SessionKey;
+use super::protocol;
+
+/// 由传输码导出生成元的域分隔前缀
+const GENERATOR_CONTEXT: &[u8] = b"uft-cpace-ristretto255-v1";
+/// 由共享点导出会话密钥和确认密钥的域分隔前缀
+const KEY_CONTEXT: &[u8] = b"uft-cpace-isk-v1";
+
+/// 配对中的角色，双方的消息按角色排序后参与密钥导出和确认
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    Sender,
+    Receiver,
+}
+
+impl Role {
+    fn to_byte(self) -> u8 {
+        match self {
+            Role::Sender => b'S',
+            Role::Receiver => b'R',
+        }
+    }
+
+    fn peer(self) -> Role {
+        match self {
+            Role::Sender => Role::Receiver,
+            Role::Receiver => Role::Sender,
+        }
+    }
+}
+
+/// 第一轮消息：本端的公开元素（十六进制）
+#[derive(Debug, Serialize, Deserialize)]
+struct PakeMessage {
+    element: String,
+}
+
+/// 第二轮消息：密钥确认码（十六进制），证明本端算出了相同的密钥
+#[derive(Debug, Serialize, Deserialize)]
+struct PakeConfirm {
+    mac: String,
+}
+
+/// CPace 风格的口令认证密钥交换：生成元由传输码和通道 ID 导出，双方各用随机标量乘以生成元后交换，
+/// 只有知道同一传输码的双方才能算出相同的共享点。旁听者或中继得不到可离线穷举传输码的数据，
+/// 冒充的一方每次连接只能猜一次
+struct Pake {
+    role: Role,
+    secret: Zeroizing<Scalar>,
+    element: [u8; 32],
+}
+
+impl Pake {
+    fn start(code: &str, channel: &str, role: Role) -> Pake {
+        let generator = generator(code, channel);
+        let mut wide = Zeroizing::new([0u8; 64]);
+        OsRng.fill_bytes(wide.as_mut());
+        let secret = Zeroizing::new(Scalar::from_bytes_mod_order_wide(&wide));
+        let element = (generator * *secret).compress().to_bytes();
+        Pake { role, secret, element }
+    }
+
+    /// 由对端元素算出共享点，返回 `(会话密钥, 确认密钥, 双方元素)`
+    fn finish(&self, peer_element: &[u8]) -> anyhow::Result<(SessionKey, Zeroizing<[u8; 32]>, Vec<u8>)> {
+        let invalid = || anyhow::anyhow!("对端的配对消息无效");
+        let peer = CompressedRistretto::from_slice(peer_element).map_err(|_| invalid())?
+            .decompress()
+            .filter(|p| !p.is_identity())
+            .ok_or_else(invalid)?;
+        let shared = peer * *self.secret;
+        if shared.is_identity() {
+            return Err(invalid());
+        }
+
+        // 双方元素按 发送端、接收端 的顺序排列，两端得到相同的记录
+        let mut elements = Vec::with_capacity(64);
+        match self.role {
+            Role::Sender => {
+                elements.extend_from_slice(&self.element);
+                elements.extend_from_slice(peer_element);
+            }
+            Role::Receiver => {
+                elements.extend_from_slice(peer_element);
+                elements.extend_from_slice(&self.element);
+            }
+        }
+        let mut hasher = Sha512::new();
+        hasher.update(KEY_CONTEXT);
+        hasher.update(shared.compress().as_bytes());
+        hasher.update(&elements);
+        let isk = Zeroizing::new(<[u8; 64]>::from(hasher.finalize()));
+        let key = SessionKey::from_bytes(&isk[..32])?;
+        let mut confirm = Zeroizing::new([0u8; 32]);
+        confirm.copy_from_slice(&isk[32..]);
+        Ok((key, confirm, elements))
+    }
+}
+
+/// 由传输码和通道 ID 导出本次配对的生成元
+fn generator(code: &str, channel: &str) -> RistrettoPoint {
+    let mut hasher = Sha512::new();
+    hasher.update(GENERATOR_CONTEXT);
+    for part in [code.as_bytes(), channel.as_bytes()] {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    let uniform = Zeroizing::new(<[u8; 64]>::from(hasher.finalize()));
+    RistrettoPoint::from_uniform_bytes(&uniform)
+}
+
+/// `role` 一方的密钥确认码
+fn confirmation(confirm_key: &[u8], role: Role, elements: &[u8]) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(confirm_key).expect("HMAC 接受任意长度的密钥");
+    mac.update(&[role.to_byte()]);
+    mac.update(elements);
+    mac
+}
+
+/// 在刚建立的连接上凭传输码完成配对并确认双方密钥一致，返回用于保护会话密钥的共享密钥。
+/// 传输码不一致或对端不是持有传输码的一方时返回错误，此时尚未发送任何文件数据
+pub(crate) async fn exchange<S>(stream: &mut S, code: &str, channel: &str, role: Role) -> anyhow::Result<SessionKey>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let pake = Pake::start(code, channel, role);
+    protocol::write_frame(stream, &PakeMessage { element: hex::encode(pake.element) }).await?;
+    let peer: PakeMessage = protocol::read_frame(stream).await?;
+    let peer_element = hex::decode(&peer.element).map_err(|_| anyhow::anyhow!("对端的配对消息无效"))?;
+    let (key, confirm_key, elements) = pake.finish(&peer_element)?;
+
+    let mine = confirmation(confirm_key.as_ref(), role, &elements).finalize().into_bytes();
+    protocol::write_frame(stream, &PakeConfirm { mac: hex::encode(mine) }).await?;
+    let theirs: PakeConfirm = protocol::read_frame(stream).await?;
+    let theirs = hex::decode(&theirs.mac).unwrap_or_default();
+    confirmation(confirm_key.as_ref(), role.peer(), &elements)
+        .verify_slice(&theirs)
+        .map_err(|_| anyhow::anyhow!("密钥确认失败：传输码不一致，或对端不是持有该传输码的一方，已中止"))?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn pair(sender_code: &str, receiver_code: &str) -> (anyhow::Result<SessionKey>, anyhow::Result<SessionKey>) {
+        let (mut a, mut b) = tokio::io::duplex(4096);
+        tokio::join!(
+            exchange(&mut a, sender_code, "channel", Role::Sender),
+            exchange(&mut b, receiver_code, "channel", Role::Receiver),
+        )
+    }
+
+    #[tokio::test]
+    async fn same_code_agrees_on_key() {
+        let (sender, receiver) = pair("7-amber-atlas", "7-amber-atlas").await;
+        assert_eq!(sender.unwrap().as_bytes(), receiver.unwrap().as_bytes());
+    }
+
+    #[tokio::test]
+    async fn different_code_fails_confirmation() {
+        let (sender, receiver) = pair("7-amber-atlas", "7-amber-aspen").await;
+        assert!(sender.is_err());
+        assert!(receiver.is_err());
+    }
+
+    #[test]
+    fn rejects_identity_element() {
+        let pake = Pake::start("7-amber-atlas", "channel", Role::Sender);
+        assert!(pake.finish(&[0u8; 32]).is_err());
+    }
+}
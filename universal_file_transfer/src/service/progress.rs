@@ -0,0 +1,200 @@
+use std::io::{IsTerminal, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use serde_json::json;
+use super::output;
+
+/// 终端上刷新进度行的最小间隔
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+/// 标准错误不是终端时输出进度日志的间隔
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+/// `--output json` 时输出进度事件的间隔
+const EVENT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 进度的显示方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Display {
+    /// `--quiet`：不显示
+    Hidden,
+    /// 在终端上原地刷新一行
+    Terminal,
+    /// 重定向到文件或管道时定期输出一行日志
+    Log,
+    /// `--output json`：定期在标准输出上输出 `progress` 事件
+    Event,
+}
+
+/// 单个文件的传输进度：已传输字节数、百分比、速率和剩余时间，输出到标准错误。
+/// 属于批量传输时同时显示总体进度
+pub(crate) struct Progress {
+    label: String,
+    total: Option<u64>,
+    done: u64,
+    started: Instant,
+    last_output: Instant,
+    display: Display,
+    batch: Option<(Arc<Batch>, u64)>,
+}
+
+impl Progress {
+    /// `total` 未知时（如标准输入）只显示字节数和速率
+    pub(crate) fn new(label: &str, total: Option<u64>, quiet: bool) -> Progress {
+        let display = if quiet {
+            Display::Hidden
+        } else if output::is_json() {
+            Display::Event
+        } else if std::io::stderr().is_terminal() {
+            Display::Terminal
+        } else {
+            Display::Log
+        };
+        let now = Instant::now();
+        Progress {
+            label: label.to_string(),
+            total,
+            done: 0,
+            started: now,
+            last_output: now,
+            display,
+            batch: None,
+        }
+    }
+
+    /// 计入批量传输的总体进度，`size` 为本文件在总量中所占的字节数
+    pub(crate) fn with_batch(mut self, batch: Option<Arc<Batch>>, size: u64) -> Progress {
+        self.batch = batch.map(|batch| (batch, size));
+        self
+    }
+
+    /// 开始传输后才知道总量时（如带长度前缀的数据）设置
+    pub(crate) fn set_total(&mut self, total: u64) {
+        self.total = Some(total);
+    }
+
+    pub(crate) fn inc(&mut self, n: u64) {
+        // 速率从收到或发出第一块数据时算起，不计握手和压缩等准备时间
+        if self.done == 0 {
+            self.started = Instant::now();
+        }
+        self.done += n;
+        let interval = match self.display {
+            Display::Hidden => return,
+            Display::Terminal => REDRAW_INTERVAL,
+            Display::Log => LOG_INTERVAL,
+            Display::Event => EVENT_INTERVAL,
+        };
+        if self.last_output.elapsed() >= interval {
+            self.last_output = Instant::now();
+            self.output(false);
+        }
+    }
+
+    /// 数据发送或接收完毕：终端上保留最终的一行
+    pub(crate) fn finish(&mut self) {
+        if self.display != Display::Hidden {
+            self.output(true);
+        }
+    }
+
+    fn output(&self, finished: bool) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 { self.done as f64 / elapsed } else { 0.0 };
+        if self.display == Display::Event {
+            output::event("progress", json!({
+                "file": self.label,
+                "bytes": self.done,
+                "total": self.total,
+                "rate": rate as u64,
+                "finished": finished,
+            }));
+            return;
+        }
+        let mut line = format!("{}：{}", self.label, format_bytes(self.done));
+        if let Some(total) = self.total.filter(|&t| t > 0) {
+            line.push_str(&format!(" / {}（{}%）", format_bytes(total), self.done.min(total) * 100 / total));
+        }
+        line.push_str(&format!("，{}/s", format_bytes(rate as u64)));
+        match self.total {
+            _ if finished => line.push_str(&format!("，用时 {}", format_duration(elapsed))),
+            Some(total) if rate > 0.0 => {
+                let remaining = total.saturating_sub(self.done) as f64 / rate;
+                line.push_str(&format!("，剩余 {}", format_duration(remaining)));
+            }
+            _ => {}
+        }
+        if let Some((batch, size)) = &self.batch {
+            let fraction = match self.total.filter(|&t| t > 0) {
+                Some(total) => self.done.min(total) as f64 / total as f64,
+                None => 0.0,
+            };
+            let current = if finished { *size } else { (*size as f64 * fraction) as u64 };
+            line.push_str(&format!("；{}", batch.summary(current, finished)));
+        }
+        let mut stderr = std::io::stderr().lock();
+        match self.display {
+            // 清除行尾残留的旧内容
+            Display::Terminal if finished => writeln!(stderr, "\r{}\x1b[K", line),
+            Display::Terminal => write!(stderr, "\r{}\x1b[K", line),
+            _ => writeln!(stderr, "{}", line),
+        }
+        .ok();
+        stderr.flush().ok();
+    }
+}
+
+/// 批量传输（如目录同步）的总体进度，由其中各文件的 [`Progress`] 共享
+#[derive(Debug)]
+pub(crate) struct Batch {
+    files: usize,
+    total: u64,
+    done_files: AtomicUsize,
+    done_bytes: AtomicU64,
+}
+
+impl Batch {
+    pub(crate) fn new(files: usize, total: u64) -> Arc<Batch> {
+        Arc::new(Batch { files, total, done_files: AtomicUsize::new(0), done_bytes: AtomicU64::new(0) })
+    }
+
+    /// 一个文件处理结束（无论成功与否），由批量传输的调用方计入已完成
+    pub(crate) fn file_done(&self, size: u64) {
+        self.done_files.fetch_add(1, Ordering::Relaxed);
+        self.done_bytes.fetch_add(size, Ordering::Relaxed);
+    }
+
+    /// `current` 为当前文件已完成的部分，`finished` 表示当前文件已传输完毕
+    fn summary(&self, current: u64, finished: bool) -> String {
+        let done = self.done_bytes.load(Ordering::Relaxed) + current;
+        let percent = (done.min(self.total) * 100).checked_div(self.total).unwrap_or(100);
+        let files = self.done_files.load(Ordering::Relaxed) + finished as usize;
+        format!("总计 {}/{} 个文件（{}%）", files, self.files, percent)
+    }
+}
+
+/// 以 1024 为进制的可读大小，如 `12.3 MiB`
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// `mm:ss`，超过一小时时为 `h:mm:ss`
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    let (h, m, s) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if h > 0 {
+        format!("{}:{:02}:{:02}", h, m, s)
+    } else {
+        format!("{:02}:{:02}", m, s)
+    }
+}
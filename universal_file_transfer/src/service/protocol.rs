@@ -0,0 +1,320 @@
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use super::compression::Compression;
+use super::cryptography::{self, CipherSuite, Kdf, SessionKey};
+use super::hashing::HashAlgorithm;
+
+/// 单个握手帧允许的最大长度，防止对端发送超大长度耗尽内存
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+/// 文件头认证数据的域分隔前缀
+const HEADER_AAD_CONTEXT: &[u8] = b"uft-header-v1";
+
+/// 连接用途
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Mode {
+    /// 发送单个文件，接收端遇到重名时另存为新文件
+    File,
+    /// 目录同步中的单个文件：按相对路径保存并覆盖旧文件
+    SyncFile,
+    /// 目录同步：交换文件清单并处理删除请求
+    Manifest,
+    /// 长度未知的数据流（如标准输入）：分块加密发送，SHA256 在末尾给出
+    Stream,
+}
+
+/// 发送端握手：列出本次传输希望使用的参数
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ClientHello {
+    /// 按偏好排序的压缩算法
+    pub(crate) compression: Vec<Compression>,
+    /// 期望的压缩级别
+    pub(crate) level: i32,
+    /// 是否请求增量传输（仅发送接收端缺少的块）
+    pub(crate) delta: bool,
+    pub(crate) mode: Mode,
+    /// 握手随机数，接收端的身份签名需包含它
+    pub(crate) nonce: String,
+    /// 会话密钥加密给的接收者公钥；设置后文件头携带公钥加密的会话密钥，而不是保护密钥
+    pub(crate) recipient: Option<String>,
+    /// 按偏好排序的文件数据加密算法
+    pub(crate) ciphers: Vec<CipherSuite>,
+    /// 会话密钥由双方共享的口令保护时，口令派生密钥的算法和参数；为 `None` 时由传输码配对或握手密钥交换得到的密钥保护
+    pub(crate) kdf: Option<Kdf>,
+    /// 文件明文大小（字节），数据流模式下未知
+    pub(crate) size: Option<u64>,
+    /// 按偏好排序的完整性摘要算法
+    pub(crate) hashes: Vec<HashAlgorithm>,
+    /// 文件头之后附带对文件摘要的签名 [`super::signing::FileSignature`]（明文帧，中转存储的节点也能校验）
+    pub(crate) signed: bool,
+    /// 握手密钥交换的临时 X25519 公钥（十六进制）
+    pub(crate) ephemeral: String,
+}
+
+/// 接收端握手：给出最终选定的参数。
+/// 文件数据按 [`super::hashing::VERIFY_CHUNK_SIZE`] 分块压缩、用流式加密逐块认证发送，两端都不必把整个文件读入内存
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ServerHello {
+    pub(crate) compression: Compression,
+    pub(crate) level: i32,
+    pub(crate) delta: bool,
+    /// 握手随机数，发送端的身份签名需包含它
+    pub(crate) nonce: String,
+    pub(crate) cipher: CipherSuite,
+    /// 文件摘要使用的算法
+    pub(crate) hash: HashAlgorithm,
+    /// 文件模式下使用分块摘要校验：文件头带 Merkle 根，数据前发送 [`ChunkHashes`]，
+    /// 每个数据块到达时对照对应的叶子校验，损坏的块可按 [`Receipt::repair`] 重传
+    pub(crate) chunk_hashes: bool,
+    /// 接收端接受目录同步和监听模式的传输（以 `--allow-sync` 启动）；
+    /// 为假时请求这两种模式的发送端在握手后中止，不会反复重试
+    pub(crate) sync: bool,
+    /// 握手密钥交换的临时 X25519 公钥（十六进制）
+    pub(crate) ephemeral: String,
+}
+
+/// 身份证明：长期公钥及其对握手记录摘要的签名（均为十六进制）。
+/// 双方在 [`ServerHello`] 之后各自单独发送一帧，接收端先发
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct IdentityProof {
+    pub(crate) public_key: String,
+    pub(crate) signature: String,
+}
+
+impl ServerHello {
+    /// 接收端从发送端的偏好列表中选择第一个本端支持的算法。
+    /// 不加密只在接收端以 `allow_insecure` 启动时接受；没有可用的加密算法时回落到 AES-256-GCM，由发送端判断是否接受
+    pub(crate) fn negotiate(hello: &ClientHello, allow_insecure: bool) -> ServerHello {
+        let compression = hello.compression.iter()
+            .copied()
+            .find(|c| Compression::SUPPORTED.contains(c))
+            .unwrap_or(Compression::None);
+        let cipher = hello.ciphers.iter()
+            .copied()
+            .find(|c| *c != CipherSuite::None || allow_insecure)
+            .unwrap_or_default();
+        ServerHello {
+            compression,
+            level: compression.clamp_level(hello.level),
+            // 公钥加密时中间节点可能无法解密，不能提供增量签名
+            delta: hello.delta && hello.recipient.is_none(),
+            nonce: String::new(),
+            cipher,
+            hash: hello.hashes.first().copied().unwrap_or_default(),
+            // 公钥加密时中间节点无法解密校验；数据流模式已逐块认证，且无法重传
+            chunk_hashes: matches!(hello.mode, Mode::File | Mode::SyncFile) && hello.recipient.is_none(),
+            sync: false,
+            ephemeral: String::new(),
+        }
+    }
+}
+
+/// 与文件头一同认证的传输参数：只取会影响数据解释方式的字段，避免握手帧新增字段导致两端摘要不一致
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct HeaderContext {
+    pub(crate) mode: Mode,
+    pub(crate) size: Option<u64>,
+    pub(crate) recipient: Option<String>,
+    pub(crate) compression: Compression,
+    pub(crate) level: i32,
+    pub(crate) cipher: CipherSuite,
+    pub(crate) delta: bool,
+    /// 文件摘要算法
+    pub(crate) hash: HashAlgorithm,
+    /// 是否使用分块摘要校验
+    pub(crate) chunk_hashes: bool,
+    /// 口令派生算法和参数
+    pub(crate) kdf: Option<Kdf>,
+    /// 文件头之后是否附带文件签名
+    pub(crate) signed: bool,
+}
+
+impl HeaderContext {
+    pub(crate) fn new(hello: &ClientHello, agreed: &ServerHello) -> HeaderContext {
+        HeaderContext {
+            mode: hello.mode,
+            size: hello.size,
+            recipient: hello.recipient.clone(),
+            compression: agreed.compression,
+            level: agreed.level,
+            cipher: agreed.cipher,
+            delta: agreed.delta,
+            hash: agreed.hash,
+            chunk_hashes: agreed.chunk_hashes,
+            kdf: hello.kdf,
+            signed: hello.signed,
+        }
+    }
+
+    /// 文件头的附加认证数据：文件头原始字节（文件名、摘要、密钥参数）与本组传输参数的摘要。
+    /// 任何一项被篡改都会使数据解密失败
+    pub(crate) fn aad(&self, header: &[u8]) -> Vec<u8> {
+        let mut hasher = Sha256::new();
+        hasher.update(HEADER_AAD_CONTEXT);
+        hasher.update((header.len() as u64).to_be_bytes());
+        hasher.update(header);
+        hasher.update(serde_json::to_vec(self).unwrap_or_default());
+        hasher.finalize().to_vec()
+    }
+}
+
+/// 本次传输的附加认证数据
+pub(crate) fn header_aad(header: &[u8], hello: &ClientHello, agreed: &ServerHello) -> Vec<u8> {
+    HeaderContext::new(hello, agreed).aad(header)
+}
+
+/// 文件明文按 [`super::hashing::VERIFY_CHUNK_SIZE`] 分块的 Merkle 树叶子，发送端在文件头之后以加密帧发送；
+/// 接收端用文件头中的 Merkle 根校验整个列表
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ChunkHashes {
+    pub(crate) hashes: Vec<String>,
+}
+
+/// 发送端重传的数据块，以加密帧发送
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct RepairedChunk {
+    pub(crate) index: u64,
+    /// 块的明文（十六进制）
+    pub(crate) data: String,
+    /// 块到 Merkle 根的路径，接收端只凭文件头中的根即可校验该块
+    pub(crate) proof: Vec<String>,
+}
+
+/// 接收端在文件保存并校验完成后回给发送端的确认
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Receipt {
+    pub(crate) error: Option<String>,
+    /// 分块校验失败、需要发送端重传的块序号；不为空时这不是最终确认，发送端重传后继续等待
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) repair: Vec<u64>,
+}
+
+impl Receipt {
+    pub(crate) fn from_result(result: &anyhow::Result<()>) -> Receipt {
+        Receipt {
+            error: result.as_ref().err().map(|e| e.to_string()),
+            repair: Vec::new(),
+        }
+    }
+
+    /// 请求重传指定的块
+    pub(crate) fn repair(chunks: Vec<u64>) -> Receipt {
+        Receipt { error: None, repair: chunks }
+    }
+}
+
+/// 写入一个握手帧：4 字节大端长度 + JSON
+pub(crate) async fn write_frame<S, T>(stream: &mut S, value: &T) -> anyhow::Result<()>
+where
+    S: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    write_frame_raw(stream, value).await.map(drop)
+}
+
+/// 写入一个握手帧，并返回帧内容的原始字节，用于计算握手记录摘要
+pub(crate) async fn write_frame_raw<S, T>(stream: &mut S, value: &T) -> anyhow::Result<Vec<u8>>
+where
+    S: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let bytes = serde_json::to_vec(value)?;
+    stream.write_u32(bytes.len() as u32).await?;
+    stream.write_all(&bytes).await?;
+    // 帧可能是连接上的最后一次写入（如回执），写完即推送出去
+    stream.flush().await?;
+    Ok(bytes)
+}
+
+/// 读取一个握手帧
+pub(crate) async fn read_frame<S, T>(stream: &mut S) -> anyhow::Result<T>
+where
+    S: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    read_frame_raw(stream).await.map(|(value, _)| value)
+}
+
+/// 读取一个握手帧，同时返回帧内容的原始字节
+pub(crate) async fn read_frame_raw<S, T>(stream: &mut S) -> anyhow::Result<(T, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let len = stream.read_u32().await? as usize;
+    if len > MAX_FRAME_LEN {
+        anyhow::bail!("握手帧过长: {} 字节", len);
+    }
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).await?;
+    Ok((serde_json::from_slice(&buf)?, buf))
+}
+
+/// 用会话密钥加密后的帧，用于传输不应以明文出现在网络上的结构化数据
+#[derive(Debug, Serialize, Deserialize)]
+struct SealedFrame {
+    salt: String,
+    nonce: String,
+    ct: String,
+}
+
+/// 写入一个以会话密钥加密的帧
+pub(crate) async fn write_sealed_frame<S, T>(stream: &mut S, key: &SessionKey, value: &T) -> anyhow::Result<()>
+where
+    S: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let (salt, nonce, ct) = cryptography::encrypt_data(key, &serde_json::to_vec(value)?);
+    write_frame(stream, &SealedFrame { salt, nonce, ct }).await
+}
+
+/// 读取并解密一个以会话密钥加密的帧
+pub(crate) async fn read_sealed_frame<S, T>(stream: &mut S, key: &SessionKey) -> anyhow::Result<T>
+where
+    S: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let frame: SealedFrame = read_frame(stream).await?;
+    let plain = cryptography::decrypt_data(key, &frame.salt, &frame.nonce, &frame.ct)
+        .map_err(|_| anyhow::anyhow!("加密帧解密失败"))?;
+    Ok(serde_json::from_slice(&plain)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> HeaderContext {
+        HeaderContext {
+            mode: Mode::File,
+            size: Some(42),
+            recipient: None,
+            compression: Compression::None,
+            level: 0,
+            cipher: CipherSuite::Aes256Gcm,
+            delta: false,
+            hash: HashAlgorithm::Sha256,
+            chunk_hashes: false,
+            kdf: None,
+            signed: false,
+        }
+    }
+
+    #[test]
+    fn authenticated_fields_change_aad() {
+        let base = context().aad(b"header");
+        let variants = [
+            HeaderContext { hash: HashAlgorithm::Blake3, ..context() },
+            HeaderContext { chunk_hashes: true, ..context() },
+            HeaderContext { kdf: Some(Kdf::default()), ..context() },
+            HeaderContext { signed: true, ..context() },
+            HeaderContext { size: Some(43), ..context() },
+        ];
+        for context in variants {
+            assert_ne!(context.aad(b"header"), base);
+        }
+        assert_ne!(context().aad(b"other header"), base);
+    }
+}
@@ -0,0 +1,404 @@
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use fs2::FileExt;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use super::SendOptions;
+use serde_json::json;
+use super::output::{self, say};
+use tracing::{error, warn};
+
+/// 首次失败后的重试间隔，之后每次翻倍
+const BACKOFF_BASE: Duration = Duration::from_secs(5);
+/// 重试间隔上限
+const BACKOFF_MAX: Duration = Duration::from_secs(600);
+/// 未指定 `--max-attempts` 时的最大尝试次数
+pub(crate) const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+/// 队列处理循环检查到期任务的间隔
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 任务状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum JobStatus {
+    /// 等待发送或等待下一次重试
+    Pending,
+    /// 已用完重试次数，需要 `queue retry` 手动重新加入
+    Failed,
+}
+
+/// 发送队列中的一个任务，每个任务对应队列目录下的一个 JSON 文件
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Job {
+    pub(crate) id: String,
+    /// 待发送文件的绝对路径，任务完成前需保持存在
+    pub(crate) file: PathBuf,
+    pub(crate) server: String,
+    pub(crate) port: u16,
+    pub(crate) send: SendOptions,
+    pub(crate) status: JobStatus,
+    /// 已尝试次数
+    pub(crate) attempts: u32,
+    pub(crate) max_attempts: u32,
+    /// 下一次允许尝试的时间（Unix 秒）
+    pub(crate) next_attempt: u64,
+    pub(crate) last_error: Option<String>,
+    /// 入队时间（Unix 秒）
+    pub(crate) created: u64,
+}
+
+/// 队列目录：`<数据目录>/queue`
+pub(crate) fn spool_dir() -> PathBuf {
+    super::data_dir().join("queue")
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn job_path(id: &str) -> PathBuf {
+    spool_dir().join(format!("{}.json", id))
+}
+
+/// 任务的锁文件：任务文件保存时会被替换，锁加在不改名的单独文件上
+fn lock_path(id: &str) -> PathBuf {
+    spool_dir().join(format!("{}.lock", id))
+}
+
+/// 第 `attempts` 次失败后的等待时间：指数退避，并在 [一半, 全部] 之间随机抖动，
+/// 避免大量任务在接收端恢复时同时重试
+fn backoff(attempts: u32) -> Duration {
+    let exp = BACKOFF_BASE.saturating_mul(1 << attempts.saturating_sub(1).min(16));
+    let delay = exp.min(BACKOFF_MAX);
+    let millis = delay.as_millis() as u64;
+    Duration::from_millis(rand::thread_rng().gen_range(millis / 2..=millis))
+}
+
+impl Job {
+    fn load(path: &Path) -> io::Result<Job> {
+        serde_json::from_slice(&std::fs::read(path)?).map_err(io::Error::other)
+    }
+
+    /// 先写临时文件再改名，避免中途退出留下损坏的任务文件
+    fn save(&self) -> io::Result<()> {
+        let path = job_path(&self.id);
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    /// 发送结束后保存任务；任务文件已不存在说明发送期间被 `queue cancel` 取消，不再写回
+    fn save_if_queued(&self) -> io::Result<()> {
+        if !job_path(&self.id).exists() {
+            say!("[{}] 任务已被取消", self.id);
+            return Ok(());
+        }
+        self.save()
+    }
+
+    /// 移出队列；任务文件已被取消时同样视为成功
+    fn remove(&self) -> io::Result<()> {
+        match std::fs::remove_file(job_path(&self.id)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        std::fs::remove_file(lock_path(&self.id)).ok();
+        Ok(())
+    }
+
+    /// 认领任务：持有锁期间其他 `queue run` 进程不会发送同一任务，进程退出时锁自动释放。
+    /// 加锁后重新读取任务文件，以其他进程可能刚写入的内容为准；
+    /// 任务已被认领、已完成或已取消时返回 `None`
+    fn claim(id: &str) -> io::Result<Option<(Job, File)>> {
+        let lock = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(lock_path(id))?;
+        match lock.try_lock_exclusive() {
+            Ok(()) => {}
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        match Job::load(&job_path(id)) {
+            Ok(job) => Ok(Some((job, lock))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                std::fs::remove_file(lock_path(id)).ok();
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn is_due(&self, now: u64) -> bool {
+        self.status == JobStatus::Pending && self.next_attempt <= now
+    }
+
+    /// 发送一次；成功则移出队列，失败则按退避策略安排下一次重试
+    async fn attempt(&mut self) -> io::Result<()> {
+        self.attempts += 1;
+        say!("[{}] 第 {}/{} 次发送 {}", self.id, self.attempts, self.max_attempts, self.file.display());
+        let file = self.file.to_string_lossy();
+        match super::tcp_send(&self.server, self.port, &file, &self.send).await {
+            Ok(()) => {
+                say!("[{}] 发送成功，已移出队列", self.id);
+                output::event("attempt", json!({ "id": self.id, "attempt": self.attempts, "ok": true }));
+                self.remove()
+            }
+            Err(e) => {
+                output::event("attempt", json!({ "id": self.id, "attempt": self.attempts, "ok": false, "error": e.to_string() }));
+                match self.record_failure(&e, now_secs()) {
+                    Some(delay) => warn!(job = %self.id, attempt = self.attempts, "发送失败，{} 秒后重试: {:#}", delay.as_secs(), e),
+                    None => error!(job = %self.id, attempt = self.attempts, "发送失败，已达最大尝试次数: {:#}", e),
+                }
+                self.save_if_queued()
+            }
+        }
+    }
+
+    /// 记录一次失败：未用完尝试次数时按退避策略安排下一次重试并返回等待时间，用完时标记为失败
+    fn record_failure(&mut self, error: &anyhow::Error, now: u64) -> Option<Duration> {
+        self.last_error = Some(error.to_string());
+        if self.attempts >= self.max_attempts {
+            self.status = JobStatus::Failed;
+            return None;
+        }
+        let delay = backoff(self.attempts);
+        self.next_attempt = now + delay.as_secs();
+        Some(delay)
+    }
+}
+
+/// 把文件加入发送队列
+pub(crate) fn enqueue(file: &str, server: &str, port: u16, send: &SendOptions, max_attempts: u32) -> anyhow::Result<Job> {
+    let file = std::fs::canonicalize(file)?;
+    if !file.is_file() {
+        anyhow::bail!("{} 不是普通文件", file.display());
+    }
+    std::fs::create_dir_all(spool_dir())?;
+    let now = now_secs();
+    let job = Job {
+        id: format!("{:x}-{:04x}", now, rand::thread_rng().r#gen::<u16>()),
+        file,
+        server: server.to_string(),
+        port,
+        send: send.clone(),
+        status: JobStatus::Pending,
+        attempts: 0,
+        max_attempts: max_attempts.max(1),
+        next_attempt: now,
+        last_error: None,
+        created: now,
+    };
+    job.save()?;
+    say!("已加入发送队列：{}", job.id);
+    output::event("queued", json!({ "id": job.id, "file": job.file }));
+    Ok(job)
+}
+
+/// 读取队列中的全部任务，按入队时间排序
+pub(crate) fn list_jobs() -> io::Result<Vec<Job>> {
+    let entries = match std::fs::read_dir(spool_dir()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut jobs = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "json") {
+            match Job::load(&path) {
+                Ok(job) => jobs.push(job),
+                Err(e) => warn!("忽略损坏的任务文件 {}: {}", path.display(), e),
+            }
+        }
+    }
+    jobs.sort_by(|a, b| (a.created, &a.id).cmp(&(b.created, &b.id)));
+    Ok(jobs)
+}
+
+fn find_job(id: &str) -> anyhow::Result<Job> {
+    let path = job_path(id);
+    if !id.chars().all(|c| c.is_ascii_hexdigit() || c == '-') || !path.exists() {
+        anyhow::bail!("队列中没有任务 {}", id);
+    }
+    Ok(Job::load(&path)?)
+}
+
+/// 处理队列中的到期任务。
+/// 指定 `only` 时只处理该任务；否则处理全部任务，`follow` 为真时持续运行并接收新入队的任务。
+/// 按 Ctrl+C 时不再开始新的任务，等正在发送的任务完成后退出，未完成的任务留在队列中
+pub(crate) async fn run(only: Option<&str>, follow: bool) -> anyhow::Result<()> {
+    use transfer_api::health;
+
+    std::fs::create_dir_all(spool_dir())?;
+    while !health::is_draining() {
+        let now = now_secs();
+        let mut jobs = list_jobs()?;
+        if let Some(id) = only {
+            jobs.retain(|j| j.id == id);
+        }
+        let waiting = jobs.iter().filter(|j| j.status == JobStatus::Pending).count();
+        transfer_api::metrics::set_queue_depth(waiting as u64);
+        if waiting == 0 && !follow {
+            break;
+        }
+        for job in jobs.into_iter().filter(|j| j.is_due(now)) {
+            if health::is_draining() {
+                break;
+            }
+            // 同时运行的其他 `queue run` 可能已认领或刚处理完该任务
+            let Some((mut job, _lock)) = Job::claim(&job.id)? else { continue };
+            if !job.is_due(now_secs()) {
+                continue;
+            }
+            super::finish_or_interrupt(async { Ok(job.attempt().await?) }).await?;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+            _ = tokio::signal::ctrl_c() => health::set_draining(true),
+        }
+    }
+
+    if health::is_draining() {
+        say!("发送队列已停止，未完成的任务留在队列中");
+        return Ok(());
+    }
+
+    if let Some(Ok(job)) = only.map(find_job) {
+        anyhow::bail!("任务 {} 发送失败: {}", job.id, job.last_error.unwrap_or_default());
+    }
+    Ok(())
+}
+
+/// 打印队列内容
+pub(crate) fn print_list() -> anyhow::Result<()> {
+    let jobs = list_jobs()?;
+    if jobs.is_empty() {
+        say!("发送队列为空");
+        return Ok(());
+    }
+    let now = now_secs();
+    for job in &jobs {
+        let state = match job.status {
+            JobStatus::Failed => "失败".to_string(),
+            JobStatus::Pending if job.next_attempt <= now => "等待发送".to_string(),
+            JobStatus::Pending => format!("{} 秒后重试", job.next_attempt - now),
+        };
+        say!(
+            "{}  {}  尝试 {}/{}  {}:{}  {}",
+            job.id, state, job.attempts, job.max_attempts, job.server, job.port, job.file.display(),
+        );
+        if let Some(err) = &job.last_error {
+            say!("    最近错误: {}", err);
+        }
+        output::event("job", json!({
+            "id": job.id,
+            "status": job.status,
+            "attempts": job.attempts,
+            "max_attempts": job.max_attempts,
+            "server": job.server,
+            "port": job.port,
+            "file": job.file,
+            "next_attempt": job.next_attempt,
+            "last_error": job.last_error,
+        }));
+    }
+    Ok(())
+}
+
+/// 重置任务的尝试次数，使其立即重新发送；`id` 为空时重置全部失败任务
+pub(crate) fn retry(id: Option<&str>) -> anyhow::Result<()> {
+    let jobs = match id {
+        Some(id) => vec![find_job(id)?],
+        None => list_jobs()?.into_iter().filter(|j| j.status == JobStatus::Failed).collect(),
+    };
+    for mut job in jobs {
+        job.status = JobStatus::Pending;
+        job.attempts = 0;
+        job.next_attempt = now_secs();
+        job.save()?;
+        say!("任务 {} 已重新加入队列", job.id);
+    }
+    Ok(())
+}
+
+/// 从队列中移除任务
+pub(crate) fn cancel(id: &str) -> anyhow::Result<()> {
+    find_job(id)?.remove()?;
+    say!("任务 {} 已取消", id);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(max_attempts: u32) -> Job {
+        Job {
+            id: "1-0000".to_string(),
+            file: PathBuf::from("/tmp/file"),
+            server: "127.0.0.1".to_string(),
+            port: 1,
+            send: SendOptions::default(),
+            status: JobStatus::Pending,
+            attempts: 0,
+            max_attempts,
+            next_attempt: 0,
+            last_error: None,
+            created: 0,
+        }
+    }
+
+    fn assert_within(delay: Duration, full: Duration) {
+        assert!(delay >= full / 2 && delay <= full, "{:?} 不在 [{:?}, {:?}] 内", delay, full / 2, full);
+    }
+
+    #[test]
+    fn backoff_doubles_from_base() {
+        for _ in 0..100 {
+            assert_within(backoff(1), BACKOFF_BASE);
+            assert_within(backoff(2), BACKOFF_BASE * 2);
+            assert_within(backoff(3), BACKOFF_BASE * 4);
+            assert_within(backoff(7), BACKOFF_BASE * 64);
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        for attempts in [8, 20, u32::MAX] {
+            assert_within(backoff(attempts), BACKOFF_MAX);
+        }
+    }
+
+    #[test]
+    fn backoff_is_jittered() {
+        let delays: Vec<Duration> = (0..200).map(|_| backoff(4)).collect();
+        let min = delays.iter().min().unwrap();
+        let max = delays.iter().max().unwrap();
+        assert!(*min >= BACKOFF_BASE * 4 && *max <= BACKOFF_BASE * 8);
+        assert!(min < max, "重试间隔没有随机抖动");
+    }
+
+    #[test]
+    fn last_attempt_marks_job_failed() {
+        let mut job = job(3);
+        let error = anyhow::anyhow!("连接被拒绝");
+        for attempt in 1..3 {
+            job.attempts = attempt;
+            let delay = job.record_failure(&error, 1000).unwrap();
+            assert_eq!(job.status, JobStatus::Pending);
+            assert_eq!(job.next_attempt, 1000 + delay.as_secs());
+        }
+        job.attempts = 3;
+        assert!(job.record_failure(&error, 2000).is_none());
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.last_error.as_deref(), Some("连接被拒绝"));
+        assert!(!job.is_due(u64::MAX));
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use super::code;
+use serde_json::json;
+use super::output::{self, say};
+use tracing::error;
+
+/// 中继握手中的角色标识
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    Sender,
+    Receiver,
+}
+
+impl Role {
+    fn to_byte(self) -> u8 {
+        match self {
+            Role::Sender => b'S',
+            Role::Receiver => b'R',
+        }
+    }
+
+    fn from_byte(b: u8) -> Option<Role> {
+        match b {
+            b'S' => Some(Role::Sender),
+            b'R' => Some(Role::Receiver),
+            _ => None,
+        }
+    }
+}
+
+/// 配对成功后中继回给双方的确认字节
+const PAIRED_ACK: u8 = 1;
+/// 通道上已有同一角色的连接在等待时回给新连接的拒绝字节
+const CHANNEL_BUSY: u8 = 2;
+/// 通道 ID 的最大长度：传输码派生的通道 ID 为 64 个十六进制字符
+const MAX_CHANNEL_LEN: usize = 64;
+/// 等待对端加入的最长时间，超时后关闭连接并释放通道
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// 等待配对的连接：通道 ID -> (登记序号, 角色, 连接)；序号用于超时时只移除自己登记的连接
+type Waiting = Arc<Mutex<HashMap<String, (u64, Role, TcpStream)>>>;
+
+/// 传输码模式下未指定端口时使用的默认中继端口
+pub(crate) const DEFAULT_PORT: u16 = 4001;
+
+/// 运行中继服务：按通道 ID 配对发送端与接收端，并在两者之间原样转发字节
+pub(crate) async fn run(port: u16) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    say!("中继模式：监听端口 {}...", port);
+    output::event("listening", json!({ "mode": "relay", "port": port }));
+    serve(listener, PAIRING_TIMEOUT).await
+}
+
+async fn serve(listener: TcpListener, pairing_timeout: Duration) -> anyhow::Result<()> {
+    let waiting: Waiting = Arc::new(Mutex::new(HashMap::new()));
+    let next_id = Arc::new(AtomicU64::new(0));
+
+    loop {
+        let (mut socket, addr) = listener.accept().await?;
+        let waiting = waiting.clone();
+        let id = next_id.fetch_add(1, Ordering::Relaxed);
+        tokio::spawn(async move {
+            let result: anyhow::Result<()> = async {
+                // 读取握手：角色(1 字节) + 通道 ID 长度(2 字节) + 通道 ID
+                let role = Role::from_byte(socket.read_u8().await?)
+                    .ok_or_else(|| anyhow::anyhow!("未知的中继角色"))?;
+                let len = socket.read_u16().await? as usize;
+                if len > MAX_CHANNEL_LEN {
+                    anyhow::bail!("通道 ID 过长（{} 字节）", len);
+                }
+                let mut channel_buf = vec![0u8; len];
+                socket.read_exact(&mut channel_buf).await?;
+                let channel = String::from_utf8(channel_buf)?;
+                let tag: String = channel.chars().take(8).collect();
+
+                let (mut socket, mut peer) = match register(&waiting, &channel, id, role, socket) {
+                    Pairing::Paired(socket, peer) => (socket, peer),
+                    Pairing::Busy(mut socket) => {
+                        socket.write_u8(CHANNEL_BUSY).await.ok();
+                        anyhow::bail!("通道 {} 上已有同一角色的连接在等待", tag);
+                    }
+                    Pairing::Waiting => {
+                        say!("{} 正在通道 {} 上等待对端", addr, tag);
+                        // 对端超时未加入时移除登记，关闭连接
+                        tokio::time::sleep(pairing_timeout).await;
+                        let mut map = waiting.lock().unwrap();
+                        if map.get(&channel).is_some_and(|(waiting_id, _, _)| *waiting_id == id) {
+                            map.remove(&channel);
+                            say!("通道 {} 等待对端超时，已关闭", tag);
+                        }
+                        return Ok(());
+                    }
+                };
+
+                socket.write_u8(PAIRED_ACK).await?;
+                peer.write_u8(PAIRED_ACK).await?;
+                say!("通道 {} 已配对，开始转发", tag);
+
+                // 中继只转发密文，不参与解密
+                let (up, down) = tokio::io::copy_bidirectional(&mut socket, &mut peer).await?;
+                say!("通道 {} 转发结束：{} / {} 字节", tag, up, down);
+                Ok(())
+            }.await;
+
+            if let Err(e) = result {
+                error!(peer = %addr, "处理中继客户端时出错: {:#}", e);
+            }
+        });
+    }
+}
+
+/// 登记一个连接的结果
+enum Pairing {
+    /// 已登记，等待对端加入
+    Waiting,
+    /// 与等待中的对端配对：(本端连接, 对端连接)
+    Paired(TcpStream, TcpStream),
+    /// 通道上已有同一角色的连接在等待，不顶替它，退回本端连接
+    Busy(TcpStream),
+}
+
+/// 等待中的连接是否已断开：收到配对确认之前客户端不发送数据，能读到结束即对端已关闭
+fn is_closed(stream: &TcpStream) -> bool {
+    match stream.try_read(&mut [0u8; 1]) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => e.kind() != std::io::ErrorKind::WouldBlock,
+    }
+}
+
+/// 若通道上有另一角色的对端在等待则取出配对，否则登记本端连接；已断开的等待连接直接丢弃
+fn register(waiting: &Waiting, channel: &str, id: u64, role: Role, socket: TcpStream) -> Pairing {
+    let mut map = waiting.lock().unwrap();
+    match map.remove(channel).filter(|(_, _, peer)| !is_closed(peer)) {
+        Some((_, peer_role, peer)) if peer_role != role => Pairing::Paired(socket, peer),
+        Some(entry) => {
+            map.insert(channel.to_string(), entry);
+            Pairing::Busy(socket)
+        }
+        None => {
+            map.insert(channel.to_string(), (id, role, socket));
+            Pairing::Waiting
+        }
+    }
+}
+
+/// 连接中继并等待与对端配对，返回可直接用于传输的连接
+pub(crate) async fn connect(server: &str, port: u16, code: &str, role: Role) -> anyhow::Result<TcpStream> {
+    let mut stream = TcpStream::connect((server, port)).await?;
+    let channel = code::channel_id(code);
+    stream.write_u8(role.to_byte()).await?;
+    stream.write_u16(channel.len() as u16).await?;
+    stream.write_all(channel.as_bytes()).await?;
+    say!("已连接中继 {}:{}，等待对端加入...", server, port);
+
+    match stream.read_u8().await {
+        Ok(PAIRED_ACK) => {}
+        Ok(CHANNEL_BUSY) => anyhow::bail!("该传输码已有同一角色的连接在中继上等待，请换用新的传输码"),
+        Ok(_) => anyhow::bail!("中继返回了无效的配对确认"),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => anyhow::bail!("等待对端超时，中继已关闭连接"),
+        Err(e) => return Err(e.into()),
+    }
+    say!("已通过中继与对端配对");
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn start(pairing_timeout: Duration) -> u16 {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve(listener, pairing_timeout));
+        port
+    }
+
+    #[tokio::test]
+    async fn pairs_sender_and_receiver() {
+        let port = start(PAIRING_TIMEOUT).await;
+        let receiver = tokio::spawn(connect("127.0.0.1", port, "42-pair", Role::Receiver));
+        let mut sender = connect("127.0.0.1", port, "42-pair", Role::Sender).await.unwrap();
+        let mut receiver = receiver.await.unwrap().unwrap();
+        sender.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        receiver.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn rejects_duplicate_role_and_keeps_waiting_peer() {
+        let port = start(PAIRING_TIMEOUT).await;
+        let first = tokio::spawn(connect("127.0.0.1", port, "42-dup", Role::Sender));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let err = connect("127.0.0.1", port, "42-dup", Role::Sender).await.unwrap_err();
+        assert!(err.to_string().contains("同一角色"), "{}", err);
+        // 先等待的一方仍可与接收端配对
+        let _receiver = connect("127.0.0.1", port, "42-dup", Role::Receiver).await.unwrap();
+        first.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn closed_waiting_peer_is_replaced() {
+        let port = start(PAIRING_TIMEOUT).await;
+        let first = tokio::spawn(connect("127.0.0.1", port, "42-gone", Role::Sender));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        first.abort();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let sender = tokio::spawn(connect("127.0.0.1", port, "42-gone", Role::Sender));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        connect("127.0.0.1", port, "42-gone", Role::Receiver).await.unwrap();
+        sender.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn waiting_peer_times_out() {
+        let port = start(Duration::from_millis(100)).await;
+        let err = connect("127.0.0.1", port, "42-late", Role::Sender).await.unwrap_err();
+        assert!(err.to_string().contains("超时"), "{}", err);
+        // 通道已释放，可以重新登记
+        let receiver = tokio::spawn(connect("127.0.0.1", port, "42-late", Role::Receiver));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        connect("127.0.0.1", port, "42-late", Role::Sender).await.unwrap();
+        receiver.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_long_channel_id() {
+        let port = start(PAIRING_TIMEOUT).await;
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        stream.write_u8(Role::Sender.to_byte()).await.unwrap();
+        stream.write_u16(u16::MAX).await.unwrap();
+        assert!(stream.read_u8().await.is_err());
+    }
+}
@@ -0,0 +1,608 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use x25519_dalek::{PublicKey, StaticSecret};
+use super::compression::{self, Compression};
+use super::{chunked, cryptography, identity, SendOptions};
+use super::cryptography::{CipherSuite, Kdf, SessionKey};
+use super::hashing::{self, HashAlgorithm};
+use super::protocol::{HeaderContext, Mode};
+use super::output::{self, FileRecord, say};
+
+/// 加密文件的文件头魔数
+const MAGIC: &[u8; 4] = b"UFT1";
+/// 加密文件的扩展名
+pub(crate) const EXTENSION: &str = "uft";
+
+/// 会话密钥经接收者公钥加密后的结果，对应 `cryptography::encrypt_data_to` 的返回值
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct WrappedKey {
+    /// 接收者公钥（十六进制）
+    pub(crate) recipient: String,
+    pub(crate) ephemeral: String,
+    pub(crate) nonce: String,
+    pub(crate) ct: String,
+}
+
+impl WrappedKey {
+    /// 用接收者公钥加密会话密钥
+    pub(crate) fn wrap(recipient: &PublicKey, key: &SessionKey) -> WrappedKey {
+        let (ephemeral, nonce, ct) = cryptography::encrypt_data_to(recipient, key.as_bytes());
+        WrappedKey { recipient: identity::encode_recipient(recipient), ephemeral, nonce, ct }
+    }
+
+    /// 本机是否为该文件的接收者
+    pub(crate) fn is_for_me(&self) -> io::Result<bool> {
+        let mine = PublicKey::from(&identity::recipient_secret()?);
+        Ok(self.recipient == identity::encode_recipient(&mine))
+    }
+
+    /// 用本机接收私钥解出会话密钥
+    pub(crate) fn unwrap_key(&self) -> anyhow::Result<SessionKey> {
+        if !self.is_for_me()? {
+            anyhow::bail!("数据加密给了其他接收者 {}，本机无法解密", self.recipient);
+        }
+        self.unwrap_with(&identity::recipient_secret()?)
+    }
+
+    fn unwrap_with(&self, secret: &StaticSecret) -> anyhow::Result<SessionKey> {
+        let pt = cryptography::decrypt_data_with(secret, &self.ephemeral, &self.nonce, &self.ct)
+            .map_err(|_| anyhow::anyhow!("无法解密会话密钥：数据损坏"))?;
+        Ok(SessionKey::from_bytes(&zeroize::Zeroizing::new(pt))?)
+    }
+}
+
+/// 由口令派生的密钥加密的文件密钥，对应 `cryptography::encrypt_data_kdf` 的返回值
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct PassphraseKey {
+    pub(crate) kdf: Kdf,
+    pub(crate) salt: String,
+    pub(crate) nonce: String,
+    pub(crate) ct: String,
+}
+
+/// 加密文件头：解密和校验所需的全部信息
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SealedHeader {
+    /// 原始文件名
+    pub(crate) name: String,
+    /// 原始文件的摘要（十六进制）
+    pub(crate) digest: String,
+    /// 摘要算法
+    pub(crate) hash: HashAlgorithm,
+    pub(crate) compression: Compression,
+    pub(crate) cipher: CipherSuite,
+    /// 加密给接收者公钥的文件密钥
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) key: Option<WrappedKey>,
+    /// 由口令保护的文件密钥
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) passphrase: Option<PassphraseKey>,
+    /// 传输时的文件头原始字节（十六进制），与 `context` 一起构成密文的附加认证数据
+    pub(crate) header: String,
+    /// 与文件头一同认证的传输参数
+    pub(crate) context: HeaderContext,
+}
+
+/// 创建加密文件并写入 `[魔数][u32 文件头长度][JSON 文件头]`，返回用于追加密文的文件
+pub(crate) fn create(path: &Path, header: &SealedHeader) -> io::Result<BufWriter<File>> {
+    let header_bytes = serde_json::to_vec(header)?;
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(MAGIC)?;
+    out.write_all(&(header_bytes.len() as u32).to_be_bytes())?;
+    out.write_all(&header_bytes)?;
+    Ok(out)
+}
+
+/// 打开加密文件，返回文件头和位于密文开头的读取器
+pub(crate) fn read(path: &Path) -> io::Result<(SealedHeader, BufReader<File>)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let invalid = || io::Error::new(ErrorKind::InvalidData, format!("{} 不是有效的加密文件", path.display()));
+    let mut prefix = [0u8; 8];
+    reader.read_exact(&mut prefix).map_err(|_| invalid())?;
+    if &prefix[..4] != MAGIC {
+        return Err(invalid());
+    }
+    let header_len = u32::from_be_bytes(prefix[4..].try_into().unwrap()) as usize;
+    let mut header_bytes = Vec::new();
+    (&mut reader).take(header_len as u64).read_to_end(&mut header_bytes)?;
+    if header_bytes.len() != header_len {
+        return Err(invalid());
+    }
+    let header: SealedHeader = serde_json::from_slice(&header_bytes).map_err(|_| invalid())?;
+    Ok((header, reader))
+}
+
+/// 用本机接收私钥或口令解密加密文件并校验摘要；未指定 `output` 时保存到加密文件所在目录，使用原始文件名。
+/// 边解密边写入临时文件，校验通过后改名
+pub(crate) fn open(path: &str, output: Option<&str>, password: Option<&str>) -> anyhow::Result<PathBuf> {
+    let path = Path::new(path);
+    let (header, body) = read(path)?;
+    let key = match (&header.key, &header.passphrase) {
+        (Some(key), _) => key.unwrap_key()?,
+        (None, Some(protected)) => {
+            let password = password.ok_or_else(|| anyhow::anyhow!("该文件由口令保护，请使用 --password"))?;
+            let pt = cryptography::decrypt_data_kdf(&protected.kdf, password, &protected.salt, &protected.nonce, &protected.ct)
+                .map_err(|e| match e.kind() {
+                    ErrorKind::InvalidInput => anyhow::anyhow!("口令派生参数无效: {}", e),
+                    _ => anyhow::anyhow!("无法解密文件密钥：口令错误或数据损坏"),
+                })?;
+            SessionKey::from_bytes(&zeroize::Zeroizing::new(pt))?
+        }
+        (None, None) => anyhow::bail!("{} 不是有效的加密文件", path.display()),
+    };
+    decrypt(path, &header, body, &key, output)
+}
+
+/// 用已解出的文件密钥解密 `read` 得到的密文
+fn decrypt(
+    path: &Path,
+    header: &SealedHeader,
+    mut body: BufReader<File>,
+    key: &SessionKey,
+    output: Option<&str>,
+) -> anyhow::Result<PathBuf> {
+    // 文件名、摘要和解密参数都取自经过认证的原始文件头，忽略 JSON 中的副本
+    let context = &header.context;
+    let invalid = || anyhow::anyhow!("{} 的文件头无效", path.display());
+    let raw = hex::decode(&header.header).map_err(|_| invalid())?;
+    let (name, digest) = parse_transfer_header(&raw, context.hash).ok_or_else(invalid)?;
+    let aad = context.aad(&raw);
+
+    let target = match output {
+        Some(out) => PathBuf::from(out),
+        None => {
+            let name = Path::new(&name).file_name()
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from("decrypted.bin"));
+            path.parent().unwrap_or(Path::new(".")).join(name)
+        }
+    };
+    if target.exists() {
+        anyhow::bail!("{} 已存在，请用 -o 指定其他输出路径", target.display());
+    }
+
+    let part = part_path(&target, "part");
+    let result = File::create(&part).map_err(anyhow::Error::from).and_then(|file| {
+        let mut out = BufWriter::new(file);
+        chunked::open_chunks(&mut body, key, &aad, context.cipher, context.compression, context.hash, &mut out)
+            .map_err(|e| anyhow::anyhow!("文件解密失败：{:#}", e))
+    });
+    // 数据流模式保存的文件摘要只在尾块中给出
+    let result = result.and_then(|(size, calculated)| {
+        if digest != super::deferred_digest(context.hash) && calculated != digest.to_lowercase() {
+            anyhow::bail!("{} 校验失败: {} != {}", context.hash, digest, calculated);
+        }
+        Ok((size, calculated))
+    });
+    let (size, calculated) = match result {
+        Ok(done) => {
+            std::fs::rename(&part, &target)?;
+            done
+        }
+        Err(e) => {
+            std::fs::remove_file(&part).ok();
+            return Err(e);
+        }
+    };
+    say!("已解密到 {}", target.display());
+    output::file("decrypted", FileRecord::new(target.display().to_string()).size(size).digest(context.hash, &calculated));
+    Ok(target)
+}
+
+/// 文件密钥的保护方式
+#[derive(Clone, Copy)]
+pub(crate) enum Protection<'a> {
+    /// 加密给接收者公钥
+    Recipient(&'a PublicKey),
+    /// 由口令派生的密钥加密
+    Passphrase(&'a Kdf, &'a str),
+}
+
+/// 生成加密文件所需的参数
+#[derive(Clone, Copy)]
+pub(crate) struct SealOptions<'a> {
+    /// 原始文件名
+    pub(crate) name: &'a str,
+    /// 明文摘要；数据流模式下事先未知，为 `None`，摘要只在尾块中给出
+    pub(crate) digest: Option<&'a str>,
+    pub(crate) size: Option<u64>,
+    pub(crate) protection: Protection<'a>,
+    pub(crate) compression: Compression,
+    pub(crate) level: i32,
+    pub(crate) cipher: CipherSuite,
+    pub(crate) hash: HashAlgorithm,
+}
+
+/// `<目标路径>.<后缀>`
+fn part_path(target: &Path, suffix: &str) -> PathBuf {
+    let mut part = target.as_os_str().to_owned();
+    part.push(".");
+    part.push(suffix);
+    PathBuf::from(part)
+}
+
+/// 流式写入分块加密的加密文件，用于接收端加密存储和离线加密。
+/// 文件头按网络传输时的格式构造并参与认证，`decrypt` 命令可直接解密和校验；
+/// 明文按 [`chunked::CHUNK_SIZE`] 分块加密追加到临时文件，[`Writer::finish`] 写入尾块后改名为目标文件，
+/// 未完成时丢弃临时文件
+pub(crate) struct Writer<'a> {
+    options: SealOptions<'a>,
+    target: PathBuf,
+    part: PathBuf,
+    file_key: SessionKey,
+    aad: Vec<u8>,
+    /// 密文在临时文件中的起始位置
+    body_offset: u64,
+    out: BufWriter<File>,
+    sealer: Option<chunked::Sealer>,
+    buffer: Vec<u8>,
+    finished: bool,
+}
+
+impl<'a> Writer<'a> {
+    pub(crate) fn create(target: &Path, options: SealOptions<'a>) -> anyhow::Result<Writer<'a>> {
+        Writer::create_at(target, part_path(target, "part"), options)
+    }
+
+    fn create_at(target: &Path, part: PathBuf, options: SealOptions<'a>) -> anyhow::Result<Writer<'a>> {
+        let file_key = SessionKey::generate();
+        let digest = options.digest.map_or_else(|| super::deferred_digest(options.hash), str::to_string);
+
+        let mut raw = Vec::new();
+        raw.extend_from_slice(&(options.name.len() as u32).to_be_bytes());
+        raw.extend_from_slice(options.name.as_bytes());
+        raw.extend_from_slice(digest.as_bytes());
+        let (key, passphrase) = match options.protection {
+            Protection::Recipient(recipient) => {
+                let wrapped = WrappedKey::wrap(recipient, &file_key);
+                raw.extend_from_slice(wrapped.ephemeral.as_bytes());
+                raw.extend_from_slice(wrapped.nonce.as_bytes());
+                raw.extend_from_slice(wrapped.ct.as_bytes());
+                (Some(wrapped), None)
+            }
+            Protection::Passphrase(kdf, password) => {
+                let (salt, nonce, ct) = cryptography::encrypt_data_kdf(kdf, password, file_key.as_bytes())?;
+                raw.extend_from_slice(salt.as_bytes());
+                raw.extend_from_slice(nonce.as_bytes());
+                raw.extend_from_slice(ct.as_bytes());
+                (None, Some(PassphraseKey { kdf: *kdf, salt, nonce, ct }))
+            }
+        };
+        let context = HeaderContext {
+            mode: if options.digest.is_some() { Mode::File } else { Mode::Stream },
+            size: options.size,
+            recipient: key.as_ref().map(|k| k.recipient.clone()),
+            compression: options.compression,
+            level: options.level,
+            cipher: options.cipher,
+            delta: false,
+            hash: options.hash,
+            chunk_hashes: false,
+            kdf: passphrase.as_ref().map(|p| p.kdf),
+            signed: false,
+        };
+        let aad = context.aad(&raw);
+        let header = SealedHeader {
+            name: options.name.to_string(),
+            digest,
+            hash: options.hash,
+            compression: options.compression,
+            cipher: options.cipher,
+            key,
+            passphrase,
+            header: hex::encode(&raw),
+            context,
+        };
+
+        let mut out = create(&part, &header)?;
+        let (sealer, stream_header) = chunked::Sealer::new(options.cipher, &file_key, &aad, options.compression, options.level, options.hash);
+        let body_offset = out.stream_position()?;
+        out.write_all(&stream_header)?;
+        Ok(Writer {
+            options,
+            target: target.to_path_buf(),
+            part,
+            file_key,
+            aad,
+            body_offset,
+            out,
+            sealer: Some(sealer),
+            buffer: Vec::with_capacity(chunked::CHUNK_SIZE),
+            finished: false,
+        })
+    }
+
+    /// 加密缓冲中不满一块的数据并写入尾块，返回全部明文的摘要
+    fn close(&mut self) -> anyhow::Result<String> {
+        let mut sealer = self.sealer.take().ok_or_else(|| anyhow::anyhow!("加密文件已写完"))?;
+        if !self.buffer.is_empty() {
+            self.out.write_all(&sealer.data(&self.buffer)?)?;
+            self.buffer.clear();
+        }
+        let (last, _, digest) = sealer.last()?;
+        self.out.write_all(&last)?;
+        self.out.flush()?;
+        Ok(digest)
+    }
+
+    /// 写完数据并改名为目标文件；`expected` 为事先声明的摘要，与写入的数据不符时丢弃文件。返回摘要
+    pub(crate) fn finish(mut self, expected: Option<&str>) -> anyhow::Result<String> {
+        let digest = self.close()?;
+        if let Some(expected) = expected.filter(|e| e.to_lowercase() != digest) {
+            return Err(super::metrics::integrity_failure(format!("{} 校验失败: {} != {}", self.options.hash, expected, digest)));
+        }
+        std::fs::rename(&self.part, &self.target)?;
+        self.finished = true;
+        Ok(digest)
+    }
+
+    /// 用重传的块替换已写入的同序号块：已写入的数据按块解密后连同替换的块重新加密到新的临时文件，
+    /// 使用新的文件密钥，不会以同一 nonce 加密不同的内容。返回新的写入器，之后照常 [`Writer::finish`]
+    pub(crate) fn replace_chunks(mut self, repaired: &HashMap<u64, Vec<u8>>) -> anyhow::Result<Writer<'a>> {
+        self.close()?;
+        let mut next = Writer::create_at(&self.target, part_path(&self.target, "repair.part"), self.options)?;
+        let mut reader = BufReader::new(File::open(&self.part)?);
+        reader.seek(SeekFrom::Start(self.body_offset))?;
+        let mut stream_header = [0u8; cryptography::STREAM_HEADER_LENGTH];
+        reader.read_exact(&mut stream_header)?;
+        let mut opener = chunked::Opener::new(self.options.cipher, &self.file_key, &stream_header, &self.aad, self.options.compression);
+        let mut index = 0u64;
+        loop {
+            let (flag, ciphertext) = chunked::read_chunk_sync(&mut reader)?;
+            if !chunked::is_data(flag)? {
+                break;
+            }
+            let plain = opener.data(&ciphertext, chunked::CHUNK_SIZE)?;
+            next.write_all(repaired.get(&index).unwrap_or(&plain))?;
+            index += 1;
+        }
+        Ok(next)
+    }
+}
+
+impl Write for Writer<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(chunked::CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == chunked::CHUNK_SIZE {
+            let sealer = self.sealer.as_mut().ok_or_else(|| io::Error::other("加密文件已写完"))?;
+            let frame = sealer.data(&self.buffer).map_err(io::Error::other)?;
+            self.out.write_all(&frame)?;
+            self.buffer.clear();
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl Drop for Writer<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            std::fs::remove_file(&self.part).ok();
+        }
+    }
+}
+
+/// 离线加密本地文件（如通过 U 盘传递），加密给 `--to` 指定的接收者或使用 `--password` 口令；
+/// 未指定 `output` 时保存为同目录下的 `<文件名>.uft`
+pub(crate) fn encrypt(path: &str, output: Option<&str>, opts: &SendOptions) -> anyhow::Result<PathBuf> {
+    let source = Path::new(path);
+    let recipient = opts.recipient.as_deref().map(identity::decode_recipient).transpose()?;
+    let protection = match (&recipient, &opts.password) {
+        (Some(recipient), None) => Protection::Recipient(recipient),
+        (None, Some(password)) => Protection::Passphrase(&opts.kdf, password),
+        (Some(_), Some(_)) => anyhow::bail!("--to 与 --password 只能指定一个"),
+        (None, None) => anyhow::bail!("请用 --to 指定接收公钥，或用 --password 设置口令"),
+    };
+    if opts.delta {
+        anyhow::bail!("离线加密不支持 --delta");
+    }
+    if opts.sign {
+        anyhow::bail!("离线加密不支持 --sign");
+    }
+    let cipher = opts.ciphers()?[0];
+    if cipher == CipherSuite::None {
+        anyhow::bail!("离线加密不支持 --cipher none");
+    }
+    let compression = if compression::is_already_compressed(source) { Compression::None } else { opts.compression };
+    let level = compression.clamp_level(opts.level.unwrap_or(compression.default_level()));
+
+    let name = source.file_name()
+        .ok_or_else(|| anyhow::anyhow!("{} 不是文件", path))?
+        .to_string_lossy()
+        .to_string();
+    let target = match output {
+        Some(out) => PathBuf::from(out),
+        None => source.with_file_name(format!("{}.{}", name, EXTENSION)),
+    };
+    if target.exists() {
+        anyhow::bail!("{} 已存在，请用 -o 指定其他输出路径", target.display());
+    }
+    // 先读一遍计算摘要（写入文件头），再逐块加密，不把整个文件读入内存
+    let hash = opts.hashes()[0];
+    let digest = hashing::hash_file(hash, source)?;
+    let size = std::fs::metadata(source)?.len();
+    let mut writer = Writer::create(&target, SealOptions {
+        name: &name,
+        digest: Some(&digest),
+        size: Some(size),
+        protection,
+        compression,
+        level,
+        cipher,
+        hash,
+    })?;
+    io::copy(&mut File::open(source)?, &mut writer)?;
+    writer.finish(Some(&digest))?;
+    say!("已加密到 {}", target.display());
+    output::file("encrypted", FileRecord::new(target.display().to_string()).size(size));
+    Ok(target)
+}
+
+/// 从传输时的原始文件头中取出文件名和摘要：`[u32 文件名长度][文件名][十六进制摘要]...`
+fn parse_transfer_header(raw: &[u8], hash: HashAlgorithm) -> Option<(String, String)> {
+    let name_len = u32::from_be_bytes(raw.get(..4)?.try_into().ok()?) as usize;
+    let name = raw.get(4..4 + name_len)?;
+    let digest = raw.get(4 + name_len..4 + name_len + hash.hex_len())?;
+    Some((String::from_utf8_lossy(name).to_string(), String::from_utf8(digest.to_vec()).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 测试用的临时目录，结束时删除
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("uft-sealed-{}-{}", std::process::id(), name));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.0.join(name)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    /// 测试用的低成本口令派生参数
+    const KDF: Kdf = Kdf::Argon2id { memory_kib: 64, iterations: 1, parallelism: 1 };
+
+    fn sample() -> Vec<u8> {
+        (0..chunked::CHUNK_SIZE * 5 / 2).map(|i| (i % 241) as u8).collect()
+    }
+
+    fn options<'a>(digest: &'a str, protection: Protection<'a>) -> SealOptions<'a> {
+        SealOptions {
+            name: "sample.bin",
+            digest: Some(digest),
+            size: Some(sample().len() as u64),
+            protection,
+            compression: Compression::Zstd,
+            level: 3,
+            cipher: CipherSuite::ChaCha20Poly1305,
+            hash: HashAlgorithm::Sha256,
+        }
+    }
+
+    fn seal(target: &Path, data: &[u8], protection: Protection<'_>) {
+        let digest = hashing::hash_hex(HashAlgorithm::Sha256, data);
+        let mut writer = Writer::create(target, options(&digest, protection)).unwrap();
+        writer.write_all(data).unwrap();
+        assert_eq!(writer.finish(Some(&digest)).unwrap(), digest);
+    }
+
+    fn open_with_password(path: &Path, output: &Path, password: &str) -> anyhow::Result<Vec<u8>> {
+        open(path.to_str().unwrap(), output.to_str(), Some(password))?;
+        Ok(std::fs::read(output)?)
+    }
+
+    /// 把加密文件中的一段文本替换为等长的另一段
+    fn tamper(path: &Path, from: &str, to: &str) {
+        assert_eq!(from.len(), to.len());
+        let mut bytes = std::fs::read(path).unwrap();
+        let at = bytes.windows(from.len()).position(|w| w == from.as_bytes()).unwrap();
+        bytes[at..at + to.len()].copy_from_slice(to.as_bytes());
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn recipient_round_trip() {
+        let dir = TempDir::new("recipient");
+        let secret = StaticSecret::random_from_rng(rand_core::OsRng);
+        let recipient = PublicKey::from(&secret);
+        let data = sample();
+        let sealed = dir.path("sample.bin.uft");
+        seal(&sealed, &data, Protection::Recipient(&recipient));
+
+        let (header, body) = read(&sealed).unwrap();
+        assert!(header.passphrase.is_none());
+        let wrapped = header.key.as_ref().unwrap();
+        assert_eq!(wrapped.recipient, identity::encode_recipient(&recipient));
+        // 其他接收者的私钥无法解出文件密钥
+        let other = StaticSecret::random_from_rng(rand_core::OsRng);
+        assert!(wrapped.unwrap_with(&other).is_err());
+
+        let key = wrapped.unwrap_with(&secret).unwrap();
+        let output = decrypt(&sealed, &header, body, &key, None).unwrap();
+        assert_eq!(output, dir.path("sample.bin"));
+        assert_eq!(std::fs::read(output).unwrap(), data);
+    }
+
+    #[test]
+    fn passphrase_round_trip() {
+        let dir = TempDir::new("passphrase");
+        let data = sample();
+        let sealed = dir.path("sample.bin.uft");
+        seal(&sealed, &data, Protection::Passphrase(&KDF, "correct horse"));
+        assert_eq!(open_with_password(&sealed, &dir.path("out.bin"), "correct horse").unwrap(), data);
+    }
+
+    #[test]
+    fn wrong_password_is_rejected() {
+        let dir = TempDir::new("wrong-password");
+        let sealed = dir.path("sample.bin.uft");
+        seal(&sealed, &sample(), Protection::Passphrase(&KDF, "correct horse"));
+        let err = open_with_password(&sealed, &dir.path("out.bin"), "battery staple").unwrap_err();
+        assert!(err.to_string().contains("口令错误"), "{}", err);
+        assert!(!dir.path("out.bin").exists());
+        assert!(!dir.path("out.bin.part").exists());
+    }
+
+    #[test]
+    fn tampered_header_is_rejected() {
+        let dir = TempDir::new("tampered");
+        let sealed = dir.path("sample.bin.uft");
+        seal(&sealed, &sample(), Protection::Passphrase(&KDF, "pw"));
+        let pristine = std::fs::read(&sealed).unwrap();
+
+        // 认证的原始文件头中的文件名
+        tamper(&sealed, &hex::encode("sample.bin"), &hex::encode("simple.bin"));
+        assert!(open_with_password(&sealed, &dir.path("a.bin"), "pw").is_err());
+        assert!(!dir.path("a.bin").exists());
+
+        // 与文件头一同认证的传输参数
+        std::fs::write(&sealed, &pristine).unwrap();
+        tamper(&sealed, "\"level\":3", "\"level\":4");
+        assert!(open_with_password(&sealed, &dir.path("b.bin"), "pw").is_err());
+        assert!(!dir.path("b.bin").exists());
+
+        std::fs::write(&sealed, &pristine).unwrap();
+        open_with_password(&sealed, &dir.path("c.bin"), "pw").unwrap();
+    }
+
+    #[test]
+    fn replaced_chunks_restore_original_digest() {
+        let dir = TempDir::new("replace");
+        let data = sample();
+        let digest = hashing::hash_hex(HashAlgorithm::Sha256, &data);
+        let sealed = dir.path("sample.bin.uft");
+
+        // 第 1 块写入了损坏的数据，随后用重传的块替换
+        let range = chunked::CHUNK_SIZE..2 * chunked::CHUNK_SIZE;
+        let mut damaged = data.clone();
+        damaged[range.start + 100] ^= 1;
+        let mut writer = Writer::create(&sealed, options(&digest, Protection::Passphrase(&KDF, "pw"))).unwrap();
+        writer.write_all(&damaged).unwrap();
+        let repaired = HashMap::from([(1, data[range].to_vec())]);
+        let writer = writer.replace_chunks(&repaired).unwrap();
+        assert_eq!(writer.finish(Some(&digest)).unwrap(), digest);
+        assert!(!dir.path("sample.bin.uft.part").exists());
+        assert!(!dir.path("sample.bin.uft.repair.part").exists());
+
+        assert_eq!(open_with_password(&sealed, &dir.path("out.bin"), "pw").unwrap(), data);
+    }
+}
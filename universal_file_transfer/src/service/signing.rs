@@ -0,0 +1,106 @@
+use std::path::{Path, PathBuf};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use super::hashing::{self, HashAlgorithm};
+use super::identity;
+use serde_json::json;
+use super::output::{self, say};
+
+/// 分离签名文件的扩展名
+pub(crate) const EXTENSION: &str = "sig";
+/// 文件签名的域分隔前缀，避免与握手签名混用
+const SIGNATURE_CONTEXT: &[u8] = b"uft-file-signature-v1";
+
+/// 发送端用身份密钥对文件摘要的签名；接收端校验后保存为 `<文件>.sig`，之后可用 `verify` 命令再次校验
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct FileSignature {
+    pub(crate) hash: HashAlgorithm,
+    /// 文件摘要（十六进制）
+    pub(crate) digest: String,
+    /// 签名者的身份公钥（十六进制）
+    pub(crate) public_key: String,
+    /// 十六进制签名
+    pub(crate) signature: String,
+}
+
+/// 被签名的内容：前缀 + 摘要算法 + 摘要
+fn message(hash: HashAlgorithm, digest: &str) -> Vec<u8> {
+    let mut msg = SIGNATURE_CONTEXT.to_vec();
+    msg.extend_from_slice(hash.to_string().as_bytes());
+    msg.push(0);
+    msg.extend_from_slice(digest.to_lowercase().as_bytes());
+    msg
+}
+
+impl FileSignature {
+    pub(crate) fn sign(key: &SigningKey, hash: HashAlgorithm, digest: &str) -> FileSignature {
+        FileSignature {
+            hash,
+            digest: digest.to_lowercase(),
+            public_key: identity::encode_key(&key.verifying_key()),
+            signature: hex::encode(key.sign(&message(hash, digest)).to_bytes()),
+        }
+    }
+
+    /// 校验签名，并确认签名者是本机或已信任的对端；成功时返回签名者的描述
+    pub(crate) fn verify(&self) -> anyhow::Result<String> {
+        let key = identity::decode_key(&self.public_key)?;
+        let sig_bytes: [u8; 64] = hex::decode(&self.signature).ok()
+            .and_then(|b| b.try_into().ok())
+            .ok_or_else(|| anyhow::anyhow!("无效的文件签名"))?;
+        key.verify(&message(self.hash, &self.digest), &Signature::from_bytes(&sig_bytes))
+            .map_err(|_| anyhow::anyhow!("文件签名校验失败"))?;
+        signer_name(&key)
+    }
+}
+
+/// 签名者须为本机或 known_peers 中已信任的对端
+fn signer_name(key: &VerifyingKey) -> anyhow::Result<String> {
+    let fingerprint = identity::fingerprint(key);
+    if *key == identity::load_or_create()?.verifying_key() {
+        return Ok(format!("本机（指纹 {}）", fingerprint));
+    }
+    match identity::KnownPeers::load()?.find_by_key(&identity::encode_key(key)) {
+        Some(peer) => Ok(format!("{}（指纹 {}）", peer, fingerprint)),
+        None => anyhow::bail!("签名者 {} 不在已信任的对端中，确认可信后可用 keys trust 添加", fingerprint),
+    }
+}
+
+/// 文件对应的签名文件路径：`<文件>.sig`
+pub(crate) fn sidecar_path(file: &Path) -> PathBuf {
+    let mut path = file.as_os_str().to_owned();
+    path.push(".");
+    path.push(EXTENSION);
+    PathBuf::from(path)
+}
+
+/// 在文件旁写入签名文件
+pub(crate) fn write_sidecar(file: &Path, signature: &FileSignature) -> anyhow::Result<PathBuf> {
+    let path = sidecar_path(file);
+    std::fs::write(&path, serde_json::to_string_pretty(signature)?)?;
+    Ok(path)
+}
+
+/// 用签名文件校验本地文件：重新计算摘要并校验签名，`sig` 未指定时使用 `<文件>.sig`
+pub(crate) fn verify_file(file: &str, sig: Option<&str>) -> anyhow::Result<()> {
+    let sig_path = sig.map(PathBuf::from).unwrap_or_else(|| sidecar_path(Path::new(file)));
+    let text = std::fs::read_to_string(&sig_path)
+        .map_err(|e| anyhow::anyhow!("无法读取签名文件 {}: {}", sig_path.display(), e))?;
+    let signature: FileSignature = serde_json::from_str(&text)
+        .map_err(|_| anyhow::anyhow!("{} 不是有效的签名文件", sig_path.display()))?;
+    let digest = hashing::hash_file(signature.hash, Path::new(file))
+        .map_err(|e| anyhow::anyhow!("无法读取 {}: {}", file, e))?;
+    if digest != signature.digest.to_lowercase() {
+        anyhow::bail!("{}: {} 与签名不符，文件已被修改", file, signature.hash);
+    }
+    let signer = signature.verify()?;
+    say!("{}: 签名有效，签名者 {}", file, signer);
+    output::event("verified", json!({
+        "path": file,
+        "hash": signature.hash,
+        "digest": digest,
+        "public_key": signature.public_key,
+        "signer": signer,
+    }));
+    Ok(())
+}
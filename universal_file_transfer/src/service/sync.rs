@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use super::compression::Compression;
+use super::cryptography::CipherSuite;
+use super::protocol::{self, ClientHello, Mode};
+use super::progress::Batch;
+use super::cryptography::{self, SessionKey};
+use super::{RecvOptions, SendOptions};
+use serde_json::json;
+use super::output::{self, say};
+use tracing::{error, warn};
+
+/// 清单中的单个文件
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ManifestEntry {
+    /// 相对同步根目录的路径，统一使用 `/` 分隔
+    pub(crate) path: String,
+    pub(crate) size: u64,
+    pub(crate) sha256: String,
+}
+
+/// 发送端请求接收端删除的文件
+#[derive(Debug, Serialize, Deserialize)]
+struct DeleteRequest {
+    paths: Vec<String>,
+}
+
+/// 接收端删除结果
+#[derive(Debug, Serialize, Deserialize)]
+struct DeleteResult {
+    deleted: usize,
+    error: Option<String>,
+}
+
+/// 同步计划
+#[derive(Debug, Default)]
+pub(crate) struct Plan {
+    pub(crate) new: Vec<ManifestEntry>,
+    pub(crate) changed: Vec<ManifestEntry>,
+    pub(crate) delete: Vec<String>,
+}
+
+/// 目录同步选项
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SyncOptions {
+    /// 删除接收端多出的文件
+    pub(crate) delete: bool,
+    /// 只打印计划，不做任何修改
+    pub(crate) dry_run: bool,
+    /// 传输单个文件时使用的选项
+    pub(crate) send: SendOptions,
+}
+
+/// 接收端未启用 `--allow-sync`，拒绝目录同步和监听模式的传输。重试也不会成功，监听模式据此停止
+#[derive(Debug)]
+pub(crate) struct SyncRefused;
+
+impl std::fmt::Display for SyncRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("接收端未启用 --allow-sync（或处于加密存储模式），拒绝目录同步和监听模式的传输")
+    }
+}
+
+impl std::error::Error for SyncRefused {}
+
+/// 同步过程中产生的临时文件，不计入清单
+fn is_temporary(name: &str) -> bool {
+    name.ends_with(".part")
+}
+
+/// 目录扫描结果
+#[derive(Debug, Default)]
+pub(crate) struct Listing {
+    /// 普通文件
+    pub(crate) files: Vec<PathBuf>,
+    /// 被排除的同步临时文件（`*.part`），由调用方报告
+    pub(crate) temporary: Vec<PathBuf>,
+}
+
+/// 递归列出目录下的普通文件，同步产生的临时文件单独列出
+pub(crate) fn list_files(root: &Path) -> io::Result<Listing> {
+    let mut listing = Listing::default();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                pending.push(entry.path());
+            } else if file_type.is_file() {
+                if is_temporary(&entry.file_name().to_string_lossy()) {
+                    listing.temporary.push(entry.path());
+                } else {
+                    listing.files.push(entry.path());
+                }
+            }
+        }
+    }
+    Ok(listing)
+}
+
+/// 以 `/` 连接的相对路径，作为清单和网络传输中的文件标识
+pub(crate) fn relative_name(root: &Path, path: &Path) -> String {
+    path.strip_prefix(root).unwrap_or(path)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// 文件的修改时间（Unix 秒）
+pub(crate) fn mtime_secs(meta: &std::fs::Metadata) -> u64 {
+    meta.modified().ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// 递归扫描目录，生成文件清单。
+/// 同步临时文件（`*.part`）、文件名不是有效的 UTF-8 或含有对端会拒绝的字符（见 [`sanitize_relative`]）
+/// 的文件跳过并给出警告
+pub(crate) fn build_manifest(root: &Path) -> io::Result<Vec<ManifestEntry>> {
+    let listing = list_files(root)?;
+    for path in &listing.temporary {
+        warn!(file = %path.display(), "同步临时文件（可能来自中断的传输），未计入清单");
+    }
+    let mut entries = Vec::new();
+    for path in listing.files {
+        let rel = relative_name(root, &path);
+        let Some(path_str) = path.to_str().filter(|_| sanitize_relative(&rel).is_some()) else {
+            warn!(file = %path.display(), "文件名不是有效的 UTF-8 或含有不允许的字符，已跳过");
+            continue;
+        };
+        let meta = std::fs::metadata(&path)?;
+        entries.push(ManifestEntry {
+            path: rel,
+            size: meta.len(),
+            sha256: cryptography::calculate_sha256(path_str)?,
+        });
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+/// 把对端给出的相对路径转换为本地路径；拒绝绝对路径和 `..` 等越界路径
+pub(crate) fn sanitize_relative(path: &str) -> Option<PathBuf> {
+    let mut out = PathBuf::new();
+    for part in path.split('/') {
+        if part.is_empty() || part.contains('\\') || part.contains(':') {
+            return None;
+        }
+        match Path::new(part).components().next() {
+            Some(Component::Normal(c)) => out.push(c),
+            _ => return None,
+        }
+    }
+    (!out.as_os_str().is_empty()).then_some(out)
+}
+
+/// 对比本地与远端清单，得出需要上传和删除的文件
+pub(crate) fn plan(local: &[ManifestEntry], remote: &[ManifestEntry], delete: bool) -> Plan {
+    let remote_by_path: HashMap<&str, &ManifestEntry> =
+        remote.iter().map(|e| (e.path.as_str(), e)).collect();
+    let mut plan = Plan::default();
+    for entry in local {
+        match remote_by_path.get(entry.path.as_str()) {
+            None => plan.new.push(entry.clone()),
+            Some(r) if r.size != entry.size || r.sha256 != entry.sha256 => plan.changed.push(entry.clone()),
+            Some(_) => {}
+        }
+    }
+    if delete {
+        let local_paths: std::collections::HashSet<&str> = local.iter().map(|e| e.path.as_str()).collect();
+        plan.delete = remote.iter()
+            .filter(|e| !local_paths.contains(e.path.as_str()))
+            .map(|e| e.path.clone())
+            .collect();
+    }
+    plan
+}
+
+fn print_plan(plan: &Plan, unchanged: usize) {
+    for e in &plan.new {
+        say!("  + {} ({} 字节)", e.path, e.size);
+    }
+    for e in &plan.changed {
+        say!("  ~ {} ({} 字节)", e.path, e.size);
+    }
+    for p in &plan.delete {
+        say!("  - {}", p);
+    }
+    say!(
+        "同步计划：新增 {}，更新 {}，删除 {}，未变化 {}",
+        plan.new.len(), plan.changed.len(), plan.delete.len(), unchanged,
+    );
+    output::event("sync_plan", json!({
+        "new": plan.new.iter().map(|e| &e.path).collect::<Vec<_>>(),
+        "changed": plan.changed.iter().map(|e| &e.path).collect::<Vec<_>>(),
+        "delete": plan.delete,
+        "unchanged": unchanged,
+    }));
+}
+
+/// 发送端：把本地目录同步到远端接收端的保存目录
+pub(crate) async fn run(local_dir: &str, server: &str, port: u16, opts: &SyncOptions) -> anyhow::Result<()> {
+    let root = Path::new(local_dir);
+    let local = build_manifest(root)?;
+
+    // 1. 获取远端清单
+    let mut stream = TcpStream::connect((server, port)).await?;
+    say!("已通过 TCP 连接到 {}:{}，正在交换文件清单...", server, port);
+    let mut hello = ClientHello {
+        compression: vec![Compression::None],
+        level: 0,
+        delta: false,
+        mode: Mode::Manifest,
+        nonce: String::new(),
+        recipient: None,
+        // 清单不经数据加密算法传输，只声明默认算法
+        ciphers: vec![CipherSuite::default()],
+        kdf: None,
+        size: None,
+        hashes: Vec::new(),
+        signed: false,
+        ephemeral: String::new(),
+    };
+    // 清单和删除请求都以握手密钥交换得到的共享密钥加密，只有通过了身份校验的对端能读写
+    let (_, shared) = super::client_handshake(&mut stream, &mut hello, Some(&format!("{}:{}", server, port))).await?;
+    let remote: Vec<ManifestEntry> = protocol::read_sealed_frame(&mut stream, &shared).await
+        .context("未收到远端清单")?;
+
+    // 2. 生成并打印计划
+    let plan = plan(&local, &remote, opts.delete);
+    let unchanged = local.len() - plan.new.len() - plan.changed.len();
+    print_plan(&plan, unchanged);
+
+    // 3. 删除远端多余文件（演练模式下发送空列表）
+    let paths = if opts.dry_run { Vec::new() } else { plan.delete.clone() };
+    protocol::write_sealed_frame(&mut stream, &shared, &DeleteRequest { paths }).await?;
+    let result: DeleteResult = protocol::read_sealed_frame(&mut stream, &shared).await?;
+    if let Some(err) = result.error {
+        anyhow::bail!("远端拒绝删除: {}", err);
+    }
+    if opts.dry_run {
+        say!("演练模式，未做任何修改");
+        return Ok(());
+    }
+    if result.deleted > 0 {
+        say!("已删除远端 {} 个文件", result.deleted);
+    }
+
+    // 4. 逐个上传新增和变化的文件，进度中同时显示总体进度
+    let mut failed = 0;
+    let uploads: Vec<&ManifestEntry> = plan.new.iter().chain(plan.changed.iter()).collect();
+    let batch = Batch::new(uploads.len(), uploads.iter().map(|e| e.size).sum());
+    for entry in uploads {
+        // 清单中只有合法的 UTF-8 相对路径，见 build_manifest
+        let Some(local_path) = sanitize_relative(&entry.path).map(|rel| root.join(rel)) else {
+            continue;
+        };
+        let send_opts = SendOptions { sync_path: Some(entry.path.clone()), batch: Some(batch.clone()), ..opts.send.clone() };
+        if let Err(e) = super::tcp_send(server, port, &local_path.to_string_lossy(), &send_opts).await {
+            error!(file = %entry.path, "同步失败: {:#}", e);
+            failed += 1;
+        }
+        batch.file_done(entry.size);
+    }
+    if failed > 0 {
+        anyhow::bail!("{} 个文件同步失败", failed);
+    }
+    say!("目录 {} 同步完成", local_dir);
+    Ok(())
+}
+
+/// 接收端：返回保存目录的清单，并按请求删除多余文件。
+/// `shared` 为握手密钥交换得到的共享密钥，清单、删除请求和结果都以它加密传输
+pub(crate) async fn serve_manifest<S>(
+    socket: &mut S,
+    output_dir: &str,
+    shared: &SessionKey,
+    opts: &RecvOptions,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let root = Path::new(output_dir);
+    let manifest = build_manifest(root)?;
+    protocol::write_sealed_frame(socket, shared, &manifest).await?;
+
+    let request: DeleteRequest = protocol::read_sealed_frame(socket, shared).await?;
+    if request.paths.is_empty() {
+        return protocol::write_sealed_frame(socket, shared, &DeleteResult { deleted: 0, error: None }).await;
+    }
+    if !opts.allow_delete {
+        let error = Some("接收端未启用 --allow-delete".to_string());
+        return protocol::write_sealed_frame(socket, shared, &DeleteResult { deleted: 0, error }).await;
+    }
+
+    let mut deleted = 0;
+    for rel in &request.paths {
+        let Some(rel_path) = sanitize_relative(rel) else {
+            warn!("忽略非法的删除路径: {}", rel);
+            continue;
+        };
+        match std::fs::remove_file(root.join(rel_path)) {
+            Ok(()) => {
+                say!("同步删除 {}", rel);
+                deleted += 1;
+            }
+            Err(e) => warn!("删除 {} 失败: {}", rel, e),
+        }
+    }
+    protocol::write_sealed_frame(socket, shared, &DeleteResult { deleted, error: None }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_keeps_nested_relative_paths() {
+        assert_eq!(sanitize_relative("a.txt"), Some(PathBuf::from("a.txt")));
+        assert_eq!(sanitize_relative("dir/sub/a.txt"), Some(["dir", "sub", "a.txt"].iter().collect()));
+        assert_eq!(sanitize_relative("中文/文件.txt"), Some(["中文", "文件.txt"].iter().collect()));
+    }
+
+    #[test]
+    fn sanitize_rejects_escaping_paths() {
+        for path in ["", "/etc/passwd", "../a", "a/../b", "a/./b", "./a", ".", "..", "a//b", "a/"] {
+            assert_eq!(sanitize_relative(path), None, "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn sanitize_rejects_separators_and_drive_letters() {
+        for path in ["a\\b", "..\\a", "C:", "C:/a", "a:b.txt", "dir/a:b"] {
+            assert_eq!(sanitize_relative(path), None, "{:?}", path);
+        }
+    }
+
+    #[test]
+    fn list_files_reports_temporary_files() {
+        let root = std::env::temp_dir().join(format!("uft-sync-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("a.txt"), b"a").unwrap();
+        std::fs::write(root.join("sub/b.txt"), b"b").unwrap();
+        std::fs::write(root.join("sub/b.txt.part"), b"partial").unwrap();
+
+        let mut listing = list_files(&root).unwrap();
+        listing.files.sort();
+        assert_eq!(listing.files, vec![root.join("a.txt"), root.join("sub/b.txt")]);
+        assert_eq!(listing.temporary, vec![root.join("sub/b.txt.part")]);
+
+        let manifest = build_manifest(&root).unwrap();
+        let paths: Vec<&str> = manifest.iter().map(|e| e.path.as_str()).collect();
+        std::fs::remove_dir_all(&root).ok();
+        assert_eq!(paths, ["a.txt", "sub/b.txt"]);
+    }
+}
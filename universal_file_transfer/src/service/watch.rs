@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use notify::event::{AccessKind, AccessMode};
+use notify::{EventKind, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use super::{SendOptions, cryptography, sync};
+use super::output::say;
+use tracing::warn;
+
+/// 未指定 `--state` 时，状态文件保存在监听目录下的该文件名
+const STATE_FILE_NAME: &str = ".uft-watch-state.json";
+
+/// 发送失败后再次尝试前的等待时间
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// 监听模式选项
+#[derive(Debug, Clone, Default)]
+pub(crate) struct WatchOptions {
+    /// 文件在这段时间内没有任何变化才视为写入完成
+    pub(crate) settle: Duration,
+    /// 确认送达后把文件移动到该目录
+    pub(crate) move_to: Option<PathBuf>,
+    /// 状态文件路径
+    pub(crate) state_file: Option<PathBuf>,
+    /// 发送单个文件时使用的选项
+    pub(crate) send: SendOptions,
+}
+
+/// 已成功发送的文件版本
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SentRecord {
+    size: u64,
+    mtime: u64,
+    sha256: String,
+}
+
+/// 无法发送的文件版本：接收端不会接受该文件名，文件变化前不再重试
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct FailedRecord {
+    size: u64,
+    mtime: u64,
+    reason: String,
+}
+
+/// 持久化的监听状态：相对路径 -> 最近一次成功发送的版本，以及被永久放弃的版本
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct WatchState {
+    sent: HashMap<String, SentRecord>,
+    #[serde(default)]
+    failed: HashMap<String, FailedRecord>,
+}
+
+impl WatchState {
+    fn load(path: &Path) -> io::Result<WatchState> {
+        match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(WatchState::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 先写临时文件再改名，避免中途退出留下损坏的状态文件
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    /// 该版本已发送过或已被放弃，不需要处理
+    fn is_handled(&self, rel: &str, size: u64, mtime: u64) -> bool {
+        self.sent.get(rel).is_some_and(|r| r.size == size && r.mtime == mtime)
+            || self.failed.get(rel).is_some_and(|r| r.size == size && r.mtime == mtime)
+    }
+
+    /// 修改时间变了但内容与已发送的版本相同（例如 touch）时，只更新记录中的大小和修改时间并返回真
+    fn refresh_unchanged(&mut self, rel: &str, size: u64, mtime: u64, sha256: &str) -> bool {
+        match self.sent.get_mut(rel).filter(|r| r.sha256 == sha256) {
+            Some(sent) => {
+                sent.size = size;
+                sent.mtime = mtime;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// 等待写入稳定的文件
+struct Settling {
+    /// 最近一次变化的时间
+    changed_at: Instant,
+    /// 上一次检查时观察到的 (大小, 修改时间)
+    last_seen: Option<(u64, u64)>,
+}
+
+impl Settling {
+    fn new() -> Settling {
+        Settling { changed_at: Instant::now(), last_seen: None }
+    }
+
+    /// 距最近一次变化已过 `settle`，且大小和修改时间与上一次检查时相同，才认为写入已经结束；
+    /// 观察到变化时重新计时
+    fn is_settled(&mut self, seen: (u64, u64), settle: Duration, now: Instant) -> bool {
+        if now.saturating_duration_since(self.changed_at) < settle {
+            return false;
+        }
+        if self.last_seen != Some(seen) {
+            self.last_seen = Some(seen);
+            self.changed_at = now;
+            return false;
+        }
+        true
+    }
+}
+
+/// 只关心会改变文件内容的事件；读取文件产生的访问事件需要忽略，否则发送时的读取会再次触发
+fn is_change(kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => true,
+        EventKind::Access(_) | EventKind::Remove(_) => false,
+        _ => true,
+    }
+}
+
+/// 隐藏文件和常见临时文件不自动发送
+fn is_ignored(rel: &Path) -> bool {
+    rel.components().any(|c| c.as_os_str().to_string_lossy().starts_with('.'))
+        || rel.extension().is_some_and(|e| e == "part" || e == "tmp" || e == "swp")
+}
+
+/// 把文件移入已发送目录，跨文件系统时退化为复制后删除
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if std::fs::rename(from, to).is_err() {
+        std::fs::copy(from, to)?;
+        std::fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+/// 发送端：监听目录，文件写入稳定后自动发送到接收端
+pub(crate) async fn run(dir: &str, server: &str, port: u16, opts: &WatchOptions) -> anyhow::Result<()> {
+    let root = std::fs::canonicalize(dir)?;
+    let state_path = opts.state_file.clone().unwrap_or_else(|| root.join(STATE_FILE_NAME));
+    let move_to = opts.move_to.as_ref().map(std::fs::canonicalize).transpose()?;
+    let mut state = WatchState::load(&state_path)?;
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res| {
+        let _ = tx.send(res);
+    })?;
+    watcher.watch(&root, RecursiveMode::Recursive)?;
+    say!("正在监听目录 {}，文件写入稳定 {} 秒后自动发送到 {}:{}", root.display(), opts.settle.as_secs(), server, port);
+
+    // 启动时先检查一遍，补发离线期间新增或变化的文件
+    let mut pending: HashMap<PathBuf, Settling> = sync::list_files(&root)?
+        .files
+        .into_iter()
+        .map(|p| (p, Settling::new()))
+        .collect();
+
+    let mut tick = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            Some(res) = rx.recv() => match res {
+                Ok(event) if is_change(&event.kind) => {
+                    for path in event.paths {
+                        let entry = pending.entry(path).or_insert_with(Settling::new);
+                        entry.changed_at = Instant::now();
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => warn!("目录监听出错: {}", e),
+            },
+            _ = tick.tick() => {
+                let mut ready = Vec::new();
+                pending.retain(|path, p| {
+                    if path == &state_path || move_to.as_ref().is_some_and(|m| path.starts_with(m)) {
+                        return false;
+                    }
+                    let rel = path.strip_prefix(&root).unwrap_or(path);
+                    let Ok(meta) = std::fs::metadata(path) else { return false };
+                    if !meta.is_file() || is_ignored(rel) {
+                        return false;
+                    }
+                    if !p.is_settled((meta.len(), sync::mtime_secs(&meta)), opts.settle, Instant::now()) {
+                        return true;
+                    }
+                    ready.push(path.clone());
+                    false
+                });
+
+                for path in ready {
+                    if let Err(e) = process(&root, &path, server, port, opts, move_to.as_deref(), &mut state, &state_path).await {
+                        // 接收端不接受监听模式时重试也不会成功，停止监听并报告
+                        if e.chain().any(|e| e.is::<sync::SyncRefused>()) {
+                            return Err(e.context(format!("无法发送 {}，已停止监听；请以 --allow-sync 启动接收端", path.display())));
+                        }
+                        warn!(file = %path.display(), "自动发送失败，{} 秒后重试: {:#}", RETRY_DELAY.as_secs(), e);
+                        let mut retry = Settling::new();
+                        retry.changed_at = Instant::now() + RETRY_DELAY;
+                        pending.insert(path, retry);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// 发送一个已稳定的文件，并在确认送达后更新状态
+#[allow(clippy::too_many_arguments)]
+async fn process(
+    root: &Path,
+    path: &Path,
+    server: &str,
+    port: u16,
+    opts: &WatchOptions,
+    move_to: Option<&Path>,
+    state: &mut WatchState,
+    state_path: &Path,
+) -> anyhow::Result<()> {
+    let rel = sync::relative_name(root, path);
+    let meta = std::fs::metadata(path)?;
+    let (size, mtime) = (meta.len(), sync::mtime_secs(&meta));
+    if state.is_handled(&rel, size, mtime) {
+        return Ok(());
+    }
+
+    // 接收端按同样的规则校验文件名，不合法的名称重试也不会成功，记为永久失败，文件变化后才再次尝试
+    let (Some(path_str), Some(sanitized)) = (path.to_str(), sync::sanitize_relative(&rel)) else {
+        let reason = "文件名不是有效的 UTF-8，或包含接收端不接受的字符".to_string();
+        warn!(file = %path.display(), "{}，不再自动发送", reason);
+        state.failed.insert(rel, FailedRecord { size, mtime, reason });
+        state.save(state_path)?;
+        return Ok(());
+    };
+    state.failed.remove(&rel);
+
+    let sha256 = cryptography::calculate_sha256(path_str)?;
+    if state.refresh_unchanged(&rel, size, mtime, &sha256) {
+        state.save(state_path)?;
+        return Ok(());
+    }
+
+    let send_opts = SendOptions { sync_path: Some(rel.clone()), ..opts.send.clone() };
+    super::tcp_send(server, port, path_str, &send_opts).await?;
+
+    record_sent(state, rel, SentRecord { size, mtime, sha256 }, path, move_to.map(|dir| dir.join(sanitized)))?;
+    state.save(state_path)?;
+    Ok(())
+}
+
+/// 确认送达后记录已发送的版本；指定了已发送目录时把文件移到 `moved_to`，不再保留记录
+fn record_sent(state: &mut WatchState, rel: String, record: SentRecord, path: &Path, moved_to: Option<PathBuf>) -> io::Result<()> {
+    match moved_to {
+        Some(target) => {
+            move_file(path, &target)?;
+            say!("已移动到 {}", target.display());
+            state.sent.remove(&rel);
+        }
+        None => {
+            state.sent.insert(rel, record);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 测试用的临时目录，结束时删除
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("uft-watch-{}-{}", std::process::id(), name));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    const SETTLE: Duration = Duration::from_secs(2);
+
+    #[test]
+    fn settles_only_after_size_and_mtime_hold_still() {
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut p = Settling { changed_at: start, last_seen: None };
+        // 稳定周期内不检查
+        assert!(!p.is_settled((10, 100), SETTLE, at(1)));
+        // 首次观察只记录，重新计时
+        assert!(!p.is_settled((10, 100), SETTLE, at(2)));
+        assert!(!p.is_settled((10, 100), SETTLE, at(3)));
+        // 大小变化后重新等待一个周期
+        assert!(!p.is_settled((20, 100), SETTLE, at(4)));
+        assert!(!p.is_settled((20, 100), SETTLE, at(5)));
+        // 只有修改时间变化同样重新计时
+        assert!(!p.is_settled((20, 101), SETTLE, at(6)));
+        assert!(p.is_settled((20, 101), SETTLE, at(8)));
+    }
+
+    #[test]
+    fn retry_delay_postpones_settling() {
+        let now = Instant::now();
+        let mut p = Settling { changed_at: now + RETRY_DELAY, last_seen: Some((1, 1)) };
+        assert!(!p.is_settled((1, 1), SETTLE, now + RETRY_DELAY));
+        assert!(p.is_settled((1, 1), SETTLE, now + RETRY_DELAY + SETTLE));
+    }
+
+    #[test]
+    fn state_records_sent_and_failed_versions() {
+        let dir = TempDir::new("state");
+        let path = dir.0.join("state.json");
+        let mut state = WatchState::load(&path).unwrap();
+        assert!(state.sent.is_empty() && state.failed.is_empty());
+
+        state.sent.insert("a.txt".into(), SentRecord { size: 5, mtime: 100, sha256: "aa".into() });
+        state.failed.insert("bad:name".into(), FailedRecord { size: 3, mtime: 50, reason: "文件名无效".into() });
+        state.save(&path).unwrap();
+        let mut state = WatchState::load(&path).unwrap();
+        assert!(state.is_handled("a.txt", 5, 100));
+        assert!(state.is_handled("bad:name", 3, 50));
+        // 文件变化后需要重新处理
+        assert!(!state.is_handled("a.txt", 6, 100));
+        assert!(!state.is_handled("a.txt", 5, 101));
+        assert!(!state.is_handled("bad:name", 3, 51));
+        assert!(!state.is_handled("new.txt", 5, 100));
+
+        // 只有修改时间变化、内容相同：更新记录，不重新发送
+        assert!(state.refresh_unchanged("a.txt", 5, 200, "aa"));
+        assert!(state.is_handled("a.txt", 5, 200));
+        assert!(!state.refresh_unchanged("a.txt", 5, 300, "bb"));
+        assert!(!state.is_handled("a.txt", 5, 300));
+    }
+
+    #[test]
+    fn sent_file_is_recorded_or_moved() {
+        let dir = TempDir::new("move");
+        let file = dir.0.join("report.txt");
+        std::fs::write(&file, b"data").unwrap();
+        let record = || SentRecord { size: 4, mtime: 1, sha256: "cc".into() };
+
+        let mut state = WatchState::default();
+        record_sent(&mut state, "report.txt".into(), record(), &file, None).unwrap();
+        assert_eq!(state.sent.get("report.txt"), Some(&record()));
+        assert!(file.exists());
+
+        // 移到已发送目录（按相对路径建立子目录），不再保留记录
+        let target = dir.0.join("sent").join("sub").join("report.txt");
+        record_sent(&mut state, "report.txt".into(), record(), &file, Some(target.clone())).unwrap();
+        assert!(!file.exists());
+        assert_eq!(std::fs::read(&target).unwrap(), b"data");
+        assert!(state.sent.is_empty());
+    }
+
+    #[test]
+    fn ignores_hidden_and_temporary_files() {
+        assert!(is_ignored(Path::new(".uft-watch-state.json")));
+        assert!(is_ignored(Path::new("dir/.hidden/a.txt")));
+        assert!(is_ignored(Path::new("a.txt.part")));
+        assert!(is_ignored(Path::new("a.swp")));
+        assert!(!is_ignored(Path::new("dir/a.txt")));
+    }
+}
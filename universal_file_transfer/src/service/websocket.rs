@@ -0,0 +1,139 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use futures_util::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{self, Message};
+
+/// 单个 WebSocket 消息携带的最大字节数，远小于 tungstenite 默认的消息上限
+const MAX_MESSAGE: usize = 1024 * 1024;
+
+/// 把 WebSocket 连接包装成字节流，使其与 TCP 连接走同一套握手和传输协议。
+/// 每次写入作为一个二进制消息发出，读取时按顺序拼接收到的二进制消息
+pub(crate) struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    /// 已收到但尚未读出的数据
+    pending: Vec<u8>,
+    offset: usize,
+    /// 有写入的消息还留在 tungstenite 的缓冲区中
+    unflushed: bool,
+}
+
+impl<S> WsStream<S> {
+    pub(crate) fn new(inner: WebSocketStream<S>) -> WsStream<S> {
+        WsStream { inner, pending: Vec::new(), offset: 0, unflushed: false }
+    }
+}
+
+fn to_io(e: tungstenite::Error) -> io::Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => io::ErrorKind::ConnectionAborted.into(),
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WsStream<S> {
+    /// 把缓冲中的消息推送到底层连接；等待对端数据前必须先完成，否则双方会互相等待
+    fn poll_flush_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.unflushed {
+            ready!(Pin::new(&mut self.inner).poll_flush(cx)).map_err(to_io)?;
+            self.unflushed = false;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.poll_flush_pending(cx))?;
+        while this.offset == this.pending.len() {
+            match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => {
+                    this.pending = data;
+                    this.offset = 0;
+                }
+                // 对端关闭连接视为读到末尾
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "WebSocket 连接上收到了文本消息")));
+                }
+                // Ping/Pong 由 tungstenite 自动应答
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Poll::Ready(Err(to_io(e))),
+            }
+        }
+        let n = buf.remaining().min(this.pending.len() - this.offset);
+        buf.put_slice(&this.pending[this.offset..this.offset + n]);
+        this.offset += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(Pin::new(&mut this.inner).poll_ready(cx)).map_err(to_io)?;
+        let n = buf.len().min(MAX_MESSAGE);
+        Pin::new(&mut this.inner).start_send(Message::Binary(buf[..n].to_vec())).map_err(to_io)?;
+        this.unflushed = true;
+        // 尽量立即推送；未完成时留到下次读写或 flush
+        if let Poll::Ready(result) = this.poll_flush_pending(cx) {
+            result?;
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush_pending(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match ready!(Pin::new(&mut self.inner).poll_close(cx)) {
+            Ok(()) | Err(tungstenite::Error::ConnectionClosed) => Poll::Ready(Ok(())),
+            Err(e) => Poll::Ready(Err(to_io(e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_tungstenite::tungstenite::protocol::Role;
+
+    #[tokio::test]
+    async fn carries_bytes_across_message_boundaries() {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let client = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+        let server = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+        let mut client = WsStream::new(client);
+        let mut server = WsStream::new(server);
+
+        let data: Vec<u8> = (0..3 * MAX_MESSAGE / 2).map(|i| i as u8).collect();
+        let expected = data.clone();
+        let writer = tokio::spawn(async move {
+            client.write_all(b"hi").await.unwrap();
+            client.write_all(&data).await.unwrap();
+            let mut reply = [0u8; 2];
+            client.read_exact(&mut reply).await.unwrap();
+            client.shutdown().await.unwrap();
+            reply
+        });
+
+        let mut head = [0u8; 2];
+        server.read_exact(&mut head).await.unwrap();
+        assert_eq!(&head, b"hi");
+        let mut body = vec![0u8; expected.len()];
+        server.read_exact(&mut body).await.unwrap();
+        assert_eq!(body, expected);
+        server.write_all(b"ok").await.unwrap();
+
+        assert_eq!(&writer.await.unwrap(), b"ok");
+        let mut rest = Vec::new();
+        server.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+}